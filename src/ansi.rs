@@ -0,0 +1,219 @@
+//! Converting canvases to and from text with ANSI colour escape codes, so that they can be
+//! shared with other programs (e.g. by pasting them into a terminal)
+
+use crossterm::style::Colored;
+
+use crate::{Canvas, Clip, Color, Glyph, Selection};
+
+/// Writes an area of a canvas as lines of text, using ANSI escape codes for the colours.
+/// Painted cells become spaces with a background colour, and glyphs keep their character.
+pub fn to_ansi(canvas: &Canvas, area: Selection) -> String {
+    let mut text = String::new();
+    let Some(area) = area.intersection(&canvas.full_area()) else {
+        return text;
+    };
+    for row in area.top..=area.bottom {
+        // Only write escape codes when the colours change
+        let mut current = (Color::Reset, Color::Reset);
+        for col in area.left..=area.right {
+            let background = canvas.get_cell(row, col).flatten().unwrap_or(Color::Reset);
+            let glyph = canvas.get_glyph(row, col).flatten();
+            let (foreground, char) = match glyph {
+                Some(Glyph { char, color }) => (color, char),
+                None => (current.0, ' '),
+            };
+            if foreground != current.0 {
+                text += &format!("\x1b[{}m", sgr_params(foreground, false));
+            }
+            if background != current.1 {
+                text += &format!("\x1b[{}m", sgr_params(background, true));
+            }
+            current = (foreground, background);
+            text.push(char);
+        }
+        if current != (Color::Reset, Color::Reset) {
+            text += "\x1b[0m";
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// The parameters of a "select graphic rendition" escape code that changes the foreground
+/// or background colour. These are written out here, rather than with crossterm, since
+/// crossterm writes nothing at all while its colours are turned off (e.g. for `--mono`).
+fn sgr_params(color: Color, background: bool) -> String {
+    let offset = if background { 10 } else { 0 };
+    if let Some(index) = NAMED_COLORS_BY_CODE
+        .iter()
+        .position(|named| *named == color)
+    {
+        let code = match index {
+            0..=7 => 30 + index,
+            _ => 90 + index - 8,
+        };
+        return (code + offset).to_string();
+    }
+    match color {
+        Color::Rgb { r, g, b } => format!("{};2;{};{};{}", 38 + offset, r, g, b),
+        Color::AnsiValue(value) => format!("{};5;{}", 38 + offset, value),
+        // Anything else (i.e. Reset) goes back to the terminal's own colour
+        _ => (39 + offset).to_string(),
+    }
+}
+
+/// Reads text with ANSI colour escape codes into a clip that can be pasted onto a canvas.
+/// Background colours become painted cells, and any characters other than spaces become
+/// glyphs. Escape codes that don't change the colour are ignored. Characters that aren't
+/// one column wide still take up one cell, and show up as a placeholder.
+pub fn from_ansi(text: &str) -> Clip {
+    let mut cells = Vec::new();
+    let mut glyphs = Vec::new();
+    let (mut foreground, mut background) = (Color::Reset, Color::Reset);
+    for line in text.lines() {
+        let mut cell_row = Vec::new();
+        let mut glyph_row = Vec::new();
+        let mut chars = line.chars();
+        while let Some(char) = chars.next() {
+            if char == '\x1b' {
+                // Read up to the end of the escape sequence
+                let sequence: String = chars
+                    .by_ref()
+                    .take_while(|c| !c.is_ascii_alphabetic())
+                    .collect();
+                if let Some(params) = sequence.strip_prefix('[') {
+                    apply_sgr(params, &mut foreground, &mut background);
+                }
+                continue;
+            }
+            if char.is_control() {
+                continue;
+            }
+            cell_row.push((background != Color::Reset).then_some(background));
+            glyph_row.push((char != ' ').then_some(Glyph::new(char, foreground)));
+        }
+        cells.push(cell_row);
+        glyphs.push(glyph_row);
+    }
+    // Clips are rectangular, so pad out the shorter lines
+    let width = cells.iter().map(Vec::len).max().unwrap_or(0);
+    for row in cells.iter_mut() {
+        row.resize(width, None);
+    }
+    for row in glyphs.iter_mut() {
+        row.resize(width, None);
+    }
+    Clip { cells, glyphs }
+}
+
+/// Updates the colours based on the parameters of a "select graphic rendition" escape code
+/// (the part between `ESC [` and `m`)
+fn apply_sgr(params: &str, foreground: &mut Color, background: &mut Color) {
+    let params: Vec<&str> = params.split(';').collect();
+    let mut i = 0;
+    while i < params.len() {
+        let code: u8 = params[i].parse().unwrap_or(0);
+        match code {
+            0 => (*foreground, *background) = (Color::Reset, Color::Reset),
+            30..=37 => *foreground = NAMED_COLORS_BY_CODE[code as usize - 30],
+            40..=47 => *background = NAMED_COLORS_BY_CODE[code as usize - 40],
+            90..=97 => *foreground = NAMED_COLORS_BY_CODE[code as usize - 90 + 8],
+            100..=107 => *background = NAMED_COLORS_BY_CODE[code as usize - 100 + 8],
+            38 | 48 => {
+                // Either `5;<n>` or `2;<r>;<g>;<b>` comes next
+                let length = if params.get(i + 1) == Some(&"2") {
+                    5
+                } else {
+                    3
+                };
+                let end = (i + length).min(params.len());
+                if let Some(Colored::ForegroundColor(color) | Colored::BackgroundColor(color)) =
+                    Colored::parse_ansi(&params[i..end].join(";"))
+                {
+                    let color = from_256_color(color);
+                    match code {
+                        38 => *foreground = color,
+                        _ => *background = color,
+                    }
+                }
+                i = end;
+                continue;
+            }
+            39 => *foreground = Color::Reset,
+            49 => *background = Color::Reset,
+            _ => {}
+        }
+        i += 1;
+    }
+}
+
+/// The colours for the 3/4-bit ANSI codes, in order (e.g. code 31 is the second one)
+const NAMED_COLORS_BY_CODE: [Color; 16] = [
+    Color::Black,
+    Color::DarkRed,
+    Color::DarkGreen,
+    Color::DarkYellow,
+    Color::DarkBlue,
+    Color::DarkMagenta,
+    Color::DarkCyan,
+    Color::Grey,
+    Color::DarkGrey,
+    Color::Red,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::White,
+];
+
+/// Converts colours from the 256-colour palette to RGB, since the program doesn't
+/// support them directly. Other colours are left alone.
+fn from_256_color(color: Color) -> Color {
+    let Color::AnsiValue(value) = color else {
+        return color;
+    };
+    let rgb = match value {
+        0..=15 => return NAMED_COLORS_BY_CODE[value as usize],
+        // A 6x6x6 colour cube
+        16..=231 => {
+            let level = |n: u8| if n == 0 { 0 } else { 55 + n * 40 };
+            let value = value - 16;
+            [level(value / 36), level(value / 6 % 6), level(value % 6)]
+        }
+        // Shades of grey
+        232..=255 => {
+            let level = 8 + (value - 232) * 10;
+            [level, level, level]
+        }
+    };
+    Color::Rgb {
+        r: rgb[0],
+        g: rgb[1],
+        b: rgb[2],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::TextOverflow;
+
+    #[test]
+    fn copies_colours_even_while_crossterm_colours_are_off() {
+        let mut canvas = Canvas::new(4, 1);
+        canvas.import_text("   x", &HashMap::new(), TextOverflow::Clip, None);
+        canvas.set_cell(0, 0, Some(Color::Red));
+        canvas.set_cell(0, 1, Some(Color::DarkBlue));
+        canvas.set_cell(0, 2, Some(Color::Rgb { r: 1, g: 2, b: 3 }));
+        // Like --mono does
+        Colored::set_ansi_color_disabled(true);
+        let text = to_ansi(&canvas, canvas.full_area());
+        assert_eq!(text, "\x1b[101m \x1b[44m \x1b[48;2;1;2;3m \x1b[49mx\n");
+        let clip = from_ansi(&text);
+        assert_eq!(clip.cells, [canvas.rows()[0].clone()]);
+        assert_eq!(clip.glyphs, [canvas.glyph_rows()[0].clone()]);
+    }
+}
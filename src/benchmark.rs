@@ -0,0 +1,53 @@
+//! Timing how long it takes to draw the whole screen, so that changes to the renderer can
+//! be judged by numbers. Built with `--features benchmark`, and run with `--benchmark`.
+
+use std::{collections::HashMap, time::Instant};
+
+use paint_2d::{TextOverflow, hue_to_color};
+
+use crate::{Paint2D, Settings};
+
+/// The size of the pretend terminal, as `(cols, rows)`. It's bigger than most real ones, so
+/// that slow parts of the renderer stand out.
+const TERMINAL_SIZE: (u16, u16) = (400, 120);
+
+/// How many times the screen gets drawn. The first frame isn't counted, since it's slower
+/// while everything warms up.
+const FRAMES: u32 = 200;
+
+/// Draws a busy canvas over and over into a buffer (instead of the terminal), and prints
+/// how long each frame took on average, and how much output it made
+pub fn run() -> std::io::Result<()> {
+    let mut app = Paint2D::new(Vec::new(), &TERMINAL_SIZE, Settings::default(), None);
+    // Every cell gets a different colour from its neighbours, which is the worst case for
+    // the renderer, since it can never skip a colour change
+    let (width, height) = (app.canvas.width(), app.canvas.height());
+    for row in 0..height {
+        for col in 0..width {
+            app.canvas
+                .paint(row, col, hue_to_color((row + col * 7) % 360));
+        }
+    }
+    // Some glyphs too, with a gap between them so that the colours underneath show
+    let text_row = "Paint 2D ".repeat(width as usize / 9 + 1);
+    let text = vec![text_row; height as usize / 2].join("\n\n");
+    app.canvas
+        .import_text(&text, &HashMap::new(), TextOverflow::Clip, None);
+
+    app.render_screen()?;
+    let mut bytes = 0;
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        app.stdout.clear();
+        app.render_screen()?;
+        bytes += app.stdout.len();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "Drew {} frames of a {}x{} terminal in {:.2?}",
+        FRAMES, TERMINAL_SIZE.0, TERMINAL_SIZE.1, elapsed
+    );
+    println!("{:.2?} per frame", elapsed / FRAMES);
+    println!("{} bytes of output per frame", bytes / FRAMES as usize);
+    Ok(())
+}
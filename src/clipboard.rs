@@ -0,0 +1,97 @@
+//! Access to the system clipboard, using whichever clipboard program the platform provides
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+/// The programs that can copy to the clipboard (reading from stdin), in order to try them
+const COPY_PROGRAMS: &[&[&str]] = if cfg!(windows) {
+    &[&["clip.exe"]]
+} else if cfg!(target_os = "macos") {
+    &[&["pbcopy"]]
+} else {
+    &[
+        &["wl-copy"],
+        &["xclip", "-selection", "clipboard"],
+        &["xsel", "--clipboard", "--input"],
+    ]
+};
+
+/// The programs that can print out what's on the clipboard, in order to try them
+const PASTE_PROGRAMS: &[&[&str]] = if cfg!(windows) {
+    &[&["powershell.exe", "-NoProfile", "-Command", "Get-Clipboard"]]
+} else if cfg!(target_os = "macos") {
+    &[&["pbpaste"]]
+} else {
+    &[
+        &["wl-paste", "--no-newline"],
+        &["xclip", "-selection", "clipboard", "-out"],
+        &["xsel", "--clipboard", "--output"],
+    ]
+};
+
+/// Checks that there's a clipboard to use. On Linux and other Unix-likes, there's only a
+/// clipboard if we're running in a graphical session.
+fn check_clipboard_exists() -> Result<(), String> {
+    let has_display = ["WAYLAND_DISPLAY", "DISPLAY"]
+        .iter()
+        .any(|name| std::env::var_os(name).is_some_and(|value| !value.is_empty()));
+    if cfg!(any(windows, target_os = "macos")) || has_display {
+        Ok(())
+    } else {
+        Err("There's no clipboard, since this isn't a graphical session".to_string())
+    }
+}
+
+/// Puts some text on the system clipboard
+pub fn copy(text: &str) -> Result<(), String> {
+    check_clipboard_exists()?;
+    for program in COPY_PROGRAMS {
+        let Ok(mut child) = Command::new(program[0])
+            .args(&program[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            // The program probably isn't installed, so try the next one
+            continue;
+        };
+        let written = child
+            .stdin
+            .take()
+            .is_some_and(|mut stdin| stdin.write_all(text.as_bytes()).is_ok());
+        if child.wait().is_ok_and(|status| status.success()) && written {
+            return Ok(());
+        }
+    }
+    Err(no_program_error(COPY_PROGRAMS))
+}
+
+/// Gets the text that's on the system clipboard
+pub fn paste() -> Result<String, String> {
+    check_clipboard_exists()?;
+    for program in PASTE_PROGRAMS {
+        let Ok(output) = Command::new(program[0])
+            .args(&program[1..])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+        else {
+            continue;
+        };
+        if output.status.success() {
+            return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+        }
+    }
+    Err(no_program_error(PASTE_PROGRAMS))
+}
+
+fn no_program_error(programs: &[&[&str]]) -> String {
+    let names: Vec<&str> = programs.iter().map(|program| program[0]).collect();
+    format!(
+        "Couldn't use the clipboard. Make sure one of these is installed: {}",
+        names.join(", ")
+    )
+}
@@ -0,0 +1,80 @@
+//! Writing canvases as HTML pages that look like a screenshot of the terminal, so that
+//! pictures can be shared on the web with their glyphs intact
+
+use crate::{Canvas, Color, Glyph, color_to_rgb};
+
+/// Writes a colour as a CSS hex code, or None for the terminal's default colour
+fn css_color(color: Color) -> Option<String> {
+    if color == Color::Reset {
+        return None;
+    }
+    let [r, g, b] = color_to_rgb(color);
+    Some(format!("#{:02x}{:02x}{:02x}", r, g, b))
+}
+
+/// Writes the text of a run of cells, escaping anything that HTML would treat specially
+fn push_escaped(html: &mut String, text: &str) {
+    for char in text.chars() {
+        match char {
+            '<' => *html += "&lt;",
+            '>' => *html += "&gt;",
+            '&' => *html += "&amp;",
+            _ => html.push(char),
+        }
+    }
+}
+
+/// Writes a run of cells that all have the same colours, as a `<span>` if they have any
+fn push_run(html: &mut String, style: &(Option<String>, Option<String>), text: &str) {
+    let declarations: Vec<String> = [("background", &style.0), ("color", &style.1)]
+        .iter()
+        .filter_map(|(property, value)| Some(format!("{}:{}", property, value.as_ref()?)))
+        .collect();
+    if declarations.is_empty() {
+        push_escaped(html, text);
+        return;
+    }
+    *html += &format!("<span style=\"{}\">", declarations.join(";"));
+    push_escaped(html, text);
+    *html += "</span>";
+}
+
+/// Writes the canvas as an HTML page with a `<pre>` block in it. Painted cells become
+/// spaces with a background colour, and glyphs keep their character and colour. Next to
+/// each other, cells with the same colours share a `<span>`, to keep the file small.
+/// Transparent and erased cells have no background, so they show the page's background.
+pub fn to_html(canvas: &Canvas) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n\
+         <html>\n\
+         <head>\n\
+         <meta charset=\"utf-8\">\n\
+         <title>Made with Paint 2D</title>\n\
+         </head>\n\
+         <body>\n\
+         <pre style=\"font-family:monospace;line-height:1\">\n",
+    );
+    for row in 0..canvas.height() {
+        let mut run = (None, None);
+        let mut run_text = String::new();
+        for col in 0..canvas.width() {
+            let background = canvas.get_cell(row, col).flatten().and_then(css_color);
+            let (foreground, char) = match canvas.get_glyph(row, col).flatten() {
+                Some(Glyph { char, color }) => (css_color(color), char),
+                // Spaces don't need a colour, so they can join the run that's going
+                None => (run.1.clone(), ' '),
+            };
+            let style = (background, foreground);
+            if style != run {
+                push_run(&mut html, &run, &run_text);
+                run = style;
+                run_text.clear();
+            }
+            run_text.push(char);
+        }
+        push_run(&mut html, &run, &run_text);
+        html.push('\n');
+    }
+    html += "</pre>\n</body>\n</html>\n";
+    html
+}
@@ -0,0 +1,100 @@
+//! A Unix socket that lets other programs look at and change the canvas while the app is
+//! running. Built with `--features ipc`, and opened with `--socket <path>`.
+//!
+//! Each line sent to the socket is a command, and each command gets a reply that starts
+//! with `OK` or `ERROR`. Connections are handled on their own threads, but the commands
+//! are passed over to the main thread, so that they can't happen in the middle of drawing.
+
+#[cfg(not(unix))]
+compile_error!("The ipc feature uses Unix sockets, so it only works on Unix-like systems");
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+/// A command from a program connected to the socket, waiting for its reply
+pub struct Request {
+    pub command: String,
+    reply: Sender<String>,
+}
+
+impl Request {
+    /// Sends the reply back to whoever sent the command. It can be several lines long.
+    pub fn reply(self, text: String) {
+        // The other program could have disconnected already, which is fine
+        let _ = self.reply.send(text);
+    }
+}
+
+/// The socket, and the commands that have come in through it
+pub struct Server {
+    path: PathBuf,
+    requests: Receiver<Request>,
+}
+
+impl Server {
+    /// Starts listening on a socket at `path`, replacing any socket that's left over there
+    /// from last time
+    pub fn start(path: &Path) -> Result<Server, String> {
+        if std::fs::symlink_metadata(path)
+            .is_ok_and(|metadata| std::os::unix::fs::FileTypeExt::is_socket(&metadata.file_type()))
+        {
+            let _ = std::fs::remove_file(path);
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|error| format!("Couldn't open a socket at {}: {}", path.display(), error))?;
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let sender = sender.clone();
+                thread::spawn(move || serve(stream, sender));
+            }
+        });
+        Ok(Server {
+            path: path.to_path_buf(),
+            requests,
+        })
+    }
+
+    /// The next command that's waiting to be handled, if there is one
+    pub fn next_request(&self) -> Option<Request> {
+        self.requests.try_recv().ok()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Reads commands from a connection, one per line, and writes back their replies
+fn serve(stream: UnixStream, requests: Sender<Request>) {
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        let (reply, reply_receiver) = mpsc::channel();
+        let request = Request {
+            command: line,
+            reply,
+        };
+        // Stop once the app has quit
+        if requests.send(request).is_err() {
+            return;
+        }
+        let Ok(reply) = reply_receiver.recv() else {
+            return;
+        };
+        if writeln!(writer, "{}", reply).is_err() {
+            return;
+        }
+    }
+}
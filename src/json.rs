@@ -0,0 +1,441 @@
+//! A small JSON reader and writer, used for saving sessions to disk
+
+use std::fmt::{self, Display, Write};
+
+/// A JSON value. The keys of objects keep the order that they were written in.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Parses a JSON document, which must contain exactly one value
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser {
+            chars: text.chars().collect(),
+            pos: 0,
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("Unexpected text after the end of the JSON"));
+        }
+        Ok(value)
+    }
+
+    /// Looks up a key in an object. Returns None if it's missing or this isn't an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(entries) => entries
+                .iter()
+                .find(|(entry_key, _)| entry_key == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(bool) => Some(*bool),
+            _ => None,
+        }
+    }
+
+    /// The value as a whole number, if it is one
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(number) if number.fract() == 0.0 && *number >= 0.0 => Some(*number as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Json::String(value)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Json::Bool(value)
+    }
+}
+
+impl From<u16> for Json {
+    fn from(value: u16) -> Self {
+        Json::Number(value.into())
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+/// Writes the value as compact JSON
+impl Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(bool) => write!(f, "{}", bool),
+            Json::Number(number) => write!(f, "{}", number),
+            Json::String(string) => write_string(f, string),
+            Json::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_char(']')
+            }
+            Json::Object(entries) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, string: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for char in string.chars() {
+        match char {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            char if char.is_control() => write!(f, "\\u{:04x}", char as u32)?,
+            char => f.write_char(char)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// How deeply arrays and objects can be nested inside each other. Parsing them recurses, so
+/// without a limit, a file that's nothing but `[[[[...` would overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    /// How many arrays and objects the parser is inside of
+    depth: usize,
+}
+
+impl Parser {
+    fn error(&self, message: &str) -> String {
+        format!("{} (at character {})", message, self.pos + 1)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    /// Moves past the next character if it's the one we expect
+    fn eat(&mut self, expected: char) -> bool {
+        self.skip_whitespace();
+        if self.chars.get(self.pos) == Some(&expected) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        if self.eat(expected) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("Expected '{}'", expected)))
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str, value: Json) -> Result<Json, String> {
+        for expected in keyword.chars() {
+            if self.chars.get(self.pos) != Some(&expected) {
+                return Err(self.error("Unknown keyword"));
+            }
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.chars.get(self.pos) {
+            Some('n') => self.keyword("null", Json::Null),
+            Some('t') => self.keyword("true", Json::Bool(true)),
+            Some('f') => self.keyword("false", Json::Bool(false)),
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('[') => self.nested(Parser::array),
+            Some('{') => self.nested(Parser::object),
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.number(),
+            Some(_) => Err(self.error("Unexpected character")),
+            None => Err(self.error("Unexpected end of the JSON")),
+        }
+    }
+
+    /// Parses an array or object inside whatever is being parsed already, as long as that
+    /// doesn't nest them too deeply
+    fn nested(&mut self, parse: fn(&mut Parser) -> Result<Json, String>) -> Result<Json, String> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error("The JSON is nested too deeply"));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        if !self.eat(']') {
+            loop {
+                items.push(self.value()?);
+                if self.eat(']') {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+        Ok(Json::Array(items))
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.pos += 1;
+        let mut entries = Vec::new();
+        if !self.eat('}') {
+            loop {
+                self.skip_whitespace();
+                let key = self.string()?;
+                self.expect(':')?;
+                entries.push((key, self.value()?));
+                if self.eat('}') {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+        Ok(Json::Object(entries))
+    }
+
+    /// Moves past any digits, returning how many there were
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while self.chars.get(self.pos).is_some_and(char::is_ascii_digit) {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    /// Parses a number, which has to be written the way JSON allows: an optional minus
+    /// sign, digits without extra leading zeros, then an optional fraction and exponent
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.pos;
+        self.eat_char('-');
+        let invalid = |parser: &Parser| Err(parser.error("Invalid number"));
+        match self.chars.get(self.pos) {
+            Some('0') => self.pos += 1,
+            Some('1'..='9') => {
+                self.digits();
+            }
+            _ => return invalid(self),
+        }
+        if self.eat_char('.') && self.digits() == 0 {
+            return invalid(self);
+        }
+        if self.eat_char('e') || self.eat_char('E') {
+            if !self.eat_char('+') {
+                self.eat_char('-');
+            }
+            if self.digits() == 0 {
+                return invalid(self);
+            }
+        }
+        let number: String = self.chars[start..self.pos].iter().collect();
+        number.parse().map(Json::Number).or_else(|_| invalid(self))
+    }
+
+    /// Moves past the next character if it's the one we expect, without skipping whitespace
+    fn eat_char(&mut self, expected: char) -> bool {
+        let found = self.chars.get(self.pos) == Some(&expected);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    /// Reads the 4 hex digits of a `\u` escape
+    fn unicode_escape(&mut self) -> Result<u32, String> {
+        let hex: String = self.chars.iter().skip(self.pos).take(4).collect();
+        if hex.len() != 4 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(self.error("Invalid unicode escape"));
+        }
+        self.pos += 4;
+        u32::from_str_radix(&hex, 16).map_err(|_| self.error("Invalid unicode escape"))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.chars.get(self.pos) != Some(&'"') {
+            return Err(self.error("Expected a string"));
+        }
+        self.pos += 1;
+        let mut string = String::new();
+        loop {
+            let Some(char) = self.chars.get(self.pos).copied() else {
+                return Err(self.error("Unterminated string"));
+            };
+            self.pos += 1;
+            match char {
+                '"' => return Ok(string),
+                '\\' => {
+                    let Some(escape) = self.chars.get(self.pos).copied() else {
+                        return Err(self.error("Unterminated string"));
+                    };
+                    self.pos += 1;
+                    string.push(match escape {
+                        '"' => '"',
+                        '\\' => '\\',
+                        '/' => '/',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        'u' => {
+                            let mut code = self.unicode_escape()?;
+                            // Characters outside the Basic Multilingual Plane are written as
+                            // a pair of UTF-16 surrogates
+                            if (0xD800..0xDC00).contains(&code) {
+                                if !(self.eat_char('\\') && self.eat_char('u')) {
+                                    return Err(self.error("Unpaired surrogate in unicode escape"));
+                                }
+                                let low = self.unicode_escape()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err(self.error("Unpaired surrogate in unicode escape"));
+                                }
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            char::from_u32(code)
+                                .ok_or_else(|| self.error("Invalid unicode escape"))?
+                        }
+                        _ => return Err(self.error("Invalid escape sequence")),
+                    });
+                }
+                char => string.push(char),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let value = Json::Object(vec![
+            ("null".to_string(), Json::Null),
+            ("bool".to_string(), true.into()),
+            (
+                "numbers".to_string(),
+                Json::Array(vec![
+                    Json::Number(0.0),
+                    Json::Number(-12.5),
+                    Json::Number(1e-7),
+                    Json::Number(65535.0),
+                ]),
+            ),
+            ("string".to_string(), "\"quoted\" \\ \n\t\u{1} ✓ 😀".into()),
+            ("empty".to_string(), Json::Object(Vec::new())),
+        ]);
+        assert_eq!(Json::parse(&value.to_string()), Ok(value));
+    }
+
+    #[test]
+    fn parses_escapes_and_whitespace() {
+        assert_eq!(
+            Json::parse(" [ \"\\u00e9\\ud83d\\ude00\\/\" , 1E+2 ] "),
+            Ok(Json::Array(vec!["é😀/".into(), Json::Number(100.0)]))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        for text in [
+            "",
+            "[1,]",
+            "[1 2]",
+            "{\"a\" 1}",
+            "{a: 1}",
+            "\"unterminated",
+            "nul",
+            "1 2",
+            "01",
+            "1.",
+            ".5",
+            "-",
+            "1e",
+            "+1",
+            "1-2",
+            "1.2.3",
+            "\"\\x\"",
+            "\"\\u12\"",
+            "\"\\u+123\"",
+            "\"\\ud83d\"",
+            "\"\\ud83d\\u0041\"",
+            "\"\\ude00\"",
+        ] {
+            assert!(Json::parse(text).is_err(), "{:?} was accepted", text);
+        }
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error_instead_of_a_crash() {
+        let nested = |depth| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(Json::parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(Json::parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(Json::parse(&"[{\"a\":".repeat(100_000)).is_err());
+    }
+}
@@ -0,0 +1,2096 @@
+//! The canvas model behind Paint 2D: a grid of coloured cells, the operations that can be
+//! done on it, and undo history. None of this depends on a terminal, so it can be used with
+//! any renderer and any way of handling input.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use image::{Rgb, RgbImage};
+use unicode_width::UnicodeWidthChar;
+
+pub use crossterm::style::Color;
+
+use crate::json::Json;
+
+pub mod ansi;
+pub mod html;
+pub mod json;
+pub mod rust_source;
+
+/// How many changes can be undone before the oldest ones are forgotten
+pub const MAX_UNDO_STEPS: usize = 100;
+/// How much memory the undo history can use by default, in bytes
+pub const DEFAULT_UNDO_MEMORY_LIMIT: usize = 256 * 1024 * 1024;
+/// The most cells that a canvas is allowed to have, to stop it from using a silly amount of memory
+pub const MAX_CANVAS_CELLS: usize = 1_000_000;
+
+/// A rectangular region of the canvas. Both corners are inclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Selection {
+    pub top: u16,
+    pub left: u16,
+    pub bottom: u16,
+    pub right: u16,
+}
+
+impl Selection {
+    /// Makes a selection spanning between two opposite corners, given as `(row, col)`
+    pub fn from_corners(a: (u16, u16), b: (u16, u16)) -> Self {
+        Selection {
+            top: a.0.min(b.0),
+            left: a.1.min(b.1),
+            bottom: a.0.max(b.0),
+            right: a.1.max(b.1),
+        }
+    }
+
+    pub fn contains(&self, row: u16, col: u16) -> bool {
+        (self.top..=self.bottom).contains(&row) && (self.left..=self.right).contains(&col)
+    }
+
+    /// The area covered by both this selection and another one, if they overlap
+    pub fn intersection(&self, other: &Selection) -> Option<Selection> {
+        let intersection = Selection {
+            top: self.top.max(other.top),
+            left: self.left.max(other.left),
+            bottom: self.bottom.min(other.bottom),
+            right: self.right.min(other.right),
+        };
+        let overlaps =
+            intersection.top <= intersection.bottom && intersection.left <= intersection.right;
+        overlaps.then_some(intersection)
+    }
+
+    /// The same sized area, but with its top-left corner at a different `(row, col)`
+    pub fn moved_to(&self, (top, left): (u16, u16)) -> Selection {
+        Selection {
+            top,
+            left,
+            bottom: top + (self.bottom - self.top),
+            right: left + (self.right - self.left),
+        }
+    }
+}
+
+/// How much of the carried colour is blended into each cell by `Canvas::smudge`, from 0 to 1
+const SMUDGE_STRENGTH: f32 = 0.5;
+
+/// Converts a colour to RGB. Named colours are based on the default Kitty colours.
+pub fn color_to_rgb(color: Color) -> [u8; 3] {
+    match color {
+        Color::Reset => [0, 0, 0],
+        Color::Black => [0, 0, 0],
+        Color::White => [255, 255, 255],
+        Color::Red => [242, 31, 30],
+        Color::Green => [34, 253, 0],
+        Color::Yellow => [254, 253, 0],
+        Color::Blue => [25, 143, 255],
+        Color::Magenta => [253, 39, 255],
+        Color::Cyan => [19, 255, 254],
+        Color::Grey => [221, 221, 221],
+        Color::DarkGrey => [118, 118, 118],
+        Color::DarkRed => [204, 4, 3],
+        Color::DarkGreen => [25, 203, 0],
+        Color::DarkYellow => [206, 203, 0],
+        Color::DarkBlue => [13, 115, 204],
+        Color::DarkMagenta => [203, 30, 209],
+        Color::DarkCyan => [13, 205, 205],
+        Color::Rgb { r, g, b } => [r, g, b],
+        // Any other colours aren't supported by the program, so we treat them as black
+        _ => [0, 0, 0],
+    }
+}
+
+/// How bright a colour looks, from 0 (black) to 255 (white). Green looks brighter than
+/// red, which looks brighter than blue.
+pub fn luminance(color: Color) -> u8 {
+    let [r, g, b] = color_to_rgb(color);
+    (0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32).round() as u8
+}
+
+/// The characters used to shade colours when drawing without colours, from darkest to
+/// brightest
+pub const DEFAULT_SHADE_RAMP: &[char] = &[' ', '░', '▒', '▓', '█'];
+
+/// A character from `ramp` that's filled in more for brighter colours, for drawing without
+/// colours. The ramp goes from darkest to brightest, and shouldn't be empty.
+pub fn shade_char(color: Color, ramp: &[char]) -> char {
+    ramp[luminance(color) as usize * ramp.len() / 256]
+}
+
+/// Checks that a string can be used as a shade ramp (see `shade_char`), and splits it
+/// into characters
+pub fn parse_shade_ramp(ramp: &str) -> Result<Vec<char>, String> {
+    let chars: Vec<char> = ramp.chars().collect();
+    if chars.is_empty() {
+        return Err("The shade ramp needs at least one character".to_string());
+    }
+    if let Some(char) = chars.iter().find(|char| char_width(**char) != 1) {
+        return Err(format!(
+            "Each character in the shade ramp has to be one column wide, but \"{}\" isn't",
+            char
+        ));
+    }
+    Ok(chars)
+}
+
+/// Named colours that `rgb_to_color` will convert back from RGB
+pub const NAMED_COLORS: [Color; 16] = [
+    Color::Black,
+    Color::White,
+    Color::Red,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::Grey,
+    Color::DarkGrey,
+    Color::DarkRed,
+    Color::DarkGreen,
+    Color::DarkYellow,
+    Color::DarkBlue,
+    Color::DarkMagenta,
+    Color::DarkCyan,
+];
+
+/// Gives the name of a colour, so that it can be read back with `parse_color`.
+/// Colours without a name are written as hex codes.
+pub fn color_name(color: Color) -> String {
+    let name = match color {
+        Color::Reset => "reset",
+        Color::Black => "black",
+        Color::White => "white",
+        Color::Red => "red",
+        Color::Green => "green",
+        Color::Yellow => "yellow",
+        Color::Blue => "blue",
+        Color::Magenta => "magenta",
+        Color::Cyan => "cyan",
+        Color::Grey => "grey",
+        Color::DarkGrey => "dark-grey",
+        Color::DarkRed => "dark-red",
+        Color::DarkGreen => "dark-green",
+        Color::DarkYellow => "dark-yellow",
+        Color::DarkBlue => "dark-blue",
+        Color::DarkMagenta => "dark-magenta",
+        Color::DarkCyan => "dark-cyan",
+        _ => {
+            let [r, g, b] = color_to_rgb(color);
+            return format!("#{:02x}{:02x}{:02x}", r, g, b);
+        }
+    };
+    name.to_string()
+}
+
+/// Parses a colour from its name (e.g. `red` or `dark-blue`) or a hex code (e.g. `#ff8000`).
+/// `reset` is the terminal's default colour, which is what the eraser paints with.
+pub fn parse_color(text: &str) -> Option<Color> {
+    if let Some(hex) = text.strip_prefix('#') {
+        if hex.len() != 6 || !hex.is_ascii() {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+        return Some(Color::Rgb {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        });
+    }
+    let color = match text.to_lowercase().replace(['-', '_', ' '], "").as_str() {
+        "reset" => Color::Reset,
+        "black" => Color::Black,
+        "white" => Color::White,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "grey" | "gray" => Color::Grey,
+        "darkgrey" | "darkgray" => Color::DarkGrey,
+        "darkred" => Color::DarkRed,
+        "darkgreen" => Color::DarkGreen,
+        "darkyellow" => Color::DarkYellow,
+        "darkblue" => Color::DarkBlue,
+        "darkmagenta" => Color::DarkMagenta,
+        "darkcyan" => Color::DarkCyan,
+        _ => return None,
+    };
+    Some(color)
+}
+
+/// Parses a mapping from characters to colours, written as TOML key/value pairs
+/// (e.g. `"#" = "dark-green"`). Comments and table headers are ignored.
+pub fn parse_color_map(text: &str) -> Result<HashMap<char, Color>, String> {
+    fn unquote(text: &str) -> &str {
+        let text = text.trim();
+        ['"', '\'']
+            .iter()
+            .find_map(|quote| text.strip_prefix(*quote)?.strip_suffix(*quote))
+            .unwrap_or(text)
+    }
+    let mut colors = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with('[') {
+            continue;
+        }
+        // Split on the last `=` so that `"=" = "red"` works
+        let Some((key, value)) = line.rsplit_once('=') else {
+            return Err(format!(
+                "Line {}: expected \"<character>\" = \"<colour>\"",
+                i + 1
+            ));
+        };
+        // The value could be followed by a comment, but colours never contain spaces
+        let value = unquote(value.split_whitespace().next().unwrap_or(""));
+        let mut key_chars = unquote(key).chars();
+        let (Some(char), None) = (key_chars.next(), key_chars.next()) else {
+            return Err(format!("Line {}: expected a single character", i + 1));
+        };
+        let color = parse_color(value)
+            .ok_or_else(|| format!("Line {}: unknown colour \"{}\"", i + 1, value))?;
+        colors.insert(char, color);
+    }
+    Ok(colors)
+}
+
+/// Converts RGB to a colour, preferring a named colour if one matches exactly.
+/// This means converting a named colour to RGB and back gives the same colour.
+pub fn rgb_to_color(rgb: [u8; 3]) -> Color {
+    NAMED_COLORS
+        .into_iter()
+        .find(|color| color_to_rgb(*color) == rgb)
+        .unwrap_or(Color::Rgb {
+            r: rgb[0],
+            g: rgb[1],
+            b: rgb[2],
+        })
+}
+
+/// Picks up to `count` colours that represent a list of RGB colours well, using the
+/// median cut algorithm. The colours are split into groups by repeatedly halving the group
+/// with the widest range of red, green or blue, then each group is averaged into one colour.
+/// Colours that appear more often in the list have more say in the result.
+pub fn median_cut(colors: &[[u8; 3]], count: usize) -> Vec<[u8; 3]> {
+    if colors.is_empty() || count == 0 {
+        return Vec::new();
+    }
+    // The channel (0 for red, 1 for green or 2 for blue) with the widest range in a group
+    let widest_channel = |group: &[[u8; 3]]| {
+        (0..3)
+            .map(|channel| {
+                let values = group.iter().map(|color| color[channel]);
+                let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+                (channel, range)
+            })
+            .max_by_key(|(_, range)| *range)
+            .unwrap_or((0, 0))
+    };
+    let mut groups = vec![colors.to_vec()];
+    while groups.len() < count {
+        let widest = groups
+            .iter()
+            .enumerate()
+            .map(|(i, group)| (i, widest_channel(group)))
+            .filter(|(_, (_, range))| *range > 0)
+            .max_by_key(|(_, (_, range))| *range);
+        // Every group is a single colour already
+        let Some((index, (channel, _))) = widest else {
+            break;
+        };
+        let mut lower = groups.swap_remove(index);
+        lower.sort_unstable_by_key(|color| color[channel]);
+        let upper = lower.split_off(lower.len() / 2);
+        groups.push(lower);
+        groups.push(upper);
+    }
+    groups
+        .iter()
+        .map(|group| {
+            std::array::from_fn(|channel| {
+                let total: usize = group.iter().map(|color| color[channel] as usize).sum();
+                (total / group.len()) as u8
+            })
+        })
+        .collect()
+}
+
+/// The fully saturated, full brightness colour with a certain hue, in degrees around the
+/// colour wheel (0 is red, 120 is green, and 240 is blue)
+pub fn hue_to_color(hue: u16) -> Color {
+    let hue = hue % 360;
+    // How far through the current sixth of the colour wheel the hue is
+    let rising = ((hue % 60) as u32 * 255 / 60) as u8;
+    let falling = 255 - rising;
+    let [r, g, b] = match hue / 60 {
+        0 => [255, rising, 0],
+        1 => [falling, 255, 0],
+        2 => [0, 255, rising],
+        3 => [0, falling, 255],
+        4 => [rising, 0, 255],
+        _ => [255, 0, falling],
+    };
+    Color::Rgb { r, g, b }
+}
+
+/// Works out which cells a round brush of a certain size covers, when it's centred on a
+/// `(row, col)` point. The size is the brush's width in cells. Brushes with an even size
+/// can't be centred on a cell, so they reach further down and to the right.
+pub fn brush_points((row, col): (i32, i32), size: u16) -> Vec<(i32, i32)> {
+    let size = i32::from(size.max(1));
+    let radius = size as f32 / 2.0;
+    let start = -(size - 1) / 2;
+    let mut points = Vec::new();
+    for i in 0..size {
+        for j in 0..size {
+            // How far the middle of this cell is from the middle of the brush
+            let (y, x) = (i as f32 + 0.5 - radius, j as f32 + 0.5 - radius);
+            if y * y + x * x <= radius * radius {
+                points.push((row + start + i, col + start + j));
+            }
+        }
+    }
+    points
+}
+
+/// Works out which cells a straight line between two `(row, col)` points passes through,
+/// using Bresenham's line algorithm. Both ends of the line are included.
+pub fn line_points(from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+    let (mut row, mut col) = from;
+    let row_distance = -(to.0 - from.0).abs();
+    let col_distance = (to.1 - from.1).abs();
+    let row_step = if from.0 < to.0 { 1 } else { -1 };
+    let col_step = if from.1 < to.1 { 1 } else { -1 };
+    let mut error = col_distance + row_distance;
+    let mut points = Vec::new();
+    loop {
+        points.push((row, col));
+        if (row, col) == to {
+            break;
+        }
+        let doubled_error = 2 * error;
+        if doubled_error >= row_distance {
+            error += row_distance;
+            col += col_step;
+        }
+        if doubled_error <= col_distance {
+            error += col_distance;
+            row += row_step;
+        }
+    }
+    points
+}
+
+/// Works out which cells a quadratic Bézier curve passes through. The curve starts at
+/// `start` and ends at `end`, bending towards `control` (which it doesn't usually reach).
+/// Points along the curve are joined up with straight lines, so there aren't any gaps.
+pub fn curve_points(start: (i32, i32), control: (i32, i32), end: (i32, i32)) -> Vec<(i32, i32)> {
+    let distance = |a: (i32, i32), b: (i32, i32)| (a.0 - b.0).abs().max((a.1 - b.1).abs());
+    // The curve is never longer than the lines to and from the control point, so sampling
+    // this often gives at most one cell between samples
+    let samples = (distance(start, control) + distance(control, end)).max(1);
+    let point_at = |t: f32| {
+        let along = |a: i32, b: i32, c: i32| {
+            let (a, b, c) = (a as f32, b as f32, c as f32);
+            ((1.0 - t).powi(2) * a + 2.0 * (1.0 - t) * t * b + t.powi(2) * c).round() as i32
+        };
+        (
+            along(start.0, control.0, end.0),
+            along(start.1, control.1, end.1),
+        )
+    };
+    let mut points = vec![start];
+    let mut previous = start;
+    for i in 1..=samples {
+        let next = point_at(i as f32 / samples as f32);
+        if next != previous {
+            // Skip the first point of each segment, since the last one ended there
+            points.extend(line_points(previous, next).into_iter().skip(1));
+            previous = next;
+        }
+    }
+    points
+}
+
+/// Works out which cells are inside a polygon with the given `(row, col)` vertices, by
+/// checking whether the centre of each cell is inside it (the vertices are at the centres of
+/// their cells). Uses the even-odd rule, so concave and self-intersecting polygons are
+/// handled properly.
+pub fn polygon_interior(vertices: &[(u16, u16)]) -> Vec<(u16, u16)> {
+    let Some(top) = vertices.iter().map(|vertex| vertex.0).min() else {
+        return Vec::new();
+    };
+    let bottom = vertices.iter().map(|vertex| vertex.0).max().unwrap_or(top);
+    let mut cells = Vec::new();
+    for row in top..=bottom {
+        let scanline = f64::from(row);
+        // Find where this row crosses each edge of the polygon
+        let mut crossings: Vec<f64> = Vec::new();
+        for (i, start) in vertices.iter().enumerate() {
+            let end = vertices[(i + 1) % vertices.len()];
+            let (start_row, start_col) = (f64::from(start.0), f64::from(start.1));
+            let (end_row, end_col) = (f64::from(end.0), f64::from(end.1));
+            if (start_row <= scanline) != (end_row <= scanline) {
+                let progress = (scanline - start_row) / (end_row - start_row);
+                crossings.push(start_col + progress * (end_col - start_col));
+            }
+        }
+        crossings.sort_by(f64::total_cmp);
+        // Every other gap between crossings is inside the polygon
+        for span in crossings.chunks_exact(2) {
+            let first_col = span[0].ceil().max(0.0) as u16;
+            let last_col = span[1].floor();
+            if last_col < 0.0 {
+                continue;
+            }
+            for col in first_col..=last_col as u16 {
+                cells.push((row, col));
+            }
+        }
+    }
+    cells
+}
+
+/// Picks the quarter-block character that fills one corner of a cell
+fn quarter_block(top: bool, left: bool) -> char {
+    match (top, left) {
+        (true, true) => '▘',
+        (true, false) => '▝',
+        (false, true) => '▖',
+        (false, false) => '▗',
+    }
+}
+
+/// Resizes a grid of cells, keeping the cells that fit in the new size and padding
+/// any new space with `fill`
+fn resize_grid<T: Clone>(grid: &mut Vec<Vec<Option<T>>>, width: u16, height: u16, fill: Option<T>) {
+    for row in grid.iter_mut() {
+        row.resize(width.into(), fill.clone());
+    }
+    grid.resize(height.into(), vec![fill; width.into()]);
+}
+
+/// Stretches or shrinks a grid to a new size, using the nearest cell for each new cell
+fn rescale_grid<T: Clone>(grid: &mut Vec<Vec<Option<T>>>, width: u16, height: u16) {
+    let (old_height, old_width) = (grid.len(), grid.first().map_or(0, Vec::len));
+    if old_height == 0 || old_width == 0 {
+        return resize_grid(grid, width, height, None);
+    }
+    let nearest = |new: usize, new_size: u16, old_size: usize| new * old_size / new_size as usize;
+    *grid = (0..height as usize)
+        .map(|row| {
+            let old_row = &grid[nearest(row, height, old_height)];
+            (0..width as usize)
+                .map(|col| old_row[nearest(col, width, old_width)].clone())
+                .collect()
+        })
+        .collect();
+}
+
+/// How the picture is kept when the canvas changes size
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResizeMode {
+    /// Keep the picture at the top-left, cutting off anything past the new edges
+    Clip,
+    /// Keep the picture at the top-left, but never make the canvas smaller than it was
+    Pad,
+    /// Stretch or shrink the whole picture to fit the new size
+    Rescale,
+}
+
+/// Checks that a canvas of a certain size is allowed to exist
+pub fn validate_canvas_size(width: u16, height: u16) -> Result<(), String> {
+    if width == 0 || height == 0 {
+        return Err("The canvas must be at least 1x1".to_string());
+    }
+    if usize::from(width) * usize::from(height) > MAX_CANVAS_CELLS {
+        return Err(format!(
+            "The canvas can't have more than {} cells",
+            MAX_CANVAS_CELLS
+        ));
+    }
+    Ok(())
+}
+
+/// Which side of a mask can be painted on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaskMode {
+    /// Only cells outside the masked area can be painted, like a stencil
+    Outside,
+    /// Only cells inside the masked area can be painted
+    Inside,
+}
+
+/// An area of the canvas that limits where paint can go
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mask {
+    pub area: Selection,
+    pub mode: MaskMode,
+}
+
+impl Mask {
+    /// Checks if a cell is allowed to be painted by this mask
+    pub fn allows(&self, row: u16, col: u16) -> bool {
+        match self.mode {
+            MaskMode::Outside => !self.area.contains(row, col),
+            MaskMode::Inside => self.area.contains(row, col),
+        }
+    }
+}
+
+/// A character drawn on top of a cell, e.g. from ASCII art
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Glyph {
+    pub char: char,
+    /// The colour of the character itself. The cell's colour is used as its background.
+    pub color: Color,
+}
+
+impl Glyph {
+    /// Every glyph takes up exactly one cell, so characters that the terminal would draw
+    /// wider or narrower than one column (such as CJK characters, most emoji, and combining
+    /// accents) are replaced with `WIDE_CHAR_PLACEHOLDER` to keep the grid lined up
+    pub fn new(char: char, color: Color) -> Glyph {
+        let char = match char_width(char) {
+            1 => char,
+            _ => WIDE_CHAR_PLACEHOLDER,
+        };
+        Glyph { char, color }
+    }
+}
+
+/// What glyphs show instead of characters that don't take up exactly one column
+pub const WIDE_CHAR_PLACEHOLDER: char = '?';
+
+/// The box-drawing characters that `Canvas::place_connector` uses, indexed by which of the
+/// cells around them they join up with: add 1 for above, 2 for below, 4 for the left and
+/// 8 for the right
+const CONNECTORS: [char; 16] = [
+    '─', '│', '│', '│', '─', '┘', '┐', '┤', '─', '└', '┌', '├', '─', '┴', '┬', '┼',
+];
+
+/// How many terminal columns a character takes up: 0 for control characters and ones
+/// that combine with the character before them, 2 for wide (East Asian and emoji)
+/// characters, and 1 for everything else
+pub fn char_width(char: char) -> usize {
+    if char.is_control() {
+        return 0;
+    }
+    char.width().unwrap_or(0)
+}
+
+/// What to do with lines of text that are too long to fit on the canvas
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextOverflow {
+    /// Cut off the end of the line
+    Clip,
+    /// Carry on with the rest of the line on the next row
+    Wrap,
+}
+
+/// What happens to the parts of a paste that go past the edges of the canvas
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PasteEdges {
+    /// Leave them out
+    Clip,
+    /// Carry on from the opposite edge, as if the canvas was tiled
+    Wrap,
+}
+
+/// A rectangle of cells (and their glyphs) that has been copied from a canvas
+#[derive(Clone)]
+pub struct Clip {
+    cells: Vec<Vec<Option<Color>>>,
+    glyphs: Vec<Vec<Option<Glyph>>>,
+}
+
+impl Clip {
+    pub fn width(&self) -> u16 {
+        self.cells.first().map_or(0, |row| row.len() as u16)
+    }
+
+    pub fn height(&self) -> u16 {
+        self.cells.len() as u16
+    }
+
+    /// The cell and glyph at a position in the clip.
+    /// Returns None instead if `transparent` is true and there's nothing there.
+    fn get(
+        &self,
+        row: usize,
+        col: usize,
+        transparent: bool,
+    ) -> Option<(Option<Color>, Option<Glyph>)> {
+        let (cell, glyph) = (self.cells[row][col], self.glyphs[row][col]);
+        let is_empty = cell.is_none() && glyph.is_none();
+        (!transparent || !is_empty).then_some((cell, glyph))
+    }
+}
+
+/// The contents of a canvas at some point in time, used for undoing changes
+#[derive(Clone)]
+struct Snapshot {
+    cells: Vec<Vec<Option<Color>>>,
+    glyphs: Vec<Vec<Option<Glyph>>>,
+}
+
+impl Snapshot {
+    /// Roughly how much memory the snapshot takes up, in bytes
+    fn memory_size(&self) -> usize {
+        let cell_count = self.cells.first().map_or(0, Vec::len) * self.cells.len();
+        cell_count * (size_of::<Option<Color>>() + size_of::<Option<Glyph>>())
+    }
+}
+
+/// A grid of cells that can each be painted with a colour, or left transparent.
+/// Cells can also have a glyph (a character) drawn on top of them.
+#[derive(Clone)]
+pub struct Canvas {
+    /// A vec of rows. Can be accessed like `cells[row][col]`
+    cells: Vec<Vec<Option<Color>>>,
+    /// The glyphs on top of each cell, laid out the same way as `cells`
+    glyphs: Vec<Vec<Option<Glyph>>>,
+    width: u16,
+    height: u16,
+    /// Snapshots from before each change, with the most recent change last
+    undo_stack: Vec<Snapshot>,
+    /// True if the canvas has been changed since it was last saved
+    unsaved_changes: bool,
+    /// If set, painting operations only affect the cells that the mask allows
+    mask: Option<Mask>,
+    /// True if cells are being painted one by one as part of the same stroke, which
+    /// can all be undone in one go
+    stroke_in_progress: bool,
+    /// The most memory that the undo history can take up, in bytes
+    undo_memory_limit: usize,
+    /// How many cells are painted (not transparent or erased), kept up to date as cells
+    /// change so that it doesn't need to be counted every frame
+    painted_count: usize,
+    /// Changes whenever the picture does (see `Canvas::version`)
+    version: u64,
+    /// How many times a cell has changed colour (see `Canvas::changed_cells`)
+    changed_cells: u64,
+    /// The colour that the canvas was filled with when it was made, which any space added
+    /// when it grows is filled with too
+    background: Option<Color>,
+}
+
+/// The version number for the next change to any canvas, so that no two versions are the same
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(0);
+
+fn next_version() -> u64 {
+    NEXT_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Checks if a cell has been painted, rather than being transparent or erased
+fn is_painted(cell: Option<Color>) -> bool {
+    cell.is_some_and(|color| color != Color::Reset)
+}
+
+impl Canvas {
+    /// Makes a new, fully transparent canvas
+    pub fn new(width: u16, height: u16) -> Self {
+        Canvas {
+            cells: vec![vec![None; width.into()]; height.into()],
+            glyphs: vec![vec![None; width.into()]; height.into()],
+            width,
+            height,
+            undo_stack: Vec::new(),
+            unsaved_changes: false,
+            mask: None,
+            stroke_in_progress: false,
+            undo_memory_limit: DEFAULT_UNDO_MEMORY_LIMIT,
+            painted_count: 0,
+            version: next_version(),
+            changed_cells: 0,
+            background: None,
+        }
+    }
+
+    /// Makes a new canvas with every cell painted one colour
+    pub fn new_filled(width: u16, height: u16, color: Color) -> Self {
+        let mut canvas = Canvas::new(width, height);
+        canvas.cells = vec![vec![Some(color); width.into()]; height.into()];
+        canvas.background = Some(color);
+        canvas.recount_painted();
+        canvas
+    }
+
+    /// A number that changes whenever the colour of any cell changes (including when the
+    /// canvas is resized), for knowing when something worked out from the picture needs to
+    /// be worked out again. Different canvases never have the same version.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// The colours of the cells, stretched or shrunk to a different size using the
+    /// nearest cell for each new one
+    pub fn downscaled(&self, width: u16, height: u16) -> Vec<Vec<Option<Color>>> {
+        let mut cells = self.cells.clone();
+        rescale_grid(&mut cells, width, height);
+        cells
+    }
+
+    /// Finds the `(row, col)` positions of the cells that are different on another canvas,
+    /// either in colour or in their glyph. If the canvases are different sizes, the cells
+    /// that are only on one of them count as different.
+    pub fn diff(&self, other: &Canvas) -> Vec<(u16, u16)> {
+        let width = self.width.max(other.width);
+        let height = self.height.max(other.height);
+        let mut differences = Vec::new();
+        for row in 0..height {
+            for col in 0..width {
+                if self.get_cell(row, col) != other.get_cell(row, col)
+                    || self.get_glyph(row, col) != other.get_glyph(row, col)
+                {
+                    differences.push((row, col));
+                }
+            }
+        }
+        differences
+    }
+
+    /// Compares the opposite edges of the canvas, for checking if it can be tiled without
+    /// seams showing. Returns how many cells don't match between the left and right
+    /// columns, then between the top and bottom rows.
+    pub fn edge_mismatches(&self) -> (usize, usize) {
+        let (width, height) = (self.width(), self.height());
+        let sides = (0..height)
+            .filter(|&row| self.get_cell(row, 0) != self.get_cell(row, width - 1))
+            .count();
+        let ends = (0..width)
+            .filter(|&col| self.get_cell(0, col) != self.get_cell(height - 1, col))
+            .count();
+        (sides, ends)
+    }
+
+    /// How many times a cell has changed colour since the canvas was made. When lots of
+    /// cells change at once (like when undoing), every cell on the canvas is counted.
+    pub fn changed_cells(&self) -> u64 {
+        self.changed_cells
+    }
+
+    /// How many cells are painted (transparent and erased cells don't count)
+    pub fn painted_count(&self) -> usize {
+        self.painted_count
+    }
+
+    /// The colours that are painted on the canvas, with how many cells use each of them.
+    /// They're in the order that they first appear, reading from the top-left.
+    pub fn color_counts(&self) -> Vec<(Color, usize)> {
+        let mut counts: Vec<(Color, usize)> = Vec::new();
+        let mut indexes = HashMap::new();
+        for cell in self.cells.iter().flatten() {
+            let Some(color) = cell.filter(|_| is_painted(*cell)) else {
+                continue;
+            };
+            let index = *indexes.entry(color).or_insert_with(|| {
+                counts.push((color, 0));
+                counts.len() - 1
+            });
+            counts[index].1 += 1;
+        }
+        counts
+    }
+
+    /// Counts the painted cells from scratch, after lots of them have changed at once
+    fn recount_painted(&mut self) {
+        self.version = next_version();
+        self.changed_cells += self.width as u64 * self.height as u64;
+        self.painted_count = self
+            .cells
+            .iter()
+            .flatten()
+            .filter(|cell| is_painted(**cell))
+            .count();
+    }
+
+    /// Changes a cell's colour (which should be on the canvas), keeping the count of
+    /// painted cells up to date. This doesn't save an undo step or check the mask.
+    fn write_cell(&mut self, row: usize, col: usize, cell: Option<Color>) {
+        let old = std::mem::replace(&mut self.cells[row][col], cell);
+        self.version = next_version();
+        if old != cell {
+            self.changed_cells += 1;
+        }
+        match (is_painted(old), is_painted(cell)) {
+            (false, true) => self.painted_count += 1,
+            (true, false) => self.painted_count -= 1,
+            _ => {}
+        }
+    }
+
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /// The cells of the canvas, as a slice of rows
+    pub fn rows(&self) -> &[Vec<Option<Color>>] {
+        &self.cells
+    }
+
+    /// The glyphs on top of the cells, as a slice of rows
+    pub fn glyph_rows(&self) -> &[Vec<Option<Glyph>>] {
+        &self.glyphs
+    }
+
+    /// The colour of a cell, or None if it's off the canvas. The colour itself is None if
+    /// the cell is transparent.
+    pub fn get_cell(&self, row: u16, col: u16) -> Option<Option<Color>> {
+        self.cells.get(row as usize)?.get(col as usize).copied()
+    }
+
+    /// The glyph on top of a cell, or None if it's off the canvas
+    pub fn get_glyph(&self, row: u16, col: u16) -> Option<Option<Glyph>> {
+        self.glyphs.get(row as usize)?.get(col as usize).copied()
+    }
+
+    /// Sets the colour of a cell (None makes it transparent) and removes its glyph, unless
+    /// the mask protects it. Returns false (without changing anything) if it's off the canvas.
+    /// Like `paint`, cells set one after the other get undone together.
+    pub fn set_cell(&mut self, row: u16, col: u16, color: Option<Color>) -> bool {
+        if row >= self.height || col >= self.width {
+            return false;
+        }
+        if self.is_paintable(row, col) {
+            self.start_stroke();
+            self.write_cell(row.into(), col.into(), color);
+            self.glyphs[row as usize][col as usize] = None;
+        }
+        true
+    }
+
+    /// An area covering the whole canvas
+    pub fn full_area(&self) -> Selection {
+        Selection {
+            top: 0,
+            left: 0,
+            bottom: self.height - 1,
+            right: self.width - 1,
+        }
+    }
+
+    pub fn has_unsaved_changes(&self) -> bool {
+        self.unsaved_changes
+    }
+
+    /// Converts the cells and glyphs to JSON, so that they can be saved to a file
+    pub fn to_json(&self) -> Json {
+        let cells = self
+            .cells
+            .iter()
+            .map(|row| {
+                let row = row.iter().map(|cell| cell.map(color_name).into());
+                Json::Array(row.collect())
+            })
+            .collect();
+        let glyphs = self
+            .glyphs
+            .iter()
+            .map(|row| {
+                let row = row.iter().map(|glyph| match glyph {
+                    Some(glyph) => Json::Object(vec![
+                        ("char".to_string(), glyph.char.to_string().into()),
+                        ("color".to_string(), color_name(glyph.color).into()),
+                    ]),
+                    None => Json::Null,
+                });
+                Json::Array(row.collect())
+            })
+            .collect();
+        Json::Object(vec![
+            ("width".to_string(), self.width.into()),
+            ("height".to_string(), self.height.into()),
+            ("cells".to_string(), Json::Array(cells)),
+            ("glyphs".to_string(), Json::Array(glyphs)),
+        ])
+    }
+
+    /// Reads a canvas that was saved with `to_json`. The new canvas starts off with no
+    /// undo history, no mask, and no unsaved changes.
+    pub fn from_json(json: &Json) -> Result<Canvas, String> {
+        let dimension = |key: &str| {
+            json.get(key)
+                .and_then(Json::as_u64)
+                .and_then(|value| u16::try_from(value).ok())
+                .ok_or_else(|| format!("The canvas is missing a valid {}", key))
+        };
+        let (width, height) = (dimension("width")?, dimension("height")?);
+        validate_canvas_size(width, height)?;
+        let mut canvas = Canvas::new(width, height);
+
+        // Goes through each item in a grid, checking that the grid is the right size
+        let for_each_item =
+            |key: &str, f: &mut dyn FnMut(usize, usize, &Json) -> Result<(), String>| {
+                let rows = json
+                    .get(key)
+                    .and_then(Json::as_array)
+                    .filter(|rows| rows.len() == height as usize)
+                    .ok_or_else(|| format!("The canvas should have {} rows of {}", height, key))?;
+                for (row, items) in rows.iter().enumerate() {
+                    let items = items
+                        .as_array()
+                        .filter(|items| items.len() == width as usize)
+                        .ok_or_else(|| {
+                            format!("Row {} of {} should be {} long", row, key, width)
+                        })?;
+                    for (col, item) in items.iter().enumerate() {
+                        f(row, col, item)?;
+                    }
+                }
+                Ok::<(), String>(())
+            };
+        let read_color = |json: &Json| {
+            json.as_str()
+                .and_then(parse_color)
+                .ok_or_else(|| format!("Invalid colour: {}", json))
+        };
+
+        for_each_item("cells", &mut |row, col, item| {
+            if *item != Json::Null {
+                canvas.write_cell(row, col, Some(read_color(item)?));
+            }
+            Ok(())
+        })?;
+        // Older files might not have any glyphs
+        if json.get("glyphs").is_some() {
+            for_each_item("glyphs", &mut |row, col, item| {
+                if *item == Json::Null {
+                    return Ok(());
+                }
+                let mut chars = item
+                    .get("char")
+                    .and_then(Json::as_str)
+                    .unwrap_or("")
+                    .chars();
+                let (Some(char), None) = (chars.next(), chars.next()) else {
+                    return Err(format!("Invalid glyph: {}", item));
+                };
+                let color = read_color(item.get("color").unwrap_or(&Json::Null))?;
+                canvas.glyphs[row][col] = Some(Glyph::new(char, color));
+                Ok(())
+            })?;
+        }
+        Ok(canvas)
+    }
+
+    /// Starting from `(row, col)`, moves in a direction (such as `(0, 1)` for right) until
+    /// reaching a cell that's a different colour to the starting cell, and returns where
+    /// that cell is. If there isn't one, the cell at the edge of the canvas is returned.
+    pub fn find_edge(
+        &self,
+        (row, col): (u16, u16),
+        (row_step, col_step): (i32, i32),
+    ) -> (u16, u16) {
+        // Erased cells look the same as transparent ones
+        let color_at = |row: u16, col: u16| {
+            self.get_cell(row, col)
+                .flatten()
+                .filter(|color| *color != Color::Reset)
+        };
+        let start_color = color_at(row, col);
+        let (mut row, mut col) = (row, col);
+        loop {
+            let next_row = row as i32 + row_step;
+            let next_col = col as i32 + col_step;
+            let (Ok(next_row), Ok(next_col)) = (u16::try_from(next_row), u16::try_from(next_col))
+            else {
+                return (row, col);
+            };
+            if next_row >= self.height || next_col >= self.width {
+                return (row, col);
+            }
+            (row, col) = (next_row, next_col);
+            if color_at(row, col) != start_color {
+                return (row, col);
+            }
+        }
+    }
+
+    /// Tells the canvas that its current state has been saved somewhere
+    pub fn mark_saved(&mut self) {
+        self.unsaved_changes = false;
+    }
+
+    pub fn mask(&self) -> Option<Mask> {
+        self.mask
+    }
+
+    /// Limits where paint can go, or removes the limit if `mask` is None
+    pub fn set_mask(&mut self, mask: Option<Mask>) {
+        self.mask = mask;
+    }
+
+    /// Checks if the mask (if there is one) allows a cell to be painted
+    pub fn is_paintable(&self, row: u16, col: u16) -> bool {
+        self.mask.is_none_or(|mask| mask.allows(row, col))
+    }
+
+    /// Paints a single cell (covering up its glyph), unless the mask protects it.
+    /// Returns false (without changing anything) if it's off the canvas.
+    /// Cells painted one after the other get undone together, until `end_stroke` is called.
+    pub fn paint(&mut self, row: u16, col: u16, color: Color) -> bool {
+        self.set_cell(row, col, Some(color))
+    }
+
+    /// Paints several cells at once, in the same way as `paint`.
+    /// Returns false if any of them are off the canvas (the rest are still painted).
+    pub fn paint_many(&mut self, points: &[(u16, u16)], color: Color) -> bool {
+        self.start_stroke();
+        let mut all_on_canvas = true;
+        for (row, col) in points {
+            all_on_canvas &= *row < self.height && *col < self.width;
+            self.put((*row).into(), (*col).into(), color);
+        }
+        all_on_canvas
+    }
+
+    /// Makes a single cell transparent again and removes its glyph, unless the mask protects it.
+    /// Returns false if it's off the canvas.
+    pub fn erase(&mut self, row: u16, col: u16) -> bool {
+        self.set_cell(row, col, None)
+    }
+
+    /// Places a box-drawing character, picking the one that joins up with the box-drawing
+    /// characters next to it. They get changed to join up with it too, so that lines drawn
+    /// a cell at a time turn into proper corners and junctions. Returns false if it's off
+    /// the canvas. Connectors placed one after the other get undone together, like `paint`.
+    pub fn place_connector(&mut self, row: u16, col: u16, color: Color) -> bool {
+        if row >= self.height || col >= self.width {
+            return false;
+        }
+        self.start_stroke();
+        self.version = next_version();
+        let (row, col) = (row.into(), col.into());
+        self.put_glyph(row, col, Glyph::new(CONNECTORS[0], color));
+        for (row, col) in [
+            (row, col),
+            (row - 1, col),
+            (row + 1, col),
+            (row, col - 1),
+            (row, col + 1),
+        ] {
+            self.join_connector(row, col);
+        }
+        true
+    }
+
+    /// Changes the box-drawing character in a cell to the one that joins up with the cells
+    /// around it
+    fn join_connector(&mut self, row: i32, col: i32) {
+        let Some(glyph) = self.connector_at(row, col) else {
+            return;
+        };
+        let neighbours = [
+            (row - 1, col),
+            (row + 1, col),
+            (row, col - 1),
+            (row, col + 1),
+        ];
+        let joins = neighbours
+            .iter()
+            .enumerate()
+            .filter(|(_, (row, col))| self.connector_at(*row, *col).is_some())
+            .fold(0, |joins, (index, _)| joins | 1 << index);
+        self.put_glyph(row, col, Glyph::new(CONNECTORS[joins], glyph.color));
+    }
+
+    /// The glyph in a cell, if it's one of the box-drawing characters that connectors use
+    fn connector_at(&self, row: i32, col: i32) -> Option<Glyph> {
+        let (row, col) = (u16::try_from(row).ok()?, u16::try_from(col).ok()?);
+        self.get_glyph(row, col)
+            .flatten()
+            .filter(|glyph| CONNECTORS.contains(&glyph.char))
+    }
+
+    /// Places some text (such as ASCII art) onto the canvas as glyphs, starting at the top-left.
+    /// Characters are coloured using `colors`, falling back to the terminal's default colour.
+    /// Rows of text that are shorter than the canvas are padded with empty space, and spaces
+    /// don't get a glyph (so the cell underneath shows through). If `background` is set, the
+    /// cells under the glyphs are painted with it too, so that the text is opaque.
+    pub fn import_text(
+        &mut self,
+        text: &str,
+        colors: &HashMap<char, Color>,
+        overflow: TextOverflow,
+        background: Option<Color>,
+    ) {
+        self.save_undo_step();
+        let mut row = 0;
+        let mut covered_cells = Vec::new();
+        'lines: for line in text.lines() {
+            let chars: Vec<char> = line.chars().filter(|char| !char.is_control()).collect();
+            let chunks: Vec<&[char]> = match overflow {
+                TextOverflow::Clip => vec![&chars[..chars.len().min(self.width.into())]],
+                TextOverflow::Wrap if chars.is_empty() => vec![&[]],
+                TextOverflow::Wrap => chars.chunks(self.width.into()).collect(),
+            };
+            for chunk in chunks {
+                let Some(glyph_row) = self.glyphs.get_mut(row) else {
+                    break 'lines;
+                };
+                for (col, glyph) in glyph_row.iter_mut().enumerate() {
+                    if !self
+                        .mask
+                        .is_none_or(|mask| mask.allows(row as u16, col as u16))
+                    {
+                        continue;
+                    }
+                    *glyph = match chunk.get(col) {
+                        Some(' ') | None => None,
+                        Some(char) => {
+                            covered_cells.push((row, col));
+                            Some(Glyph::new(
+                                *char,
+                                colors.get(char).copied().unwrap_or(Color::Reset),
+                            ))
+                        }
+                    }
+                }
+                row += 1;
+            }
+        }
+        if let Some(background) = background {
+            for (row, col) in covered_cells {
+                self.write_cell(row, col, Some(background));
+            }
+        }
+    }
+
+    /// Draws a polygon with the given `(row, col)` vertices. Its outline is always drawn, and
+    /// its inside is coloured in too if `fill` is true. Parts that are off the canvas are skipped.
+    pub fn draw_polygon(&mut self, vertices: &[(u16, u16)], color: Color, fill: bool) {
+        self.save_undo_step();
+        if fill {
+            for (row, col) in polygon_interior(vertices) {
+                self.put(row.into(), col.into(), color);
+            }
+        }
+        for (i, start) in vertices.iter().enumerate() {
+            let end = vertices[(i + 1) % vertices.len()];
+            let from = (start.0.into(), start.1.into());
+            let to = (end.0.into(), end.1.into());
+            for (row, col) in line_points(from, to) {
+                self.put(row, col, color);
+            }
+        }
+    }
+
+    /// Draws a straight line between two `(row, col)` points. If `smooth` is true, the corners
+    /// where the line steps diagonally get quarter-block glyphs in the line's colour, to
+    /// make it look less like a staircase.
+    pub fn draw_line(&mut self, from: (u16, u16), to: (u16, u16), color: Color, smooth: bool) {
+        self.save_undo_step();
+        let points = line_points((from.0.into(), from.1.into()), (to.0.into(), to.1.into()));
+        for (row, col) in points.iter() {
+            self.put(*row, *col, color);
+        }
+        // Erasing a line shouldn't leave behind any glyphs
+        if !smooth || color == Color::Reset {
+            return;
+        }
+        for step in points.windows(2) {
+            let ((row, col), (next_row, next_col)) = (step[0], step[1]);
+            let (down, right) = (next_row > row, next_col > col);
+            if row == next_row || col == next_col {
+                continue;
+            }
+            // Each corner gets the quarter of the cell that's closest to the line
+            let corners = [
+                ((row, next_col), quarter_block(!down, right)),
+                ((next_row, col), quarter_block(down, !right)),
+            ];
+            for ((row, col), char) in corners {
+                if !points.contains(&(row, col)) {
+                    self.put_glyph(row, col, Glyph { char, color });
+                }
+            }
+        }
+    }
+
+    /// Draws a quadratic Bézier curve (see `curve_points`) in one colour
+    pub fn draw_curve(
+        &mut self,
+        start: (u16, u16),
+        control: (u16, u16),
+        end: (u16, u16),
+        color: Color,
+    ) {
+        self.save_undo_step();
+        let point = |(row, col): (u16, u16)| (row.into(), col.into());
+        for (row, col) in curve_points(point(start), point(control), point(end)) {
+            self.put(row, col, color);
+        }
+    }
+
+    /// Stamps a round brush (see `brush_points`) at every `spacing`th point along a path,
+    /// starting with the first point. A spacing of 1 gives a solid stroke, and bigger
+    /// spacings give dotted or dashed ones. The whole path is undone in one go.
+    pub fn stamp_along(
+        &mut self,
+        path: &[(i32, i32)],
+        brush_size: u16,
+        spacing: u16,
+        color: Color,
+    ) {
+        self.save_undo_step();
+        for point in path.iter().step_by(spacing.max(1).into()) {
+            for (row, col) in brush_points(*point, brush_size) {
+                self.put(row, col, color);
+            }
+        }
+    }
+
+    /// Drags the colour at `from` along a straight line to `to`, blending it into each cell
+    /// that it passes over. Each cell becomes a mix of its own colour and the colour
+    /// carried from the cell before it, so the colours smear in the direction of movement.
+    /// Transparent and erased cells aren't blended. Like `paint`, smudges done one after the
+    /// other get undone together.
+    pub fn smudge(&mut self, from: (u16, u16), to: (u16, u16)) {
+        let color_at = |canvas: &Canvas, (row, col): (i32, i32)| {
+            let cell = canvas.get_cell(u16::try_from(row).ok()?, u16::try_from(col).ok()?)?;
+            cell.filter(|color| *color != Color::Reset)
+        };
+        let points = line_points((from.0.into(), from.1.into()), (to.0.into(), to.1.into()));
+        let mut carried = color_at(self, points[0]);
+        for (row, col) in points.into_iter().skip(1) {
+            let Some(color) = color_at(self, (row, col)) else {
+                carried = None;
+                continue;
+            };
+            let Some(source) = carried.filter(|_| self.is_paintable(row as u16, col as u16)) else {
+                carried = Some(color);
+                continue;
+            };
+            let (source, target) = (color_to_rgb(source), color_to_rgb(color));
+            let blended = rgb_to_color(std::array::from_fn(|i| {
+                let mixed =
+                    target[i] as f32 + (source[i] as f32 - target[i] as f32) * SMUDGE_STRENGTH;
+                mixed.round() as u8
+            }));
+            self.start_stroke();
+            self.write_cell(row as usize, col as usize, Some(blended));
+            carried = Some(blended);
+        }
+    }
+
+    /// Places a glyph without saving an undo step, doing nothing if it's off the canvas
+    /// or protected by the mask
+    fn put_glyph(&mut self, row: i32, col: i32, glyph: Glyph) {
+        let (Ok(row), Ok(col)) = (u16::try_from(row), u16::try_from(col)) else {
+            return;
+        };
+        if row < self.height && col < self.width && self.is_paintable(row, col) {
+            self.glyphs[row as usize][col as usize] = Some(glyph);
+        }
+    }
+
+    /// Paints a cell without saving an undo step, doing nothing if it's off the canvas
+    /// or protected by the mask
+    fn put(&mut self, row: i32, col: i32, color: Color) {
+        let (Ok(row), Ok(col)) = (u16::try_from(row), u16::try_from(col)) else {
+            return;
+        };
+        if !self.is_paintable(row, col) {
+            return;
+        }
+        if row < self.height && col < self.width {
+            self.write_cell(row.into(), col.into(), Some(color));
+            self.glyphs[row as usize][col as usize] = None;
+        }
+    }
+
+    /// Changes the size of the canvas, with `mode` deciding what happens to the picture.
+    /// Can be undone.
+    pub fn resize(&mut self, width: u16, height: u16, mode: ResizeMode) -> Result<(), String> {
+        validate_canvas_size(width, height)?;
+        let (width, height) = self.new_size(width, height, mode);
+        self.save_undo_step();
+        Self::resize_grids(
+            &mut self.cells,
+            &mut self.glyphs,
+            width,
+            height,
+            mode,
+            self.background,
+        );
+        self.width = width;
+        self.height = height;
+        self.recount_painted();
+        Ok(())
+    }
+
+    /// Grows the canvas to at least `width` by `height` in a way that can't be undone, for
+    /// when the size is changed by something other than the user (e.g. the terminal window
+    /// being resized). It never gets smaller, and the picture is never rescaled, so none of
+    /// it is lost. The undo history grows as well, so undoing won't change the size back.
+    pub fn fit_to(&mut self, width: u16, height: u16) -> Result<(), String> {
+        let (width, height) = (width.max(self.width), height.max(self.height));
+        validate_canvas_size(width, height)?;
+        if (width, height) == (self.width, self.height) {
+            return Ok(());
+        }
+        Self::resize_grids(
+            &mut self.cells,
+            &mut self.glyphs,
+            width,
+            height,
+            ResizeMode::Pad,
+            self.background,
+        );
+        for snapshot in self.undo_stack.iter_mut() {
+            Self::resize_grids(
+                &mut snapshot.cells,
+                &mut snapshot.glyphs,
+                width,
+                height,
+                ResizeMode::Pad,
+                self.background,
+            );
+        }
+        self.width = width;
+        self.height = height;
+        self.recount_painted();
+        // The snapshots might have grown too big
+        self.trim_undo_stack();
+        Ok(())
+    }
+
+    /// The size that the canvas ends up as when it's resized to `(width, height)`
+    fn new_size(&self, width: u16, height: u16, mode: ResizeMode) -> (u16, u16) {
+        match mode {
+            ResizeMode::Pad => (width.max(self.width), height.max(self.height)),
+            ResizeMode::Clip | ResizeMode::Rescale => (width, height),
+        }
+    }
+
+    fn resize_grids(
+        cells: &mut Vec<Vec<Option<Color>>>,
+        glyphs: &mut Vec<Vec<Option<Glyph>>>,
+        width: u16,
+        height: u16,
+        mode: ResizeMode,
+        background: Option<Color>,
+    ) {
+        match mode {
+            ResizeMode::Clip | ResizeMode::Pad => {
+                resize_grid(cells, width, height, background);
+                resize_grid(glyphs, width, height, None);
+            }
+            ResizeMode::Rescale => {
+                rescale_grid(cells, width, height);
+                rescale_grid(glyphs, width, height);
+            }
+        }
+    }
+
+    /// Saves an undo step before painting a cell, unless it's part of a stroke that
+    /// already has one
+    fn start_stroke(&mut self) {
+        if !self.stroke_in_progress {
+            self.save_undo_step();
+            self.stroke_in_progress = true;
+        }
+    }
+
+    /// Finishes the current stroke, so that the next cell that's painted can be undone
+    /// separately
+    pub fn end_stroke(&mut self) {
+        self.stroke_in_progress = false;
+    }
+
+    /// Changes how much memory the undo history can use, in bytes. The oldest steps are
+    /// forgotten if they don't fit any more.
+    pub fn set_undo_memory_limit(&mut self, bytes: usize) {
+        self.undo_memory_limit = bytes;
+        self.trim_undo_stack();
+    }
+
+    /// Forgets the oldest undo steps until the history fits within its limits
+    fn trim_undo_stack(&mut self) {
+        let mut memory: usize = self.undo_stack.iter().map(Snapshot::memory_size).sum();
+        let mut excess = 0;
+        // The newest step is always kept, even if it doesn't fit on its own, so that the
+        // last change can still be undone
+        while excess + 1 < self.undo_stack.len()
+            && (self.undo_stack.len() - excess > MAX_UNDO_STEPS || memory > self.undo_memory_limit)
+        {
+            memory -= self.undo_stack[excess].memory_size();
+            excess += 1;
+        }
+        self.undo_stack.drain(..excess);
+    }
+
+    /// Remembers the current state of the canvas, so that the next change can be undone.
+    /// Anything worth undoing changes the picture, so this changes the version too.
+    fn save_undo_step(&mut self) {
+        self.version = next_version();
+        self.stroke_in_progress = false;
+        self.undo_stack.push(Snapshot {
+            cells: self.cells.clone(),
+            glyphs: self.glyphs.clone(),
+        });
+        self.trim_undo_stack();
+        self.unsaved_changes = true;
+    }
+
+    /// Replaces the whole picture (and the canvas size) with another canvas's, in a way that
+    /// can be undone. The mask and undo history are kept.
+    pub fn replace_contents(&mut self, other: &Canvas) {
+        self.save_undo_step();
+        self.cells = other.cells.clone();
+        self.glyphs = other.glyphs.clone();
+        self.width = other.width;
+        self.height = other.height;
+        self.painted_count = other.painted_count;
+        self.version = next_version();
+        self.changed_cells += self.width as u64 * self.height as u64;
+    }
+
+    /// Reverts the most recent change. Returns false if there was nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        self.stroke_in_progress = false;
+        match self.undo_stack.pop() {
+            Some(Snapshot { cells, glyphs }) => {
+                // The change might have resized the canvas
+                self.height = cells.len() as u16;
+                self.width = cells.first().map_or(0, |row| row.len() as u16);
+                self.cells = cells;
+                self.glyphs = glyphs;
+                self.recount_painted();
+                self.unsaved_changes = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Replaces each painted cell in an area with its RGB complement. Inverting the same
+    /// area twice gives back the original colours, with two exceptions. RGB colours that
+    /// are exactly the same as a named colour come back as the named colour (which only
+    /// looks different on terminals with their own palette). And colours that the program
+    /// doesn't support, like the 256-colour palette, are treated as black, so they come
+    /// back black.
+    pub fn invert_colors(&mut self, area: Selection) {
+        self.map_colors(area, |[r, g, b]| [255 - r, 255 - g, 255 - b]);
+    }
+
+    /// Makes the painted cells in an area lighter (or darker, if `brightness` is negative),
+    /// and warmer (or cooler, if `warmth` is negative), by adding to their RGB channels.
+    /// Warmth is added to the red channel and taken away from the blue one. Channels stop
+    /// at 0 and 255, so going too far and then back again loses some detail.
+    pub fn adjust_colors(&mut self, area: Selection, brightness: i16, warmth: i16) {
+        let adjust = |channel: u8, amount: i16| (channel as i16 + amount).clamp(0, 255) as u8;
+        self.map_colors(area, |[r, g, b]| {
+            [
+                adjust(r, brightness + warmth),
+                adjust(g, brightness),
+                adjust(b, brightness - warmth),
+            ]
+        });
+    }
+
+    /// Changes the colour of each painted cell in an area, as one undo step
+    fn map_colors(&mut self, area: Selection, change: impl Fn([u8; 3]) -> [u8; 3]) {
+        self.save_undo_step();
+        for row in area.top..=area.bottom {
+            for col in area.left..=area.right {
+                if !self.is_paintable(row, col) {
+                    continue;
+                }
+                // Erased cells are left alone, just like transparent ones
+                if let Some(Some(color)) = self.get_cell(row, col)
+                    && color != Color::Reset
+                {
+                    let color = rgb_to_color(change(color_to_rgb(color)));
+                    self.write_cell(row.into(), col.into(), Some(color));
+                }
+            }
+        }
+    }
+
+    /// Paints the transparent and erased cells in an area, leaving the painted ones alone,
+    /// like adding a background behind a picture. Glyphs are kept. Returns how many cells
+    /// were painted, and only saves an undo step if there were any.
+    pub fn fill_empty(&mut self, area: Selection, color: Color) -> usize {
+        let Some(area) = area.intersection(&self.full_area()) else {
+            return 0;
+        };
+        let mut empty_cells = Vec::new();
+        for row in area.top..=area.bottom {
+            for col in area.left..=area.right {
+                let cell = self.cells[row as usize][col as usize];
+                if !is_painted(cell) && self.is_paintable(row, col) {
+                    empty_cells.push((row as usize, col as usize));
+                }
+            }
+        }
+        if !empty_cells.is_empty() {
+            self.save_undo_step();
+        }
+        for (row, col) in empty_cells.iter() {
+            self.write_cell(*row, *col, Some(color));
+        }
+        empty_cells.len()
+    }
+
+    /// Moves the cells (and glyphs) in an area so that its top-left corner is at `to`,
+    /// leaving transparent cells behind. Cells protected by the mask aren't moved or overwritten.
+    pub fn move_area(&mut self, area: Selection, to: (u16, u16)) {
+        self.save_undo_step();
+        let mut moved = Vec::new();
+        for row in area.top..=area.bottom.min(self.height - 1) {
+            for col in area.left..=area.right.min(self.width - 1) {
+                if !self.is_paintable(row, col) {
+                    continue;
+                }
+                let (row, col) = (row as usize, col as usize);
+                moved.push((row, col, self.cells[row][col], self.glyphs[row][col]));
+                self.write_cell(row, col, None);
+                self.glyphs[row][col] = None;
+            }
+        }
+        for (row, col, cell, glyph) in moved {
+            let row = row - area.top as usize + to.0 as usize;
+            let col = col - area.left as usize + to.1 as usize;
+            if row < self.height.into()
+                && col < self.width.into()
+                && self.is_paintable(row as u16, col as u16)
+            {
+                self.write_cell(row, col, cell);
+                self.glyphs[row][col] = glyph;
+            }
+        }
+    }
+
+    /// The smallest area that contains all of the painted cells and glyphs, or None if
+    /// the canvas is empty. Erased cells count as empty.
+    pub fn content_bounds(&self) -> Option<Selection> {
+        let mut bounds: Option<Selection> = None;
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let (row_index, col_index) = (row as usize, col as usize);
+                let painted = self.cells[row_index][col_index].is_some_and(|c| c != Color::Reset);
+                if !painted && self.glyphs[row_index][col_index].is_none() {
+                    continue;
+                }
+                bounds = Some(match bounds {
+                    Some(bounds) => Selection {
+                        top: bounds.top.min(row),
+                        left: bounds.left.min(col),
+                        bottom: bounds.bottom.max(row),
+                        right: bounds.right.max(col),
+                    },
+                    None => Selection::from_corners((row, col), (row, col)),
+                });
+            }
+        }
+        bounds
+    }
+
+    /// Makes a new canvas containing just the content of this one (see `content_bounds`),
+    /// with `margin` transparent cells around each side. An empty canvas is kept the same size.
+    pub fn cropped(&self, margin: u16) -> Canvas {
+        let Some(bounds) = self.content_bounds() else {
+            return Canvas::new(self.width, self.height);
+        };
+        let clip = self.copy(bounds);
+        let mut canvas = Canvas::new(
+            clip.width().saturating_add(margin.saturating_mul(2)),
+            clip.height().saturating_add(margin.saturating_mul(2)),
+        );
+        canvas.paste(&clip, (margin, margin), false, PasteEdges::Clip);
+        canvas.undo_stack.clear();
+        canvas
+    }
+
+    /// Shrinks the canvas to just an area, moving it to the top-left and throwing away
+    /// everything outside of it. The mask moves along with the picture. Can be undone.
+    pub fn crop(&mut self, area: Selection) {
+        let Some(area) = area.intersection(&self.full_area()) else {
+            return;
+        };
+        let clip = self.copy(area);
+        let mut canvas = Canvas::new(clip.width(), clip.height());
+        canvas.paste(&clip, (0, 0), false, PasteEdges::Clip);
+        self.replace_contents(&canvas);
+        self.mask = self.mask.and_then(|mask| {
+            let Some(masked) = mask.area.intersection(&area) else {
+                // None of the masked area is left, so either everything or nothing can be
+                // painted on
+                return match mask.mode {
+                    MaskMode::Outside => None,
+                    MaskMode::Inside => Some(Mask {
+                        area: self.full_area(),
+                        mode: MaskMode::Outside,
+                    }),
+                };
+            };
+            Some(Mask {
+                area: masked.moved_to((masked.top - area.top, masked.left - area.left)),
+                mode: mask.mode,
+            })
+        });
+    }
+
+    /// Copies the cells and glyphs in an area
+    pub fn copy(&self, area: Selection) -> Clip {
+        let Some(area) = area.intersection(&self.full_area()) else {
+            return Clip {
+                cells: Vec::new(),
+                glyphs: Vec::new(),
+            };
+        };
+        let rows = area.top as usize..=area.bottom as usize;
+        let cols = area.left as usize..=area.right as usize;
+        Clip {
+            cells: self.cells[rows.clone()]
+                .iter()
+                .map(|row| row[cols.clone()].to_vec())
+                .collect(),
+            glyphs: self.glyphs[rows]
+                .iter()
+                .map(|row| row[cols.clone()].to_vec())
+                .collect(),
+        }
+    }
+
+    /// Pastes a clip with its top-left corner at `(row, col)`. Parts that don't fit on the
+    /// canvas are cut off, or wrap around to the opposite edge (see `PasteEdges`). If
+    /// `transparent` is true, empty cells in the clip are skipped instead of overwriting
+    /// what's underneath them.
+    pub fn paste(
+        &mut self,
+        clip: &Clip,
+        (row, col): (u16, u16),
+        transparent: bool,
+        edges: PasteEdges,
+    ) {
+        self.save_undo_step();
+        for clip_row in 0..clip.height() {
+            for clip_col in 0..clip.width() {
+                let position = match edges {
+                    PasteEdges::Clip => {
+                        (row.saturating_add(clip_row), col.saturating_add(clip_col))
+                    }
+                    // Work in u32 so that adding the offsets can't overflow
+                    PasteEdges::Wrap => (
+                        ((row as u32 + clip_row as u32) % self.height as u32) as u16,
+                        ((col as u32 + clip_col as u32) % self.width as u32) as u16,
+                    ),
+                };
+                self.put_clip_cell(clip, (clip_row, clip_col), position, transparent);
+            }
+        }
+    }
+
+    /// Fills an area by repeating a clip over and over, starting from the area's top-left
+    /// corner. `transparent` works the same as for `paste`.
+    pub fn pattern_fill(&mut self, clip: &Clip, area: Selection, transparent: bool) {
+        if clip.width() == 0 || clip.height() == 0 {
+            return;
+        }
+        self.save_undo_step();
+        for row in area.top..=area.bottom {
+            for col in area.left..=area.right {
+                let clip_row = (row - area.top) % clip.height();
+                let clip_col = (col - area.left) % clip.width();
+                self.put_clip_cell(clip, (clip_row, clip_col), (row, col), transparent);
+            }
+        }
+    }
+
+    /// Copies one cell from a clip onto the canvas, without saving an undo step.
+    /// Does nothing if it's off the canvas or protected by the mask.
+    fn put_clip_cell(
+        &mut self,
+        clip: &Clip,
+        (clip_row, clip_col): (u16, u16),
+        (row, col): (u16, u16),
+        transparent: bool,
+    ) {
+        if row >= self.height || col >= self.width || !self.is_paintable(row, col) {
+            return;
+        }
+        if let Some((cell, glyph)) = clip.get(clip_row.into(), clip_col.into(), transparent) {
+            self.write_cell(row.into(), col.into(), cell);
+            self.glyphs[row as usize][col as usize] = glyph;
+        }
+    }
+
+    /// Reduces the painted cells in an area to at most `count` different colours (see
+    /// `median_cut`), changing each cell to whichever of those colours is closest to it.
+    /// Returns the colours that were chosen. Erased cells and cells protected by the mask
+    /// are left alone.
+    pub fn quantize(&mut self, area: Selection, count: usize) -> Vec<Color> {
+        let Some(area) = area.intersection(&self.full_area()) else {
+            return Vec::new();
+        };
+        let mut cells = Vec::new();
+        for row in area.top..=area.bottom {
+            for col in area.left..=area.right {
+                let cell = self.cells[row as usize][col as usize];
+                if let Some(color) =
+                    cell.filter(|_| is_painted(cell) && self.is_paintable(row, col))
+                {
+                    cells.push((row as usize, col as usize, color_to_rgb(color)));
+                }
+            }
+        }
+        let rgb_colors: Vec<[u8; 3]> = cells.iter().map(|(_, _, rgb)| *rgb).collect();
+        let palette = median_cut(&rgb_colors, count);
+        if palette.is_empty() {
+            return Vec::new();
+        }
+        self.save_undo_step();
+        let distance = |a: [u8; 3], b: [u8; 3]| {
+            (0..3)
+                .map(|i| (a[i] as i32 - b[i] as i32).pow(2))
+                .sum::<i32>()
+        };
+        for (row, col, rgb) in cells {
+            let nearest = palette
+                .iter()
+                .min_by_key(|color| distance(**color, rgb))
+                .copied()
+                .unwrap_or(rgb);
+            self.write_cell(row, col, Some(rgb_to_color(nearest)));
+        }
+        palette.into_iter().map(rgb_to_color).collect()
+    }
+
+    /// Colours in the cells connected to `(row, col)` (above, below, left and right) that
+    /// are the same colour as it, like a paint bucket. The fill doesn't spread outside of
+    /// `bounds`, or into cells protected by the mask. Returns the number of cells filled.
+    pub fn flood_fill(
+        &mut self,
+        (row, col): (u16, u16),
+        color: Option<Color>,
+        bounds: Selection,
+    ) -> usize {
+        let Some(bounds) = bounds.intersection(&self.full_area()) else {
+            return 0;
+        };
+        let Some(target) = self.get_cell(row, col) else {
+            return 0;
+        };
+        if target == color || !bounds.contains(row, col) || !self.is_paintable(row, col) {
+            return 0;
+        }
+        self.save_undo_step();
+        let mut filled = 0;
+        let mut stack = vec![(row, col)];
+        while let Some((row, col)) = stack.pop() {
+            if !bounds.contains(row, col)
+                || !self.is_paintable(row, col)
+                || self.cells[row as usize][col as usize] != target
+            {
+                continue;
+            }
+            self.write_cell(row.into(), col.into(), color);
+            self.glyphs[row as usize][col as usize] = None;
+            filled += 1;
+            // Wrapping around at 0 leaves the row or column far outside of the bounds
+            stack.push((row.wrapping_sub(1), col));
+            stack.push((row + 1, col));
+            stack.push((row, col.wrapping_sub(1)));
+            stack.push((row, col + 1));
+        }
+        filled
+    }
+
+    /// Fills transparent holes in an area with the most common colour around them.
+    /// Only holes whose neighbours directly above, below, left and right are all painted
+    /// get filled, so running it a second time doesn't change anything.
+    /// Returns the number of holes that were filled.
+    pub fn inpaint(&mut self, area: Selection) -> usize {
+        const ORTHOGONAL: [(isize, isize); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+        const DIAGONAL: [(isize, isize); 4] = [(-1, -1), (-1, 1), (1, -1), (1, 1)];
+        // Outer None if out of bounds, inner None if the cell is transparent or erased
+        let painted_color = |row: usize, col: usize, offset: (isize, isize)| {
+            let row = row.checked_add_signed(offset.0)?;
+            let col = col.checked_add_signed(offset.1)?;
+            let cell = self.cells.get(row)?.get(col)?;
+            Some(cell.filter(|color| *color != Color::Reset))
+        };
+
+        let mut fills = Vec::new();
+        for row in area.top as usize..=area.bottom as usize {
+            for col in area.left as usize..=area.right as usize {
+                if painted_color(row, col, (0, 0)) != Some(None)
+                    || !self.is_paintable(row as u16, col as u16)
+                {
+                    continue;
+                }
+                // Out-of-bounds neighbours don't count, so that holes along the edges get filled
+                let surrounded = ORTHOGONAL
+                    .iter()
+                    .all(|offset| painted_color(row, col, *offset) != Some(None));
+                if !surrounded {
+                    continue;
+                }
+                // Count up the colours around the hole and pick the most common one
+                let mut counts: Vec<(Color, usize)> = Vec::new();
+                for offset in ORTHOGONAL.iter().chain(DIAGONAL.iter()) {
+                    let Some(Some(color)) = painted_color(row, col, *offset) else {
+                        continue;
+                    };
+                    match counts.iter_mut().find(|(counted, _)| *counted == color) {
+                        Some((_, count)) => *count += 1,
+                        None => counts.push((color, 1)),
+                    }
+                }
+                // If there's a tie, the colour that was seen first wins
+                let majority = counts
+                    .iter()
+                    .rev()
+                    .max_by_key(|(_, count)| *count)
+                    .map(|(color, _)| *color);
+                if let Some(color) = majority {
+                    fills.push((row, col, color));
+                }
+            }
+        }
+
+        if !fills.is_empty() {
+            self.save_undo_step();
+            for (row, col, color) in fills.iter() {
+                self.write_cell(*row, *col, Some(*color));
+            }
+        }
+        fills.len()
+    }
+
+    /// The `(width, height)` in pixels of the image that `to_image` would make
+    pub fn image_size(&self, block_width: u16, block_height: u16) -> (u32, u32) {
+        (
+            u32::from(self.width) * u32::from(block_width),
+            u32::from(self.height) * u32::from(block_height),
+        )
+    }
+
+    /// Draws the canvas to an image, with each cell becoming a block of pixels.
+    /// Transparent cells are drawn black, and glyphs aren't drawn at all.
+    pub fn to_image(&self, block_width: u16, block_height: u16) -> RgbImage {
+        let (image_width, image_height) = self.image_size(block_width, block_height);
+        let mut image = RgbImage::new(image_width, image_height);
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let rgb = match self.get_cell(row, col).flatten() {
+                    Some(color) => Rgb(color_to_rgb(color)),
+                    None => Rgb([0, 0, 0]),
+                };
+                // Draw the block to the image buffer!
+                // I feel like there should be a more efficient way to do this (without iterating)
+                let start_x = u32::from(col) * u32::from(block_width);
+                let start_y = u32::from(row) * u32::from(block_height);
+                for y in 0..block_height.into() {
+                    for x in 0..block_width.into() {
+                        image.put_pixel(start_x + x, start_y + y, rgb);
+                    }
+                }
+            }
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drag_undoes_in_one_step() {
+        let mut canvas = Canvas::new(100, 1);
+        for col in 0..100 {
+            canvas.set_cell(0, col, Some(Color::Red));
+        }
+        canvas.end_stroke();
+        assert!(canvas.undo());
+        assert!(canvas.rows().iter().flatten().all(Option::is_none));
+        assert!(!canvas.undo());
+    }
+
+    #[test]
+    fn undo_memory_limit_drops_oldest_steps() {
+        let mut canvas = Canvas::new(10, 10);
+        let snapshot_size = Snapshot {
+            cells: canvas.cells.clone(),
+            glyphs: canvas.glyphs.clone(),
+        }
+        .memory_size();
+        canvas.set_undo_memory_limit(snapshot_size * 3);
+        let colors = [
+            Color::Red,
+            Color::Green,
+            Color::Blue,
+            Color::Yellow,
+            Color::Cyan,
+        ];
+        for color in colors {
+            canvas.set_cell(0, 0, Some(color));
+            canvas.end_stroke();
+        }
+        assert_eq!(canvas.undo_stack.len(), 3);
+        for _ in 0..3 {
+            assert!(canvas.undo());
+        }
+        // The steps before the first two strokes were forgotten
+        assert_eq!(canvas.get_cell(0, 0), Some(Some(Color::Green)));
+        assert!(!canvas.undo());
+    }
+
+    #[test]
+    fn fitting_never_shrinks_the_picture_or_its_history() {
+        let mut canvas = Canvas::new(4, 4);
+        canvas.set_cell(3, 3, Some(Color::Red));
+        canvas.end_stroke();
+        canvas.set_cell(3, 3, Some(Color::Blue));
+        canvas.end_stroke();
+        canvas.fit_to(2, 2).unwrap();
+        assert_eq!((canvas.width(), canvas.height()), (4, 4));
+        canvas.fit_to(6, 3).unwrap();
+        assert_eq!((canvas.width(), canvas.height()), (6, 4));
+        assert_eq!(canvas.get_cell(3, 3), Some(Some(Color::Blue)));
+        assert!(canvas.undo());
+        assert_eq!(canvas.get_cell(3, 3), Some(Some(Color::Red)));
+        assert_eq!((canvas.width(), canvas.height()), (6, 4));
+    }
+
+    #[test]
+    fn growing_a_filled_canvas_fills_the_new_space() {
+        let mut canvas = Canvas::new_filled(2, 2, Color::Blue);
+        canvas.set_cell(0, 0, Some(Color::Red));
+        canvas.end_stroke();
+        canvas.fit_to(3, 3).unwrap();
+        assert_eq!(canvas.get_cell(2, 2), Some(Some(Color::Blue)));
+        canvas.resize(4, 4, ResizeMode::Clip).unwrap();
+        assert_eq!(canvas.get_cell(0, 3), Some(Some(Color::Blue)));
+        assert_eq!(canvas.get_cell(3, 0), Some(Some(Color::Blue)));
+        assert_eq!(canvas.get_cell(0, 0), Some(Some(Color::Red)));
+        assert!(canvas.undo());
+        assert!(canvas.undo());
+        assert_eq!(canvas.get_cell(2, 2), Some(Some(Color::Blue)));
+        assert_eq!(canvas.get_cell(0, 0), Some(Some(Color::Blue)));
+    }
+
+    #[test]
+    fn cropping_moves_the_mask_with_the_picture() {
+        let mut canvas = Canvas::new(10, 10);
+        let area = |top, left, bottom, right| Selection {
+            top,
+            left,
+            bottom,
+            right,
+        };
+        let mask = |area, mode| Some(Mask { area, mode });
+        canvas.set_mask(mask(area(4, 4, 9, 9), MaskMode::Outside));
+        canvas.crop(area(2, 3, 6, 7));
+        assert_eq!(canvas.mask(), mask(area(2, 1, 4, 4), MaskMode::Outside));
+        assert!(canvas.is_paintable(1, 1));
+        assert!(!canvas.is_paintable(2, 1));
+
+        // If none of the mask is left, it either protects everything or nothing
+        canvas.crop(area(0, 0, 1, 1));
+        assert_eq!(canvas.mask(), None);
+        canvas.set_mask(mask(area(1, 1, 1, 1), MaskMode::Inside));
+        canvas.crop(area(0, 0, 0, 0));
+        assert!(!canvas.is_paintable(0, 0));
+    }
+
+    #[test]
+    fn the_newest_undo_step_is_kept_even_if_it_is_too_big() {
+        let mut canvas = Canvas::new(10, 10);
+        canvas.set_undo_memory_limit(1);
+        canvas.set_cell(0, 0, Some(Color::Red));
+        canvas.end_stroke();
+        canvas.set_cell(0, 0, Some(Color::Blue));
+        canvas.end_stroke();
+        assert_eq!(canvas.undo_stack.len(), 1);
+        assert!(canvas.undo());
+        assert_eq!(canvas.get_cell(0, 0), Some(Some(Color::Red)));
+        assert!(!canvas.undo());
+    }
+
+    #[test]
+    fn only_cells_that_change_colour_are_counted_as_changed() {
+        let mut canvas = Canvas::new(4, 3);
+        canvas.set_cell(0, 0, Some(Color::Red));
+        canvas.set_cell(0, 0, Some(Color::Red));
+        canvas.set_cell(1, 2, Some(Color::Blue));
+        assert_eq!(canvas.changed_cells(), 2);
+        canvas.end_stroke();
+        canvas.undo();
+        assert_eq!(canvas.changed_cells(), 2 + 12);
+    }
+
+    #[test]
+    fn char_widths() {
+        assert_eq!(char_width('a'), 1);
+        assert_eq!(char_width('█'), 1);
+        assert_eq!(char_width('中'), 2);
+        assert_eq!(char_width('😀'), 2);
+        assert_eq!(char_width('\u{3000}'), 2);
+        assert_eq!(char_width('\u{0301}'), 0);
+        assert_eq!(char_width('\n'), 0);
+    }
+
+    #[test]
+    fn inverting_twice_gives_back_the_original_colours() {
+        let colors = [
+            Some(Color::Red),
+            Some(Color::Black),
+            Some(Color::White),
+            Some(Color::DarkCyan),
+            Some(Color::Rgb { r: 1, g: 2, b: 3 }),
+            Some(Color::Reset),
+            None,
+        ];
+        let mut canvas = Canvas::new(colors.len() as u16, 1);
+        for (col, color) in colors.iter().enumerate() {
+            canvas.set_cell(0, col as u16, *color);
+        }
+        canvas.invert_colors(canvas.full_area());
+        canvas.invert_colors(canvas.full_area());
+        assert_eq!(canvas.rows()[0], colors);
+
+        // These two don't come back exactly
+        let red = color_to_rgb(Color::Red);
+        let mut canvas = Canvas::new(2, 1);
+        canvas.set_cell(
+            0,
+            0,
+            Some(Color::Rgb {
+                r: red[0],
+                g: red[1],
+                b: red[2],
+            }),
+        );
+        canvas.set_cell(0, 1, Some(Color::AnsiValue(100)));
+        canvas.invert_colors(canvas.full_area());
+        canvas.invert_colors(canvas.full_area());
+        assert_eq!(canvas.rows()[0], [Some(Color::Red), Some(Color::Black)]);
+    }
+
+    #[test]
+    fn edits_that_skip_write_cell_still_change_the_version() {
+        let mut canvas = Canvas::new_filled(4, 4, Color::Red);
+        let version = canvas.version();
+        canvas.invert_colors(canvas.full_area());
+        assert_ne!(canvas.version(), version);
+        let version = canvas.version();
+        canvas.import_text("hi", &HashMap::new(), TextOverflow::Clip, None);
+        assert_ne!(canvas.version(), version);
+        let version = canvas.version();
+        canvas.adjust_colors(canvas.full_area(), 16, -16);
+        assert_ne!(canvas.version(), version);
+    }
+}
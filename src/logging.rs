@@ -0,0 +1,46 @@
+//! Writing log messages to a file, since anything printed to the terminal would mess up
+//! the canvas. Turned on by the `--log <path>` option.
+
+use std::{
+    fs::File,
+    io::{LineWriter, Write},
+    sync::Mutex,
+};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+struct FileLogger {
+    file: Mutex<LineWriter<File>>,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let time = chrono::Local::now().format("%H:%M:%S%.3f");
+        if let Ok(mut file) = self.file.lock() {
+            // There's nowhere to report problems with the log file, so they're ignored
+            let _ = writeln!(file, "{} {:<5} {}", time, record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.flush();
+        }
+    }
+}
+
+/// Starts sending log messages to a file, replacing anything that's already in it
+pub fn start(path: &str) -> Result<(), String> {
+    let file = File::create(path).map_err(|error| format!("Couldn't open {}: {}", path, error))?;
+    let logger = FileLogger {
+        file: Mutex::new(LineWriter::new(file)),
+    };
+    // The logger lasts until the program exits, so leaking it is fine
+    log::set_logger(Box::leak(Box::new(logger))).map_err(|error| error.to_string())?;
+    log::set_max_level(LevelFilter::Debug);
+    Ok(())
+}
@@ -1,23 +1,40 @@
 use std::{
+    borrow::Cow,
     collections::HashMap,
     io::Write,
     ops::Range,
+    path::{Path, PathBuf},
     sync::{
         Arc,
         atomic::{AtomicBool, Ordering},
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use chrono::Local;
 use crossterm::{
     ExecutableCommand,
     cursor::{self, MoveTo},
-    event::{self, Event, KeyEventKind, MouseButton, MouseEvent, MouseEventKind},
-    style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor},
+    event::{self, Event, KeyEvent, KeyEventKind, MouseButton, MouseEvent, MouseEventKind},
+    style::{
+        Attribute, Color, Colored, Print, ResetColor, SetAttribute, SetBackgroundColor,
+        SetForegroundColor,
+    },
     terminal::{self, Clear, ClearType},
 };
-use image::{Rgb, RgbImage};
+use paint_2d::{
+    Canvas, Clip, DEFAULT_SHADE_RAMP, DEFAULT_UNDO_MEMORY_LIMIT, Glyph, Mask, MaskMode, PasteEdges,
+    ResizeMode, Selection, TextOverflow, ansi, brush_points, color_name, color_to_rgb,
+    curve_points, html, hue_to_color, json::Json, line_points, luminance, parse_color,
+    parse_color_map, parse_shade_ramp, rust_source, shade_char,
+};
+
+#[cfg(feature = "benchmark")]
+mod benchmark;
+mod clipboard;
+#[cfg(feature = "ipc")]
+mod ipc;
+mod logging;
 
 struct PaintCursor {
     row: u16,
@@ -25,6 +42,12 @@ struct PaintCursor {
     canvas_rows: u16,
     canvas_cols: u16,
     color: Color,
+    /// True if moving off the left/right edge wraps around to the other side,
+    /// false if the cursor should stop at the edge
+    wrap_h: bool,
+    /// True if moving off the top/bottom edge wraps around to the other side,
+    /// false if the cursor should stop at the edge
+    wrap_v: bool,
 }
 
 impl PaintCursor {
@@ -35,43 +58,50 @@ impl PaintCursor {
             canvas_cols: canvas_size.0,
             canvas_rows: canvas_size.1,
             color: Color::White,
+            wrap_h: true,
+            wrap_v: true,
         }
     }
 
-    fn left(&mut self, by: u16) {
-        if self.col >= by {
-            self.col -= by;
+    /// Moves a position backwards along an axis of a certain length, either wrapping around
+    /// to the end or stopping at the start
+    fn move_back(position: u16, by: u16, length: u16, wrap: bool) -> u16 {
+        if wrap {
+            // Worked out in u32, since the sum doesn't fit in u16 on very wide canvases
+            let (position, length) = (u32::from(position), u32::from(length));
+            let by = u32::from(by) % length;
+            ((position + length - by) % length) as u16
         } else {
-            let underflow = by - self.col;
-            self.col = self.canvas_cols - underflow;
+            position.saturating_sub(by)
         }
     }
 
-    fn right(&mut self, by: u16) {
-        if self.col < self.canvas_cols - by {
-            self.col += by;
+    /// Moves a position forwards along an axis of a certain length, either wrapping around
+    /// to the start or stopping at the end
+    fn move_forward(position: u16, by: u16, length: u16, wrap: bool) -> u16 {
+        if wrap {
+            let (position, length) = (u32::from(position), u32::from(length));
+            let by = u32::from(by) % length;
+            ((position + by) % length) as u16
         } else {
-            let overflow = by - (self.canvas_cols - self.col);
-            self.col = 0 + overflow;
+            position.saturating_add(by).min(length - 1)
         }
     }
 
+    fn left(&mut self, by: u16) {
+        self.col = Self::move_back(self.col, by, self.canvas_cols, self.wrap_h);
+    }
+
+    fn right(&mut self, by: u16) {
+        self.col = Self::move_forward(self.col, by, self.canvas_cols, self.wrap_h);
+    }
+
     fn up(&mut self, by: u16) {
-        if self.row >= by {
-            self.row -= by;
-        } else {
-            let underflow = by - self.row;
-            self.row = self.canvas_rows - underflow;
-        }
+        self.row = Self::move_back(self.row, by, self.canvas_rows, self.wrap_v);
     }
 
     fn down(&mut self, by: u16) {
-        if self.row < self.canvas_rows - by {
-            self.row += by;
-        } else {
-            let overflow = by - (self.canvas_rows - self.row);
-            self.row = 0 + overflow;
-        }
+        self.row = Self::move_forward(self.row, by, self.canvas_rows, self.wrap_v);
     }
 
     fn set_canvas_size(&mut self, size: &(u16, u16)) {
@@ -80,6 +110,17 @@ impl PaintCursor {
     }
 }
 
+/// Describes how far apart two `(row, col)` positions are, for the ruler tool
+fn measurement_text(from: (u16, u16), to: (u16, u16)) -> String {
+    let across = from.1.abs_diff(to.1);
+    let down = from.0.abs_diff(to.0);
+    let diagonal = f64::from(across).hypot(f64::from(down));
+    format!(
+        "{} across, {} down, {:.1} diagonally",
+        across, down, diagonal
+    )
+}
+
 fn make_dark(color: Color) -> Color {
     match color {
         Color::White => Color::Grey,
@@ -94,27 +135,731 @@ fn make_dark(color: Color) -> Color {
     }
 }
 
-/// All the state and main methods for the TUI program
-struct Paint2D {
-    stdout: std::io::Stdout,
+/// Black or white, whichever stands out more against a colour
+fn contrasting_color(color: Color) -> Color {
+    if luminance(color) > 128 {
+        Color::Black
+    } else {
+        Color::White
+    }
+}
+
+/// What the paintbrush does when Space is pressed
+enum Tool {
+    /// Paints the cell under the cursor
+    Brush,
+    /// Adds a vertex to a polygon, which gets drawn when Enter is pressed
+    Polygon { vertices: Vec<(u16, u16)> },
+    /// Marks the start of a line, then draws the line to the cursor when pressed again
+    Line { start: Option<(u16, u16)> },
+    /// Marks the start and control points of a curve, then draws the curve to the cursor
+    /// when pressed a third time
+    Curve { points: Vec<(u16, u16)> },
+    /// Doesn't do anything when pressed. Instead, moving the cursor smudges the colour
+    /// that it's leaving into the cells that it moves onto.
+    Smudge,
+    /// Places box-drawing characters that join up with the ones next to them, for drawing
+    /// diagrams. Holding Space while moving draws a line of them.
+    Connector,
+    /// Marks a starting point, then shows how far the cursor is from it without painting
+    /// anything. Pressing Space again pins the measurement to the canvas.
+    Ruler { start: Option<(u16, u16)> },
+}
+
+impl Tool {
+    /// All the tools, in the order that the tool sidebar shows them
+    fn all() -> [Tool; 7] {
+        [
+            Tool::Brush,
+            Tool::Line { start: None },
+            Tool::Curve { points: Vec::new() },
+            Tool::Polygon {
+                vertices: Vec::new(),
+            },
+            Tool::Smudge,
+            Tool::Connector,
+            Tool::Ruler { start: None },
+        ]
+    }
+
+    /// The tool's name, as shown in the tool sidebar
+    fn name(&self) -> &'static str {
+        match self {
+            Tool::Brush => "Brush",
+            Tool::Line { .. } => "Line",
+            Tool::Curve { .. } => "Curve",
+            Tool::Polygon { .. } => "Polygon",
+            Tool::Smudge => "Smudge",
+            Tool::Connector => "Connector",
+            Tool::Ruler { .. } => "Ruler",
+        }
+    }
+
+    /// Finds a tool by its name, ignoring case
+    fn from_name(name: &str) -> Option<Tool> {
+        Tool::all()
+            .into_iter()
+            .find(|tool| tool.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// A selection that's being moved around, before the move has been confirmed
+#[derive(Clone, Copy)]
+struct Grab {
+    /// Where the selection was before it was grabbed
+    area: Selection,
+    /// The `(row, col)` that its top-left corner would be moved to
+    to: (u16, u16),
+}
+
+/// Measurements shown in the debug overlay
+#[derive(Default)]
+struct DebugStats {
+    /// How long the last frame took to render
+    render_time: Duration,
+    /// How many events have been handled since the last frame
+    events: u32,
+    /// `Canvas::changed_cells` as of the last frame, used to count how many have changed
+    previous_changed_cells: u64,
+}
+
+/// The state of the colour mixer overlay, which makes a colour out of red, green and blue
+struct ColorMixer {
+    /// The amount of red, green and blue, from 0 to 255
+    rgb: [u8; 3],
+    /// Which slider is being changed, where 0 is red
+    channel: usize,
+}
+
+/// Another canvas that the current one is being compared with by `:diff`
+struct Diff {
+    /// The file that the other canvas came from
+    path: String,
+    other: Canvas,
+    /// The version of the current canvas that `differences` was worked out for
+    version: u64,
+    /// The `(row, col)` of each cell that's different on the two canvases
+    differences: Vec<(u16, u16)>,
+}
+
+/// Something that can be done by pressing a key (or with the mouse), as listed in `KEYMAP`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Action {
+    Move,
+    ExtendSelection,
+    JumpToEdge,
+    JumpBack,
+    ExtraCursor,
+    Paint,
+    Color,
+    StoreColor,
+    RecallColor,
+    ShowColorSlots,
+    Line,
+    Curve,
+    Polygon,
+    FinishPolygon,
+    Smudge,
+    Connector,
+    Ruler,
+    CycleTool,
+    Select,
+    SelectAll,
+    SaveOpen,
+    QuickSave,
+    Grab,
+    CopyPaste,
+    Fill,
+    Mix,
+    SwapColor,
+    Invert,
+    Mask,
+    Undo,
+    Macro,
+    Export,
+    Command,
+    Help,
+    Debug,
+    Cancel,
+    Quit,
+}
+
+impl Action {
+    /// The name that `--list-keys` shows for the action
+    fn name(self) -> &'static str {
+        match self {
+            Action::Move => "move",
+            Action::ExtendSelection => "extend-selection",
+            Action::JumpToEdge => "jump-to-edge",
+            Action::JumpBack => "jump-back",
+            Action::ExtraCursor => "extra-cursor",
+            Action::Paint => "paint",
+            Action::Color => "color",
+            Action::StoreColor => "store-color",
+            Action::RecallColor => "recall-color",
+            Action::ShowColorSlots => "show-color-slots",
+            Action::Line => "line",
+            Action::Curve => "curve",
+            Action::Polygon => "polygon",
+            Action::FinishPolygon => "finish-polygon",
+            Action::Smudge => "smudge",
+            Action::Connector => "connector",
+            Action::Ruler => "ruler",
+            Action::CycleTool => "cycle-tool",
+            Action::Select => "select",
+            Action::SelectAll => "select-all",
+            Action::SaveOpen => "save-open",
+            Action::QuickSave => "quick-save",
+            Action::Grab => "grab",
+            Action::CopyPaste => "copy-paste",
+            Action::Fill => "fill",
+            Action::Mix => "mix",
+            Action::SwapColor => "swap-color",
+            Action::Invert => "invert",
+            Action::Mask => "mask",
+            Action::Undo => "undo",
+            Action::Macro => "macro",
+            Action::Export => "export",
+            Action::Command => "command",
+            Action::Help => "help",
+            Action::Debug => "debug",
+            Action::Cancel => "cancel",
+            Action::Quit => "quit",
+        }
+    }
+}
+
+/// Something waiting for the user to press a key to choose a macro register
+#[derive(Clone, Copy)]
+enum MacroAction {
+    Record,
+    Play,
+}
+
+/// How to let the user know that something they tried to do didn't work
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Feedback {
+    /// Briefly invert the colours of the bottom bar
+    Flash,
+    /// Ring the terminal bell
+    Bell,
+    Off,
+}
+
+/// What pressing Ctrl+C does
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CtrlC {
+    /// Quit, asking first if there are unsaved changes (the same as pressing Q)
+    Quit,
+    /// Cancel the current tool, overlay or selection, and only quit if there isn't one
+    Cancel,
+}
+
+/// What happens when something is done to a position that's off the canvas
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Bounds {
+    /// Show an error (or flash), so that mistakes in macros and scripts get noticed
+    Strict,
+    /// Move the position onto the nearest edge of the canvas, or quietly skip the part
+    /// that's off it
+    Lenient,
+}
+
+/// How the paintbrush speeds up while an arrow key is held down
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum AccelerationCurve {
+    /// Move one more cell at a time after every few key repeats
+    Linear,
+    /// Move twice as many cells at a time after every few key repeats
+    Doubling,
+}
+
+/// Which corner of the canvas is `(0, 0)` when showing and typing in coordinates.
+/// Positions are always stored from the top-left; this only changes how they're presented.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Origin {
+    TopLeft,
+    /// Like on a graph, so y goes up the canvas
+    BottomLeft,
+}
+
+/// Options that can be changed while the program is running, using the `:set` command
+struct Settings {
+    /// Ask before quitting if there are unsaved changes
+    confirm_quit: bool,
+    /// Colour in the inside of polygons, rather than just drawing their outline
+    fill_polygons: bool,
+    /// Skip the empty cells when pasting, so that they don't cover up what's underneath
+    transparent_paste: bool,
+    /// What happens to the parts of a paste that go past the edges of the canvas
+    paste_edges: PasteEdges,
+    /// Smooth out the steps in diagonal lines using quarter-block glyphs
+    smooth_lines: bool,
+    /// Draw painted cells as full blocks in the cell's colour, instead of as spaces with a
+    /// background colour, for terminals that leave gaps between the backgrounds of cells
+    block_cells: bool,
+    /// Draw each canvas cell two columns wide, so that they look roughly square
+    square_pixels: bool,
+    /// Show copies of the canvas to the right of and below it, as a 2x2 tiling
+    wrap_preview: bool,
+    /// Show a strip of all the palette's colours down the right edge of the screen
+    palette_strip: bool,
+    /// Show a list of the tools down the right edge of the screen, which can be clicked on
+    tool_sidebar: bool,
+    /// Show how many cells are painted in the bottom bar, and how much of the canvas they cover
+    show_coverage: bool,
+    /// Show a small view of the whole canvas in the bottom-left corner of the screen
+    minimap: bool,
+    /// Draw faint lines across the empty cells in the paintbrush's row and column
+    crosshair: bool,
+    /// If set, faint grid lines are drawn across the empty cells every `minor` cells, as
+    /// `(minor, major)`. Every `major` cells, the lines are brighter.
+    grid: Option<(u16, Option<u16>)>,
+    /// The characters that painted cells are shaded with in monochrome mode, from darkest
+    /// to brightest
+    shade_ramp: Vec<char>,
+    /// If set, transparent cells are shown with a checkerboard pattern, with squares that
+    /// are this many cells tall
+    checkerboard: Option<u16>,
+    /// The colour to draw the cursor in. If it's None, the cursor is drawn in black or
+    /// white over painted cells (whichever stands out more), and in the paintbrush's colour
+    /// over transparent ones.
+    cursor_color: Option<Color>,
+    /// What to do when an action can't be done, e.g. pressing a key that does nothing
+    feedback: Feedback,
+    /// What pressing Ctrl+C does
+    ctrl_c: CtrlC,
+    /// The key that quits, or None to only quit with `:q` (and Ctrl+C)
+    quit_key: Option<char>,
+    /// What happens when something is done to a position that's off the canvas
+    bounds: Bounds,
+    /// If set, exported images are cropped to the painted part of the canvas, with this
+    /// many cells of empty space around it
+    export_margin: Option<u16>,
+    /// The most memory that the undo history can use, in megabytes
+    undo_memory_mb: usize,
+    /// The fastest that holding down an arrow key can move the paintbrush, in cells per
+    /// key repeat. Setting this to 1 turns off acceleration.
+    max_acceleration: u16,
+    /// How many cells the paintbrush moves for each key repeat before it speeds up
+    acceleration_base: u16,
+    /// How many key repeats it takes for the movement to speed up each time
+    acceleration_ramp: u16,
+    /// How much the movement speeds up by each time
+    acceleration_curve: AccelerationCurve,
+    /// The most times per second that the screen is rendered. Any redraws requested
+    /// within the same frame get coalesced into a single render.
+    max_fps: u16,
+    /// How close the cursor can get to the edge of the view before it scrolls, in cells
+    scroll_margin: u16,
+    /// If set, the program checks for input less often after this many seconds without any,
+    /// to save power
+    idle_timeout: Option<u64>,
+    /// How many cells wide the paintbrush is. When `pressure` is on, this is the biggest
+    /// that it can get.
+    brush_size: u16,
+    /// Grow the paintbrush when painting quickly, and shrink it when painting slowly,
+    /// like pressing harder with a real brush
+    pressure: bool,
+    /// How many cells apart the paintbrush is stamped when drawing lines and curves.
+    /// 1 draws solid strokes, and bigger numbers draw dotted ones.
+    stamp_spacing: u16,
+    /// If set, each dab of paint moves this many degrees around the colour wheel from the
+    /// last one, instead of using the paintbrush's colour
+    rainbow: Option<u16>,
+    /// Remember where the cursor and view were in each session file, and go back there
+    /// when the file is opened again
+    remember_view: bool,
+    /// If set, cells that get a glyph from imported ASCII art are painted in this colour,
+    /// instead of the glyphs floating over whatever was already there
+    glyph_background: Option<Color>,
+    /// What to do with lines of imported ASCII art that are wider than the canvas
+    ascii_overflow: TextOverflow,
+    /// What happens to the picture when the canvas changes size
+    resize_mode: ResizeMode,
+    /// Where coordinates are counted from
+    origin: Origin,
+    /// The smallest that the terminal can be for the program to draw in it, as
+    /// `(cols, rows)`. Smaller terminals just show a message asking for more space.
+    min_terminal_size: (u16, u16),
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            confirm_quit: true,
+            fill_polygons: false,
+            smooth_lines: false,
+            block_cells: false,
+            square_pixels: false,
+            wrap_preview: false,
+            palette_strip: false,
+            tool_sidebar: false,
+            show_coverage: false,
+            minimap: false,
+            crosshair: false,
+            grid: None,
+            shade_ramp: DEFAULT_SHADE_RAMP.to_vec(),
+            checkerboard: None,
+            cursor_color: None,
+            transparent_paste: true,
+            paste_edges: PasteEdges::Clip,
+            feedback: Feedback::Flash,
+            ctrl_c: CtrlC::Quit,
+            quit_key: Some('q'),
+            bounds: Bounds::Strict,
+            ascii_overflow: TextOverflow::Clip,
+            glyph_background: None,
+            resize_mode: ResizeMode::Clip,
+            origin: Origin::TopLeft,
+            min_terminal_size: DEFAULT_MIN_TERMINAL_SIZE,
+            remember_view: false,
+            max_acceleration: 8,
+            acceleration_base: 1,
+            acceleration_ramp: 4,
+            acceleration_curve: AccelerationCurve::Linear,
+            scroll_margin: 0,
+            max_fps: DEFAULT_MAX_FPS,
+            idle_timeout: None,
+            brush_size: 1,
+            pressure: false,
+            stamp_spacing: 1,
+            rainbow: None,
+            export_margin: None,
+            undo_memory_mb: DEFAULT_UNDO_MEMORY_LIMIT / 1024 / 1024,
+        }
+    }
+}
+
+/// Parses the value of an on/off setting
+fn parse_toggle(value: &str) -> Result<bool, String> {
+    match value {
+        "on" | "true" | "yes" => Ok(true),
+        "off" | "false" | "no" => Ok(false),
+        _ => Err(format!("Expected on or off, got \"{}\"", value)),
+    }
+}
+
+/// The folder where the program remembers things between runs
+fn state_dir() -> Option<PathBuf> {
+    let state_dir = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ if cfg!(windows) => PathBuf::from(std::env::var_os("LOCALAPPDATA")?),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(state_dir.join("paint-2d"))
+}
+
+/// The file where the view state of each session is remembered (if `remember-view` is on)
+fn view_state_path() -> Option<PathBuf> {
+    Some(state_dir()?.join("view-state.json"))
+}
+
+/// The file where commands typed into the command prompt are remembered, one per line
+fn command_history_path() -> Option<PathBuf> {
+    Some(state_dir()?.join("command-history"))
+}
+
+/// The file where the session files that were recently opened or saved are listed,
+/// newest first
+fn recent_files_path() -> Option<PathBuf> {
+    Some(state_dir()?.join("recent-files"))
+}
+
+/// Reads the list of recent session files, leaving out any that don't exist any more
+fn read_recent_files() -> Vec<String> {
+    let Some(text) = recent_files_path().and_then(|path| std::fs::read_to_string(path).ok()) else {
+        return Vec::new();
+    };
+    text.lines()
+        .filter(|path| Path::new(path).is_file())
+        .map(str::to_string)
+        .take(MAX_RECENT_FILES)
+        .collect()
+}
+
+/// Moves a session file to the top of the recent files list
+fn add_recent_file(path: &str) -> std::io::Result<()> {
+    let (Ok(path), Some(list_path)) = (std::fs::canonicalize(path), recent_files_path()) else {
+        return Ok(());
+    };
+    let path = path.to_string_lossy().into_owned();
+    let mut recent_files = read_recent_files();
+    recent_files.retain(|recent| *recent != path);
+    recent_files.insert(0, path);
+    recent_files.truncate(MAX_RECENT_FILES);
+    if let Some(dir) = list_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(list_path, recent_files.join("\n") + "\n")
+}
+
+/// The file for one of the numbered quick-save slots
+fn quick_save_path(slot: u8) -> Option<PathBuf> {
+    Some(
+        state_dir()?
+            .join("quick-saves")
+            .join(format!("slot-{}.json", slot)),
+    )
+}
+
+/// Reads the commands that were run in previous sessions
+fn read_command_history() -> Vec<String> {
+    command_history_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .map(|text| text.lines().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+fn save_command_history(history: &[String]) -> std::io::Result<()> {
+    let Some(path) = command_history_path() else {
+        return Ok(());
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, history.join("\n") + "\n")
+}
+
+/// The colours that the terminal is currently using for text, so that colour commands
+/// can be skipped when they wouldn't change anything. `None` means it isn't known.
+#[derive(Clone, Copy, Default)]
+struct TerminalColors {
+    foreground: Option<Color>,
+    background: Option<Color>,
+}
+
+impl TerminalColors {
+    /// Changes the colour of text, unless the terminal is already using that colour
+    fn set_foreground(&mut self, out: &mut impl Write, color: Color) -> std::io::Result<()> {
+        if self.foreground != Some(color) {
+            out.execute(SetForegroundColor(color))?;
+            self.foreground = Some(color);
+        }
+        Ok(())
+    }
+
+    /// Changes the colour behind text, unless the terminal is already using that colour
+    fn set_background(&mut self, out: &mut impl Write, color: Color) -> std::io::Result<()> {
+        if self.background != Some(color) {
+            out.execute(SetBackgroundColor(color))?;
+            self.background = Some(color);
+        }
+        Ok(())
+    }
+
+    /// Goes back to the terminal's default colours, unless it's already using them
+    fn reset(&mut self, out: &mut impl Write) -> std::io::Result<()> {
+        let reset = Some(Color::Reset);
+        if self.foreground != reset || self.background != reset {
+            out.execute(ResetColor)?;
+            (self.foreground, self.background) = (reset, reset);
+        }
+        Ok(())
+    }
+}
+
+/// All the state and main methods for the TUI program. Everything gets drawn to `W`, which
+/// is the terminal, except when benchmarking the renderer.
+struct Paint2D<W: Write = std::io::Stdout> {
+    stdout: W,
+    terminal_colors: TerminalColors,
     running: Arc<AtomicBool>,
     cursor: PaintCursor,
     /// `(height, width)` i.e. (cols, rows)
     terminal_size: (u16, u16),
-    /// A vec of rows. Can be accessed like `color_canvas[row][col]`
-    color_canvas: Vec<Vec<Option<Color>>>,
+    canvas: Canvas,
+    /// The `(row, col)` of the canvas cell shown at the top-left of the screen
+    scroll: (u16, u16),
+    /// True if the canvas should be resized to fit the terminal whenever the terminal is
+    /// resized. This stops once the canvas has been given a specific size with `:resize`.
+    canvas_follows_terminal: bool,
     space_button_held: bool,
     /// True if the terminal sends key release events (as well as normal key down events)
     enhanced_key_events: bool,
+    /// True if we've asked the terminal to send key release events, which needs to be
+    /// undone when the program exits
+    keyboard_enhancement_pushed: bool,
+    /// True if the program draws on the terminal's alternate screen, rather than on top of
+    /// whatever was in the terminal before. Turned off by `--no-altscreen`.
+    alternate_screen: bool,
+    /// True if the canvas is drawn with shading characters instead of colours, and exports
+    /// are greyscale. Turned on by `--mono` or the `NO_COLOR` environment variable.
+    mono: bool,
+    /// Where the terminal's cursor was before the program started (after scrolling the
+    /// screen to make room for the canvas), if we're drawing in the normal screen, so that
+    /// it can be put back on exit
+    inline_cursor_start: Option<(u16, u16)>,
     /// The row that the color bar occupies
     color_bar_row: u16,
+    /// The colours on the number keys
+    palette: &'static Palette,
     /// Stores the columns occupied by each colour's label in the colour bar
     color_bar_color_labels: HashMap<Color, Range<u16>>,
+    /// The currently selected area of the canvas, if any
+    selection: Option<Selection>,
+    /// Where the current mouse drag started, as `(row, col)`
+    drag_start: Option<(u16, u16)>,
+    /// Where the selection started, if it's being made with Shift+arrow keys, along with
+    /// the selection that it made. If the selection has changed since, it starts again.
+    selection_anchor: Option<((u16, u16), Selection)>,
+    /// The selection that's being moved, if there is one
+    grab: Option<Grab>,
+    /// The `(row, col)` of each extra cursor, which move and paint along with the main one
+    extra_cursors: Vec<(u16, u16)>,
+    /// The cells that were last copied, if any
+    clipboard: Option<Clip>,
+    /// The arrow key that was last pressed, when it was pressed, and how many times
+    /// in a row it has been pressed (or repeated) without a pause
+    held_arrow: Option<(event::KeyCode, Instant, u16)>,
+    /// Where the cursors were drawn on the canvas in the last render, so that they can be
+    /// drawn over when they move
+    drawn_cursors: Vec<(u16, u16)>,
+    /// When the paintbrush last painted, and how big it was, for the `pressure` setting
+    last_paint: Option<(Instant, u16)>,
+    /// The `(row, col)` where the main cursor last painted, so that it can jump back there
+    last_painted_cell: Option<(u16, u16)>,
+    /// The hue that the next dab of paint will be, for the `rainbow` setting
+    rainbow_hue: u16,
+    settings: Settings,
+    tool: Tool,
+    /// True if we're waiting for the user to confirm that they want to quit
+    confirming_quit: bool,
+    /// The text typed into the command prompt so far, if the prompt is open
+    command_line: Option<String>,
+    /// Commands that have been run from the command prompt, oldest first
+    command_history: Vec<String>,
+    /// Which entry of the command history is in the prompt, while going through it with
+    /// Up and Down. The text that was typed before that is kept too, for going back to.
+    history_position: Option<(usize, String)>,
+    /// A message to show in the bottom bar until the next key press
+    status_message: Option<String>,
+    /// Recorded key presses, by the register they were recorded into
+    macros: HashMap<char, Vec<KeyEvent>>,
+    /// The register that's being recorded into, and the key presses recorded so far
+    recording_macro: Option<(char, Vec<KeyEvent>)>,
+    /// Set if the next key press chooses which macro register to record or play
+    awaiting_register: Option<MacroAction>,
+    /// True while a macro is being played back
+    playing_macro: bool,
+    /// When the bottom bar should stop flashing, if it's flashing
+    flash_until: Option<Instant>,
+    /// True if something has changed since the screen was last rendered
+    needs_redraw: bool,
+    /// True if the cursors have moved since they were last drawn, and nothing else on the
+    /// screen needs to change
+    cursors_moved: bool,
+    /// When the screen was last rendered, used to enforce the frame budget
+    last_render: Instant,
+    /// When the last event (such as a key press) was handled, for the `idle-timeout` setting
+    last_event: Instant,
+    /// Colours stored in the F1 to F8 slots
+    color_slots: [Option<Color>; COLOR_SLOT_COUNT],
+    /// True if the colour slots overlay is being shown
+    show_color_slots: bool,
+    /// The colours chosen by the last `:quantize` command, shown until the next key press
+    quantized_palette: Option<Vec<Color>>,
+    /// The file that the session was last saved to or opened from
+    session_path: Option<String>,
+    /// The recent session files and which one is highlighted, while the `:recent` overlay
+    /// is being shown
+    recent_files: Option<(Vec<String>, usize)>,
+    /// True if the controls overlay (opened with `?`) is being shown
+    show_help: bool,
+    /// The canvas version that the minimap was last worked out for, and the minimap's cells
+    minimap: Option<(u64, Vec<Vec<Option<Color>>>)>,
+    /// The latest `(cols, rows)` that the terminal was resized to, and when, if the program
+    /// hasn't adjusted to it yet
+    pending_resize: Option<((u16, u16), Instant)>,
+    /// Set while the canvas is being compared with another one
+    diff: Option<Diff>,
+    /// Set while the colour mixer overlay is being shown
+    mixer: Option<ColorMixer>,
+    /// The colour that the paintbrush had before it was last changed, which Shift+X swaps
+    /// back to
+    previous_color: Option<Color>,
+    /// The `(start, end)` of a measurement pinned with the ruler tool, which stays on the
+    /// canvas until Esc clears it
+    measurement: Option<((u16, u16), (u16, u16))>,
+    /// The socket that other programs can send commands to, from `--socket`
+    #[cfg(feature = "ipc")]
+    socket: Option<ipc::Server>,
+    /// Set if the debug overlay is being shown
+    debug: Option<DebugStats>,
 }
 
 const BOTTOM_BAR_HEIGHT: u16 = 2;
+/// The default for the `min-terminal-size` setting, as `(cols, rows)`
+const DEFAULT_MIN_TERMINAL_SIZE: (u16, u16) = (20, 5);
+/// The biggest that the minimap can be, as `(cols, rows)`
+const MINIMAP_MAX_SIZE: (u16, u16) = (24, 8);
+/// How many columns wide each of the colour mixer's sliders is
+const MIXER_SLIDER_WIDTH: u16 = 32;
+/// How much holding Ctrl makes the colour mixer's sliders change by
+const MIXER_FAST_STEP: u8 = 16;
+/// The colour of the dark squares of the checkerboard behind transparent cells
+const CHECKERBOARD_COLOR: Color = Color::Rgb {
+    r: 48,
+    g: 48,
+    b: 48,
+};
+/// The size of the checkerboard's squares when it's turned on without giving a size
+const DEFAULT_CHECKERBOARD_SIZE: u16 = 2;
+/// The biggest that the checkerboard's squares can be, in cells
+const MAX_CHECKERBOARD_SIZE: u16 = 16;
+/// How far the hue moves for each dab of paint when `rainbow` is turned on without giving
+/// a step, in degrees
+const DEFAULT_RAINBOW_STEP: u16 = 10;
+/// How many columns the palette strip takes up, when it's turned on
+const PALETTE_STRIP_WIDTH: u16 = 2;
+/// How many columns the tool sidebar takes up, when it's turned on
+const TOOL_SIDEBAR_WIDTH: u16 = 9;
+/// The default for the `max-fps` setting
+const DEFAULT_MAX_FPS: u16 = 60;
+/// The highest that the `max-fps` setting can go
+const MAX_MAX_FPS: u16 = 240;
+/// How long the terminal has to stay the same size before the program adjusts to it, so
+/// that dragging the window to resize it doesn't resize the canvas dozens of times
+const RESIZE_DEBOUNCE: Duration = Duration::from_millis(100);
+/// How long the bottom bar flashes for when an action can't be done
+const FLASH_DURATION: Duration = Duration::from_millis(150);
+/// How long an arrow key can go without repeating before the movement stops accelerating
+const ACCELERATION_RESET: Duration = Duration::from_millis(150);
+/// The biggest that the paintbrush can be, in cells
+const MAX_BRUSH_SIZE: u16 = 15;
+/// The furthest apart that the paintbrush can be stamped along lines and curves
+const MAX_STAMP_SPACING: u16 = 100;
+/// The most colours that `:quantize` can reduce a picture to
+const MAX_QUANTIZE_COLORS: usize = 256;
+/// How much `:brightness` and `:warmth` change the colours by when no amount is given
+const COLOR_ADJUSTMENT_STEP: i16 = 16;
+/// When `pressure` is on, painting again within this time makes the paintbrush grow
+const PRESSURE_FAST: Duration = Duration::from_millis(80);
+/// When `pressure` is on, waiting longer than this to paint again makes the paintbrush shrink
+const PRESSURE_SLOW: Duration = Duration::from_millis(250);
+/// How long to wait for input when there's nothing waiting to be rendered
+const IDLE_POLL_TIMEOUT: Duration = Duration::from_millis(50);
+/// How long to wait for input once the `idle-timeout` has passed
+const ASLEEP_POLL_TIMEOUT: Duration = Duration::from_secs(1);
+/// The longest to wait for input while `--socket` is open, since commands from the socket
+/// only get handled in between polls
+#[cfg(feature = "ipc")]
+const SOCKET_POLL_TIMEOUT: Duration = Duration::from_millis(10);
 /// The number of rows from the bottom that the color bar should be rendered at
 const COLOR_BAR_ROW_FROM_BOTTOM: u16 = 2;
+/// How many colour slots there are, each bound to an F-key starting at F1
+const COLOR_SLOT_COUNT: usize = 8;
+/// The most pixels that an exported image can have, to avoid using up all the memory
+/// (each pixel takes 3 bytes)
+const MAX_EXPORT_PIXELS: u64 = 100_000_000;
+/// The biggest margin that can be added around exported images, in cells
+const MAX_EXPORT_MARGIN: u16 = 100;
+/// The version of the session file format that gets saved
+const SESSION_VERSION: u64 = 1;
+/// The most session files that are listed by `:recent`
+const MAX_RECENT_FILES: usize = 10;
+/// The most commands that are remembered in the command history
+const MAX_COMMAND_HISTORY: usize = 500;
 
 struct ColorKey {
     key: char,
@@ -128,66 +873,245 @@ impl ColorKey {
     }
 }
 
-static COLOR_KEYS: [ColorKey; 9] = [
-    ColorKey::new('1', Color::White, "White"),
-    ColorKey::new('2', Color::Red, "Red"),
-    ColorKey::new('3', Color::Green, "Green"),
-    ColorKey::new('4', Color::Yellow, "Yellow"),
-    ColorKey::new('5', Color::Blue, "Blue"),
-    ColorKey::new('6', Color::Magenta, "Magenta"),
-    ColorKey::new('7', Color::Cyan, "Cyan"),
-    ColorKey::new('8', Color::Grey, "Grey"),
-    ColorKey::new('0', Color::Reset, "Eraser"),
+/// A set of colours for the number keys
+struct Palette {
+    /// What the palette is called in the `:palette` command and the `--palette` option
+    name: &'static str,
+    keys: [ColorKey; 9],
+}
+
+const fn rgb(r: u8, g: u8, b: u8) -> Color {
+    Color::Rgb { r, g, b }
+}
+
+/// The palettes that can be picked from. The first one is used by default.
+static PALETTES: [Palette; 3] = [
+    Palette {
+        name: "default",
+        keys: [
+            ColorKey::new('1', Color::White, "White"),
+            ColorKey::new('2', Color::Red, "Red"),
+            ColorKey::new('3', Color::Green, "Green"),
+            ColorKey::new('4', Color::Yellow, "Yellow"),
+            ColorKey::new('5', Color::Blue, "Blue"),
+            ColorKey::new('6', Color::Magenta, "Magenta"),
+            ColorKey::new('7', Color::Cyan, "Cyan"),
+            ColorKey::new('8', Color::Grey, "Grey"),
+            ColorKey::new('0', Color::Reset, "Eraser"),
+        ],
+    },
+    // The Okabe-Ito colours, which can be told apart with the common kinds of colour blindness
+    Palette {
+        name: "cbfriendly",
+        keys: [
+            ColorKey::new('1', Color::White, "White"),
+            ColorKey::new('2', rgb(230, 159, 0), "Orange"),
+            ColorKey::new('3', rgb(86, 180, 233), "Sky blue"),
+            ColorKey::new('4', rgb(0, 158, 115), "Green"),
+            ColorKey::new('5', rgb(240, 228, 66), "Yellow"),
+            ColorKey::new('6', rgb(0, 114, 178), "Blue"),
+            ColorKey::new('7', rgb(213, 94, 0), "Vermilion"),
+            ColorKey::new('8', rgb(204, 121, 167), "Pink"),
+            ColorKey::new('0', Color::Reset, "Eraser"),
+        ],
+    },
+    Palette {
+        name: "greyscale",
+        keys: [
+            ColorKey::new('1', Color::White, "White"),
+            ColorKey::new('2', rgb(219, 219, 219), "86%"),
+            ColorKey::new('3', rgb(182, 182, 182), "71%"),
+            ColorKey::new('4', rgb(146, 146, 146), "57%"),
+            ColorKey::new('5', rgb(109, 109, 109), "43%"),
+            ColorKey::new('6', rgb(73, 73, 73), "29%"),
+            ColorKey::new('7', rgb(36, 36, 36), "14%"),
+            ColorKey::new('8', Color::Black, "Black"),
+            ColorKey::new('0', Color::Reset, "Eraser"),
+        ],
+    },
 ];
 
-impl Paint2D {
-    fn new(terminal_size: &(u16, u16)) -> Self {
+/// Finds a palette by its name
+/// How big a canvas that follows the terminal is, for a terminal of `(cols, rows)`. It
+/// doesn't depend on the settings for how the canvas is shown (e.g. `square-pixels` or
+/// the palette strip), so that changing them never changes the picture.
+fn canvas_size_for_terminal((cols, rows): (u16, u16)) -> (u16, u16) {
+    (cols.max(1), rows.saturating_sub(BOTTOM_BAR_HEIGHT).max(1))
+}
+
+fn find_palette(name: &str) -> Result<&'static Palette, String> {
+    PALETTES
+        .iter()
+        .find(|palette| palette.name == name)
+        .ok_or_else(|| {
+            let names: Vec<&str> = PALETTES.iter().map(|palette| palette.name).collect();
+            format!(
+                "There's no palette called {}. Try one of these: {}",
+                name,
+                names.join(", ")
+            )
+        })
+}
+
+impl<W: Write> Paint2D<W> {
+    /// Sets up the program with a canvas to fit the terminal. The canvas is transparent,
+    /// unless a `background` colour is given to fill it with.
+    fn new(
+        stdout: W,
+        terminal_size: &(u16, u16),
+        settings: Settings,
+        background: Option<Color>,
+    ) -> Self {
         let rows = terminal_size.1;
-        let cols = terminal_size.0;
-        let canvas_size = (cols, rows - BOTTOM_BAR_HEIGHT);
+        let canvas_size = canvas_size_for_terminal(*terminal_size);
         Paint2D {
-            stdout: std::io::stdout(),
+            stdout,
+            terminal_colors: TerminalColors::default(),
             running: Arc::new(AtomicBool::new(true)),
-            cursor: PaintCursor::new(0, 1, canvas_size),
-            terminal_size: terminal_size.clone(),
-            color_canvas: vec![vec![None; canvas_size.0.into()]; canvas_size.1.into()],
+            cursor: PaintCursor::new(0, 1.min(canvas_size.0 - 1), canvas_size),
+            terminal_size: *terminal_size,
+            canvas: match background {
+                Some(color) => Canvas::new_filled(canvas_size.0, canvas_size.1, color),
+                None => Canvas::new(canvas_size.0, canvas_size.1),
+            },
+            scroll: (0, 0),
+            canvas_follows_terminal: true,
             space_button_held: false,
             // True if the terminal sends key release events (as well as normal key down events)
             enhanced_key_events: false,
-            color_bar_row: rows - COLOR_BAR_ROW_FROM_BOTTOM,
+            keyboard_enhancement_pushed: false,
+            alternate_screen: true,
+            mono: false,
+            inline_cursor_start: None,
+            color_bar_row: rows.saturating_sub(COLOR_BAR_ROW_FROM_BOTTOM),
+            palette: &PALETTES[0],
             color_bar_color_labels: HashMap::from_iter(
-                COLOR_KEYS
+                PALETTES[0]
+                    .keys
                     .iter()
                     // We haven't drawn the color bar yet, so all colours take up 0 space
-                    .map(|color_key| (color_key.color.clone(), 0..0)),
+                    .map(|color_key| (color_key.color, 0..0)),
             ),
+            selection: None,
+            drag_start: None,
+            selection_anchor: None,
+            grab: None,
+            extra_cursors: Vec::new(),
+            clipboard: None,
+            held_arrow: None,
+            drawn_cursors: Vec::new(),
+            last_paint: None,
+            last_painted_cell: None,
+            rainbow_hue: 0,
+            settings,
+            tool: Tool::Brush,
+            confirming_quit: false,
+            command_line: None,
+            command_history: read_command_history(),
+            history_position: None,
+            status_message: None,
+            macros: HashMap::new(),
+            recording_macro: None,
+            awaiting_register: None,
+            playing_macro: false,
+            flash_until: None,
+            needs_redraw: true,
+            cursors_moved: false,
+            last_render: Instant::now(),
+            last_event: Instant::now(),
+            color_slots: [None; COLOR_SLOT_COUNT],
+            show_color_slots: false,
+            quantized_palette: None,
+            session_path: None,
+            recent_files: None,
+            show_help: false,
+            diff: None,
+            pending_resize: None,
+            minimap: None,
+            mixer: None,
+            previous_color: None,
+            measurement: None,
+            #[cfg(feature = "ipc")]
+            socket: None,
+            debug: None,
         }
     }
 
     fn setup(&mut self) -> std::io::Result<()> {
         terminal::enable_raw_mode()?;
-        self.stdout.execute(terminal::EnterAlternateScreen)?;
-        self.stdout.execute(event::EnableMouseCapture)?;
-        let _ = self.stdout.execute(event::PushKeyboardEnhancementFlags(
-            event::KeyboardEnhancementFlags::REPORT_EVENT_TYPES,
-        ));
+        // Some terminals don't support everything, so we carry on without the features
+        // that fail to be enabled, and let the user know which ones they're missing
+        let mut unsupported = Vec::new();
+        if self.alternate_screen && self.stdout.execute(terminal::EnterAlternateScreen).is_err() {
+            self.alternate_screen = false;
+            unsupported.push("alternate screen");
+        }
+        if !self.alternate_screen
+            && let Ok((col, _)) = cursor::position()
+        {
+            // Scroll whatever was in the terminal up into its scrollback, so that the
+            // whole screen is free for the canvas and none of it gets drawn over
+            let newlines = self.terminal_size.1.saturating_sub(1);
+            write!(self.stdout, "{}", "\n".repeat(newlines.into()))?;
+            // The line the cursor was on is at the top now
+            self.inline_cursor_start = Some((col, 0));
+        }
+        if self.stdout.execute(event::EnableMouseCapture).is_err() {
+            unsupported.push("mouse");
+        }
+        // Plenty of terminals (like the legacy Windows console) don't have keyboard
+        // enhancement, and we can manage without it, so it isn't worth mentioning
+        if terminal::supports_keyboard_enhancement().unwrap_or(false) {
+            self.keyboard_enhancement_pushed = self
+                .stdout
+                .execute(event::PushKeyboardEnhancementFlags(
+                    event::KeyboardEnhancementFlags::REPORT_EVENT_TYPES,
+                ))
+                .is_ok();
+        }
         // Hide the cursor as much as we can
-        self.stdout
-            .execute(cursor::SetCursorStyle::SteadyUnderScore)?;
+        if self
+            .stdout
+            .execute(cursor::SetCursorStyle::SteadyUnderScore)
+            .is_err()
+        {
+            unsupported.push("cursor style");
+        }
         self.stdout.execute(cursor::MoveTo(0, 0))?;
-        self.stdout.execute(cursor::Hide)?;
+        if self.stdout.execute(cursor::Hide).is_err() {
+            unsupported.push("hiding the cursor");
+        }
+        if !unsupported.is_empty() {
+            log::warn!("Unsupported terminal features: {}", unsupported.join(", "));
+            self.status_message = Some(format!(
+                "Your terminal doesn't support some features, so they've been turned off: {}",
+                unsupported.join(", ")
+            ));
+        }
         Ok(())
     }
 
+    /// Draws the main cursor, and any extra cursors in a thinner style
     fn draw_cursor(&mut self) -> std::io::Result<()> {
-        const CHARS: [char; 3] = ['┣', 'ˣ', '┫'];
-        let offset: u32 = (CHARS.len() / 2).try_into().unwrap();
-        for (i, char) in CHARS.iter().enumerate() {
+        self.drawn_cursors = self.extra_cursors.clone();
+        self.drawn_cursors.push((self.cursor.row, self.cursor.col));
+        for (row, col) in self.extra_cursors.clone() {
+            self.draw_cursor_shape(row, col, ['├', 'ˣ', '┤'])?;
+        }
+        self.draw_cursor_shape(self.cursor.row, self.cursor.col, ['┣', 'ˣ', '┫'])
+    }
+
+    fn draw_cursor_shape(&mut self, row: u16, col: u16, chars: [char; 3]) -> std::io::Result<()> {
+        if self.settings.square_pixels {
+            return self.draw_square_cursor_shape(row, col, [chars[0], chars[2]]);
+        }
+        let offset: u32 = (chars.len() / 2).try_into().unwrap();
+        for (i, char) in chars.iter().enumerate() {
             // The next few lines are pure Rust pain
             // I just want to subtract two numbers and get a negative number >:(
             let i: i32 = i.try_into().unwrap();
             let relative_pos: i32 = i - TryInto::<i32>::try_into(offset).unwrap();
-            let cursor_col: i32 = self.cursor.col.into();
+            let cursor_col: i32 = col.into();
             let current_col: i32 = cursor_col + relative_pos;
             // Back to relative sanity
             let current_col: u16 = match current_col.try_into() {
@@ -196,38 +1120,200 @@ impl Paint2D {
                 // so we won't draw it
                 Err(_) => continue,
             };
-            let color = self
-                .color_canvas
-                .get(self.cursor.row as usize)
-                .and_then(|row| row.get(current_col as usize))
-                .copied()
-                .flatten();
-            self.stdout.execute(MoveTo(current_col, self.cursor.row))?;
+            let mut color = self.canvas.get_cell(row, current_col).flatten();
+            // Preview what the cell under the cursor would look like if it was painted
+            // (this is only drawn to the screen, not to the canvas)
+            if relative_pos == 0 && self.canvas.is_paintable(row, current_col) {
+                color = match self.cursor.color {
+                    Color::Reset => None,
+                    color => Some(color),
+                };
+            }
+            let Some((screen_row, screen_col)) = self.canvas_to_screen(row, current_col) else {
+                continue;
+            };
+            self.stdout.execute(MoveTo(screen_col, screen_row))?;
             if let Some(color) = color {
-                self.stdout.execute(SetBackgroundColor(color))?;
-                self.stdout.execute(SetForegroundColor(make_dark(color)))?;
+                let foreground = self
+                    .settings
+                    .cursor_color
+                    .unwrap_or_else(|| contrasting_color(color));
+                self.terminal_colors
+                    .set_background(&mut self.stdout, color)?;
+                self.terminal_colors
+                    .set_foreground(&mut self.stdout, foreground)?;
                 self.stdout.execute(Print(char))?;
-                self.stdout.execute(SetBackgroundColor(Color::Reset))?;
-                self.stdout.execute(SetForegroundColor(Color::Reset))?;
+                self.terminal_colors
+                    .set_background(&mut self.stdout, Color::Reset)?;
+                self.terminal_colors
+                    .set_foreground(&mut self.stdout, Color::Reset)?;
             } else {
-                self.stdout.execute(SetForegroundColor(self.cursor.color))?;
+                let foreground = self.settings.cursor_color.unwrap_or(self.cursor.color);
+                self.terminal_colors
+                    .set_foreground(&mut self.stdout, foreground)?;
                 self.stdout.execute(Print(char))?;
-                self.stdout.execute(SetForegroundColor(Color::Reset))?;
+                self.terminal_colors
+                    .set_foreground(&mut self.stdout, Color::Reset)?;
             }
         }
-        self.stdout.execute(SetForegroundColor(Color::White))?;
+        self.terminal_colors
+            .set_foreground(&mut self.stdout, Color::White)?;
+        Ok(())
+    }
+
+    /// Draws the cursor when each cell is two columns wide. The brackets go inside the
+    /// cell, which previews what it would look like if it was painted.
+    fn draw_square_cursor_shape(
+        &mut self,
+        row: u16,
+        col: u16,
+        brackets: [char; 2],
+    ) -> std::io::Result<()> {
+        let Some((screen_row, screen_col)) = self.canvas_to_screen(row, col) else {
+            return Ok(());
+        };
+        let mut color = self.canvas.get_cell(row, col).flatten();
+        if self.canvas.is_paintable(row, col) {
+            color = match self.cursor.color {
+                Color::Reset => None,
+                color => Some(color),
+            };
+        }
+        self.stdout.execute(MoveTo(screen_col, screen_row))?;
+        let foreground = match color {
+            Some(color) => {
+                self.terminal_colors
+                    .set_background(&mut self.stdout, color)?;
+                self.settings
+                    .cursor_color
+                    .unwrap_or_else(|| contrasting_color(color))
+            }
+            None => self.settings.cursor_color.unwrap_or(self.cursor.color),
+        };
+        self.terminal_colors
+            .set_foreground(&mut self.stdout, foreground)?;
+        self.stdout
+            .execute(Print(brackets.iter().collect::<String>()))?;
+        self.terminal_colors.reset(&mut self.stdout)?;
+        self.terminal_colors
+            .set_foreground(&mut self.stdout, Color::White)?;
         Ok(())
     }
 
     fn draw_bottom_bar(&mut self) -> std::io::Result<()> {
         self.stdout.execute(MoveTo(0, self.terminal_size.1 - 1))?;
-        self.stdout.execute(SetForegroundColor(Color::Black))?;
-        self.stdout.execute(SetBackgroundColor(Color::White))?;
-        write!(
-            self.stdout,
-            "Arrow keys: move, Space: paint, Number keys: change color, E: export, Q: quit"
-        )?;
-        self.stdout.execute(ResetColor)?;
+        let (foreground, background) = match self.flash_until {
+            Some(_) => (Color::White, Color::Black),
+            None => (Color::Black, Color::White),
+        };
+        self.terminal_colors
+            .set_foreground(&mut self.stdout, foreground)?;
+        self.terminal_colors
+            .set_background(&mut self.stdout, background)?;
+        if let Some(command) = &self.command_line {
+            write!(self.stdout, ":{}", command)?;
+        } else if let Some(message) = &self.status_message {
+            write!(self.stdout, "{}", message)?;
+        } else {
+            if let Some((register, _)) = &self.recording_macro {
+                write!(self.stdout, "Recording @{} | ", register)?;
+            }
+            let (x, y) = self.position_to_coordinates((self.cursor.row, self.cursor.col));
+            write!(self.stdout, "{},{} | ", x, y)?;
+            if self.settings.show_coverage {
+                let painted = self.canvas.painted_count();
+                let total = self.canvas.width() as usize * self.canvas.height() as usize;
+                let percent = painted as f64 / total as f64 * 100.0;
+                write!(self.stdout, "{} painted ({:.1}%) | ", painted, percent)?;
+            }
+            if let Some(diff) = &self.diff {
+                let count = diff.differences.len();
+                write!(self.stdout, "{} different from {} | ", count, diff.path)?;
+            }
+            match self.canvas.mask().map(|mask| mask.mode) {
+                Some(MaskMode::Outside) => write!(self.stdout, "Mask: painting outside | ")?,
+                Some(MaskMode::Inside) => write!(self.stdout, "Mask: painting inside | ")?,
+                None => {}
+            }
+            if let Some((from, to)) = self.measurement {
+                write!(self.stdout, "Measured {} | ", measurement_text(from, to))?;
+            }
+            if self.show_help {
+                write!(self.stdout, "Controls: press any key to close")?;
+            } else if self.mixer.is_some() {
+                write!(
+                    self.stdout,
+                    "Mixer: Up/Down: choose, Left/Right: change, Enter: use colour, Esc: cancel"
+                )?;
+            } else if self.recent_files.is_some() {
+                write!(
+                    self.stdout,
+                    "Recent files: Up/Down: choose, Enter: open, Esc: cancel"
+                )?;
+            } else if let Some(grab) = self.grab {
+                let x = grab.to.1 as i32 - grab.area.left as i32;
+                let y = match self.settings.origin {
+                    Origin::TopLeft => grab.to.0 as i32 - grab.area.top as i32,
+                    Origin::BottomLeft => grab.area.top as i32 - grab.to.0 as i32,
+                };
+                write!(
+                    self.stdout,
+                    "Moving selection by {:+},{:+}: Arrow keys: move, Enter: confirm, Esc: cancel",
+                    x, y
+                )?;
+            } else if let Tool::Polygon { vertices } = &self.tool {
+                write!(
+                    self.stdout,
+                    "Polygon: {} vertices. Space: add vertex, Enter: draw, Esc: cancel",
+                    vertices.len()
+                )?;
+            } else if let Tool::Line { start } = &self.tool {
+                match start {
+                    Some(_) => write!(self.stdout, "Line: Space: draw to here, Esc: cancel")?,
+                    None => write!(
+                        self.stdout,
+                        "Line: Space: start a line, Esc: stop drawing lines"
+                    )?,
+                }
+            } else if let Tool::Curve { points } = &self.tool {
+                let next = match points.len() {
+                    0 => "start a curve",
+                    1 => "set the control point",
+                    _ => "draw the curve to here",
+                };
+                write!(self.stdout, "Curve: Space: {}, Esc: cancel", next)?;
+            } else if matches!(self.tool, Tool::Smudge) {
+                write!(
+                    self.stdout,
+                    "Smudge: Arrow keys: smudge colours together, Esc: stop smudging"
+                )?;
+            } else if let Tool::Ruler { start } = &self.tool {
+                match start {
+                    Some(start) => write!(
+                        self.stdout,
+                        "Ruler: {} | Space: pin it, Esc: cancel",
+                        measurement_text(*start, (self.cursor.row, self.cursor.col))
+                    )?,
+                    None => write!(self.stdout, "Ruler: Space: measure from here, Esc: stop")?,
+                }
+            } else if matches!(self.tool, Tool::Connector) {
+                write!(
+                    self.stdout,
+                    "Connector: Space: place a box-drawing line (hold to keep going), Esc: stop"
+                )?;
+            } else {
+                let quit = match self.settings.quit_key {
+                    Some(key) => key.to_uppercase().to_string(),
+                    None => ":q".to_string(),
+                };
+                write!(
+                    self.stdout,
+                    "Arrow keys: move, Space: paint, Number keys: change color, E: export, :: command, {}: quit",
+                    quit
+                )?;
+            }
+        }
+        self.terminal_colors.reset(&mut self.stdout)?;
         Ok(())
     }
 
@@ -235,280 +1321,4319 @@ impl Paint2D {
         self.stdout
             .execute(MoveTo(0, self.terminal_size.1 - COLOR_BAR_ROW_FROM_BOTTOM))?;
         self.color_bar_color_labels.clear();
-        for ColorKey { key, name, color } in COLOR_KEYS.iter() {
+        // Worked out as we go, since asking the terminal where its cursor is would be slow
+        let mut col = 0;
+        for ColorKey { key, name, color } in self.palette.keys.iter() {
             let display_color = match color {
                 Color::Reset => Color::White,
                 _ => *color,
             };
 
-            let (initial_cursor_col, _) = cursor::position()?;
+            let label = format!("{} {}", key, name);
+            let label_cols = col..col + label.chars().count() as u16;
             if self.cursor.color == *color {
-                self.stdout.execute(SetBackgroundColor(display_color))?;
-                self.stdout.execute(SetForegroundColor(Color::Black))?;
-                write!(self.stdout, "{} {}", key, name)?;
-                self.stdout.execute(ResetColor)?;
+                self.terminal_colors
+                    .set_background(&mut self.stdout, display_color)?;
+                self.terminal_colors
+                    .set_foreground(&mut self.stdout, Color::Black)?;
+                write!(self.stdout, "{}", label)?;
+                self.terminal_colors.reset(&mut self.stdout)?;
             } else {
-                self.stdout.execute(SetForegroundColor(display_color))?;
-                write!(self.stdout, "{} {}", key, name)?;
-                self.stdout.execute(ResetColor)?;
+                self.terminal_colors
+                    .set_foreground(&mut self.stdout, display_color)?;
+                write!(self.stdout, "{}", label)?;
+                self.terminal_colors.reset(&mut self.stdout)?;
             }
 
-            // Update the colour_bar_color_labels hashmap
-            let (final_cursor_col, _) = cursor::position()?;
-            self.color_bar_color_labels
-                .insert(color.clone(), initial_cursor_col..final_cursor_col);
+            // Update the colour_bar_color_labels hashmap, leaving space for the gap
+            col = label_cols.end + 1;
+            self.color_bar_color_labels.insert(*color, label_cols);
 
             self.stdout.execute(Print(" "))?;
         }
         Ok(())
     }
 
+    /// Asks for the screen to be redrawn. The event loop renders it once it has handled all
+    /// of the events that are waiting, and no more than once per frame.
     fn redraw_screen(&mut self) -> std::io::Result<()> {
-        self.stdout.execute(Clear(ClearType::All))?;
-        self.stdout.execute(cursor::MoveTo(0, 0))?;
-        for r in 0..self.terminal_size.1 - BOTTOM_BAR_HEIGHT {
-            self.stdout.execute(cursor::MoveTo(0, r))?;
-            for c in 0..self.terminal_size.0 {
-                // None if the access is out of bounds, or if the colour is transparent
-                let color = self
-                    .color_canvas
-                    .get(r as usize)
-                    .and_then(|row| row.get(c as usize))
-                    .copied()
-                    .flatten();
-
-                if let Some(color) = color {
-                    self.stdout.execute(SetBackgroundColor(color))?;
-                    self.stdout.execute(Print(" "))?;
-                    self.stdout.execute(SetBackgroundColor(Color::Reset))?;
-                } else {
-                    self.stdout.execute(cursor::MoveRight(1))?;
-                    // self.stdout.execute(Print(" "))?;
-                }
+        self.needs_redraw = true;
+        Ok(())
+    }
+
+    /// The minimum time between two renders of the screen, from the `max-fps` setting
+    fn frame_budget(&self) -> Duration {
+        Duration::from_secs(1) / self.settings.max_fps.into()
+    }
+
+    /// How long the event loop can wait for input before it has to render a pending frame
+    fn poll_timeout(&self) -> Duration {
+        let timeout = if let Some((_, resized_at)) = self.pending_resize {
+            RESIZE_DEBOUNCE.saturating_sub(resized_at.elapsed())
+        } else if self.cursors_moved {
+            Duration::ZERO
+        } else if self.needs_redraw {
+            self.frame_budget()
+                .saturating_sub(self.last_render.elapsed())
+        } else if let Some(flash_until) = self.flash_until {
+            flash_until.saturating_duration_since(Instant::now())
+        } else if self.is_asleep() {
+            ASLEEP_POLL_TIMEOUT
+        } else {
+            IDLE_POLL_TIMEOUT
+        };
+        #[cfg(feature = "ipc")]
+        if self.socket.is_some() {
+            return timeout.min(SOCKET_POLL_TIMEOUT);
+        }
+        timeout
+    }
+
+    /// Checks if there hasn't been any input for longer than the `idle-timeout` setting
+    fn is_asleep(&self) -> bool {
+        self.settings
+            .idle_timeout
+            .is_some_and(|seconds| self.last_event.elapsed() >= Duration::from_secs(seconds))
+    }
+
+    /// Changes the paintbrush's colour, remembering the old one so that Shift+X can swap
+    /// back to it
+    fn choose_color(&mut self, color: Color) {
+        if color != self.cursor.color {
+            self.previous_color = Some(std::mem::replace(&mut self.cursor.color, color));
+        }
+    }
+
+    /// Lets the user know that something they tried to do didn't work
+    fn reject(&mut self) -> std::io::Result<()> {
+        match self.settings.feedback {
+            Feedback::Flash => {
+                self.flash_until = Some(Instant::now() + FLASH_DURATION);
+                self.redraw_screen()?;
+            }
+            Feedback::Bell => {
+                self.stdout.execute(Print("\x07"))?;
             }
+            Feedback::Off => {}
         }
-        self.draw_cursor()?;
-        self.draw_colors_bar()?;
-        self.draw_bottom_bar()?;
         Ok(())
     }
 
-    fn on_cursor_move(&mut self) -> std::io::Result<()> {
-        // If Space is being held, then immediately splat some paint down
-        if self.space_button_held {
-            let row = self.cursor.col as usize;
-            let col = self.cursor.row as usize;
-            self.color_canvas[col][row] = Some(self.cursor.color);
+    /// Draws a canvas cell without using any colours, for monochrome mode. Painted cells
+    /// are shaded based on how bright they are.
+    fn draw_mono_cell(&mut self, r: u16, c: u16, is_right_half: bool) -> std::io::Result<()> {
+        let (color, glyph) = self.displayed_cell(r, c);
+        let char = match (glyph, color) {
+            (Some(_), _) if is_right_half => ' ',
+            (Some(glyph), _) => glyph.char,
+            // Erased cells show whatever the terminal's background is
+            (None, Some(Color::Reset)) => ' ',
+            (None, Some(color)) => shade_char(color, &self.settings.shade_ramp),
+            (None, None) if !self.canvas.is_paintable(r, c) => '·',
+            (None, None) => ' ',
+        };
+        self.stdout.execute(Print(char))?;
+        Ok(())
+    }
+
+    /// Draws a canvas cell at the terminal's cursor, which should already be in the right
+    /// place. If `overwrite` is false, the screen is assumed to have been cleared, so empty
+    /// cells are skipped over instead of being drawn.
+    fn draw_cell(&mut self, r: u16, c: u16, overwrite: bool) -> std::io::Result<()> {
+        for column in 0..self.pixel_width() {
+            self.draw_cell_column(r, c, overwrite, column > 0)?;
         }
-        self.redraw_screen()?;
         Ok(())
     }
 
-    fn export_canvas_to_image(&self) {
-        // Block widths and heights based on Kitty with default settings
-        const BLOCK_WIDTH: u16 = 9;
-        const BLOCK_HEIGHT: u16 = 20;
-        let image_width = self.cursor.canvas_cols * BLOCK_WIDTH;
-        let image_height = self.cursor.canvas_rows * BLOCK_HEIGHT;
-        let mut image = RgbImage::new(image_width.into(), image_height.into());
-        for row in 0..self.cursor.canvas_rows {
-            for col in 0..self.cursor.canvas_cols {
-                // Named color colors are based on the default Kitty colors
-                let rgb = match self.color_canvas[row as usize][col as usize] {
-                    Some(Color::Reset) => Rgb([0, 0, 0]),
-                    Some(Color::White) => Rgb([255, 255, 255]),
-                    Some(Color::Red) => Rgb([242, 31, 30]),
-                    Some(Color::Green) => Rgb([34, 253, 0]),
-                    Some(Color::Yellow) => Rgb([254, 253, 0]),
-                    Some(Color::Blue) => Rgb([25, 143, 255]),
-                    Some(Color::Magenta) => Rgb([253, 39, 255]),
-                    Some(Color::Cyan) => Rgb([19, 255, 254]),
-                    Some(Color::Grey) => Rgb([221, 221, 221]),
-                    Some(Color::Rgb { r, g, b }) => Rgb([r, g, b]),
-                    None => Rgb([0, 0, 0]),
-                    _ => Rgb([0, 0, 0]), // Any other colours aren't supported by the program, so we draw them black
-                };
-                // Draw the block to the image buffer!
-                // I feel like there should be a more efficient way to do this (without iterating)
-                let start_x = col * BLOCK_WIDTH;
-                let start_y = row * BLOCK_HEIGHT;
-                for y in 0..BLOCK_HEIGHT {
-                    for x in 0..BLOCK_WIDTH {
-                        image.put_pixel((start_x + x).into(), (start_y + y).into(), rgb);
-                    }
-                }
-            }
-        }
-        // Save the image to a file
-        let time = Local::now().format("%Y-%m-%d %H_%M_%S");
-        let filename = format!("Paint 2D at {}.png", time);
-        match image.save(&filename) {
-            Ok(_) => {
-                print!(" Exported canvas to \"{}\"", filename);
+    /// Draws one column of a canvas cell (see `draw_cell`). With `square-pixels` on, each
+    /// cell covers two columns, and glyphs only go in the left one. The colours are left
+    /// as they are afterwards, so that the next cell can carry on using them.
+    fn draw_cell_column(
+        &mut self,
+        r: u16,
+        c: u16,
+        overwrite: bool,
+        is_right_half: bool,
+    ) -> std::io::Result<()> {
+        let (width, height) = (self.canvas.width(), self.canvas.height());
+        if r >= height || c >= width {
+            // Worked out in u32, since twice the size doesn't fit in u16 on huge canvases
+            let in_tiles =
+                u32::from(r) < u32::from(height) * 2 && u32::from(c) < u32::from(width) * 2;
+            if self.settings.wrap_preview && in_tiles {
+                // Show what the canvas would look like tiled, so that any seams stand out
+                return self.draw_cell_column(r % height, c % width, overwrite, is_right_half);
+            }
+            // Shade in the area past the edges of the canvas
+            return self.print_cell('░', Some(Color::DarkGrey), Color::Reset);
+        }
+        if self.mono {
+            return self.draw_mono_cell(r, c, is_right_half);
+        }
+        // None if the colour is transparent
+        let (color, glyph) = self.displayed_cell(r, c);
+        if let Some(glyph) = glyph {
+            let char = if is_right_half { ' ' } else { glyph.char };
+            return self.print_cell(char, Some(glyph.color), color.unwrap_or(Color::Reset));
+        }
+
+        match (color, self.grid_line(r, c, is_right_half)) {
+            // Erased cells have no colour to draw a block with
+            (Some(color), _) if self.settings.block_cells && color != Color::Reset => {
+                self.print_cell('█', Some(color), Color::Reset)
+            }
+            (Some(color), _) => self.print_cell(' ', None, color),
+            // Show which empty cells are protected by the mask
+            (None, _) if !self.canvas.is_paintable(r, c) => {
+                self.print_cell('·', Some(Color::DarkGrey), Color::Reset)
+            }
+            // Guide lines through the paintbrush, for lining things up with it
+            (None, _) if self.settings.crosshair && r == self.cursor.row => {
+                self.print_cell('─', Some(Color::DarkGrey), Color::Reset)
+            }
+            (None, _) if self.settings.crosshair && c == self.cursor.col => {
+                let char = if is_right_half { ' ' } else { '│' };
+                self.print_cell(char, Some(Color::DarkGrey), Color::Reset)
+            }
+            // Grid lines from the `grid` setting
+            (None, Some((char, color))) => self.print_cell(char, Some(color), Color::Reset),
+            (None, _)
+                if self.settings.checkerboard.is_some_and(|size| {
+                    // Cells are about twice as tall as they are wide, so the
+                    // squares are twice as wide (in columns) to look square
+                    (r / size + c * self.pixel_width() / (size * 2)) % 2 == 1
+                }) =>
+            {
+                // Show transparent cells with a checkerboard pattern
+                self.print_cell(' ', None, CHECKERBOARD_COLOR)
             }
-            Err(_) => {
-                print!(" Error exporting canvas to {}", filename);
+            (None, _) if overwrite => self.print_cell(' ', None, Color::Reset),
+            (None, _) => {
+                self.stdout.execute(cursor::MoveRight(1))?;
+                Ok(())
             }
         }
     }
 
-    fn run(&mut self) -> std::io::Result<()> {
-        self.redraw_screen()?;
-        while self.running.load(Ordering::SeqCst) {
-            while event::poll(Duration::from_millis(50))? {
-                match event::read()? {
-                    Event::Key(key) => {
-                        // We need to know if we receive key release events or not
-                        // This is a hacky way of working that out
-                        if key.kind != KeyEventKind::Press {
-                            self.enhanced_key_events = true;
-                        }
+    /// The character and colour of the grid line that goes through a cell, if there is one
+    fn grid_line(&self, r: u16, c: u16, is_right_half: bool) -> Option<(char, Color)> {
+        let (minor, major) = self.settings.grid?;
+        let is_major = |position: u16| major.is_some_and(|major| position.is_multiple_of(major));
+        let on_row = r.is_multiple_of(minor) || is_major(r);
+        let on_col = c.is_multiple_of(minor) || is_major(c);
+        let char = match (on_row, on_col) {
+            (true, true) if is_right_half => '─',
+            (true, true) => '┼',
+            (true, false) => '─',
+            (false, true) if is_right_half => ' ',
+            (false, true) => '│',
+            (false, false) => return None,
+        };
+        let is_major = (on_row && is_major(r)) || (on_col && is_major(c));
+        let color = if is_major {
+            Color::Grey
+        } else {
+            Color::DarkGrey
+        };
+        Some((char, color))
+    }
 
-                        // Keep track of if the space button is being held or not
-                        if key.code == event::KeyCode::Char(' ') && self.enhanced_key_events {
-                            match key.kind {
-                                KeyEventKind::Press => {
-                                    self.space_button_held = true;
-                                }
-                                KeyEventKind::Release => {
-                                    self.space_button_held = false;
-                                }
-                                KeyEventKind::Repeat => {}
-                            }
-                        }
+    /// Prints a character in some colours. The foreground colour can be `None` if the
+    /// character is a space, since it doesn't matter then.
+    fn print_cell(
+        &mut self,
+        char: char,
+        foreground: Option<Color>,
+        background: Color,
+    ) -> std::io::Result<()> {
+        self.terminal_colors
+            .set_background(&mut self.stdout, background)?;
+        if let Some(foreground) = foreground {
+            self.terminal_colors
+                .set_foreground(&mut self.stdout, foreground)?;
+        }
+        self.stdout.execute(Print(char))?;
+        Ok(())
+    }
 
-                        // Prevents actions happening twice per key press
-                        if key.kind == KeyEventKind::Release {
-                            continue;
-                        }
+    fn render_screen(&mut self) -> std::io::Result<()> {
+        let render_start = Instant::now();
+        self.needs_redraw = false;
+        self.cursors_moved = false;
+        self.last_render = Instant::now();
+        // Something else could have changed the colours since the last frame
+        self.terminal_colors = TerminalColors::default();
+        // In the normal screen, some terminals copy the whole screen into their scrollback
+        // when it's cleared, so only the part below the top-left is cleared there
+        let clear = if self.alternate_screen {
+            ClearType::All
+        } else {
+            ClearType::FromCursorDown
+        };
+        self.stdout.execute(cursor::MoveTo(0, 0))?;
+        self.stdout.execute(Clear(clear))?;
+        if self.is_terminal_too_small() {
+            return self.draw_too_small_message();
+        }
+        let (view_cols, view_rows) = self.visible_canvas_size();
+        for screen_row in 0..view_rows {
+            self.stdout.execute(cursor::MoveTo(0, screen_row))?;
+            for view_col in 0..view_cols {
+                self.draw_cell(screen_row + self.scroll.0, view_col + self.scroll.1, false)?;
+            }
+        }
+        self.terminal_colors.reset(&mut self.stdout)?;
+        self.draw_measurements()?;
+        self.draw_polygon_vertices()?;
+        self.draw_selection_border()?;
+        self.draw_diff()?;
+        self.draw_cursor()?;
+        if self.show_color_slots {
+            self.draw_color_slots()?;
+        }
+        if self.settings.minimap {
+            self.draw_minimap()?;
+        }
+        self.draw_quantized_palette()?;
+        self.draw_recent_files()?;
+        self.draw_mixer()?;
+        if self.show_help {
+            self.draw_help()?;
+        }
+        if self.settings.palette_strip {
+            self.draw_palette_strip()?;
+        }
+        if self.settings.tool_sidebar {
+            self.draw_tool_sidebar()?;
+        }
+        self.draw_colors_bar()?;
+        self.draw_bottom_bar()?;
+        if self.debug.is_some() {
+            self.draw_debug_overlay()?;
+        }
+        if let Some(debug) = &mut self.debug {
+            debug.render_time = render_start.elapsed();
+        }
+        Ok(())
+    }
 
-                        let is_speedy = key.modifiers.contains(event::KeyModifiers::CONTROL);
-                        let is_super_speedy =
-                            is_speedy && key.modifiers.contains(event::KeyModifiers::ALT);
-                        let horizontal_movement = if is_super_speedy {
-                            20
-                        } else if is_speedy {
-                            8
-                        } else {
-                            1
-                        };
-                        let vertical_movement = if is_super_speedy {
-                            8
-                        } else if is_speedy {
-                            2
-                        } else {
-                            1
-                        };
-                        match key.code {
-                            event::KeyCode::Char('q') => {
-                                self.running.store(false, Ordering::SeqCst);
-                            }
-                            event::KeyCode::Char('c') => {
-                                if key.modifiers.contains(event::KeyModifiers::CONTROL) {
-                                    // Ctrl+C has been pressed
-                                    self.running.store(false, Ordering::SeqCst);
-                                }
-                            }
-                            event::KeyCode::Left => {
-                                self.cursor.left(horizontal_movement);
-                                self.on_cursor_move()?;
-                            }
-                            event::KeyCode::Right => {
-                                self.cursor.right(horizontal_movement);
-                                self.on_cursor_move()?;
-                            }
-                            event::KeyCode::Up => {
-                                self.cursor.up(vertical_movement);
-                                self.on_cursor_move()?;
-                            }
-                            event::KeyCode::Down => {
-                                self.cursor.down(vertical_movement);
-                                self.on_cursor_move()?;
-                            }
-                            event::KeyCode::Char(' ') => {
-                                let row = self.cursor.col as usize;
-                                let col = self.cursor.row as usize;
-                                self.color_canvas[col][row] = Some(self.cursor.color);
-                                self.redraw_screen()?;
-                            }
-                            event::KeyCode::Char('e') => {
-                                self.export_canvas_to_image();
-                            }
-                            event::KeyCode::Char(char) => {
-                                for ColorKey { key, color, .. } in COLOR_KEYS.iter() {
-                                    if char == *key {
-                                        self.cursor.color = *color;
-                                        self.redraw_screen()?;
-                                        break;
-                                    }
-                                }
-                            }
-                            _ => {}
-                        }
-                    }
-                    Event::Resize(cols, rows) => {
-                        let new_canvas_size = (cols, rows - BOTTOM_BAR_HEIGHT);
-                        // Ensure the cursor stays within the new canvas
-                        if self.cursor.row >= new_canvas_size.1 {
-                            self.cursor.row = new_canvas_size.1 - 1;
-                        }
-                        if self.cursor.col >= new_canvas_size.0 {
-                            self.cursor.col = new_canvas_size.0 - 1;
-                        }
+    /// Checks if the terminal is smaller than the `min-terminal-size` setting
+    fn is_terminal_too_small(&self) -> bool {
+        let (min_cols, min_rows) = self.settings.min_terminal_size;
+        self.terminal_size.0 < min_cols || self.terminal_size.1 < min_rows
+    }
 
-                        // Update the attributes & redraw screen
-                        self.terminal_size = (cols, rows);
-                        self.cursor.set_canvas_size(&(cols, rows - 1));
-                        self.color_bar_row = rows - COLOR_BAR_ROW_FROM_BOTTOM;
-                        self.redraw_screen()?;
-                    }
-                    Event::Mouse(MouseEvent {
-                        kind, column, row, ..
-                    }) => {
-                        // Click to teleport the cursor
-                        if kind == MouseEventKind::Down(MouseButton::Left) {
-                            if row < self.cursor.canvas_rows && column < self.cursor.canvas_cols {
-                                self.cursor.col = column;
-                                self.cursor.row = row;
-                                self.redraw_screen()?;
-                            } else if row == self.color_bar_row {
-                                // Click on a color to select it
-                                for (color, color_cols) in self.color_bar_color_labels.iter() {
-                                    if color_cols.contains(&column) {
-                                        self.cursor.color = *color;
-                                        self.redraw_screen()?;
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    _ => {}
-                }
+    /// Shows a message in the middle of the screen instead of the canvas, for when the
+    /// terminal is too small to draw everything in
+    fn draw_too_small_message(&mut self) -> std::io::Result<()> {
+        let (min_cols, min_rows) = self.settings.min_terminal_size;
+        let (cols, rows) = self.terminal_size;
+        let message = format!(
+            "Terminal too small (need at least {}×{})",
+            min_cols, min_rows
+        );
+        // Split the message over two lines if it doesn't fit on one
+        let lines = match message.split_once(" (") {
+            Some((start, end)) if message.chars().count() > cols as usize => {
+                vec![start.to_string(), format!("({}", end)]
             }
-            self.stdout.flush()?;
+            _ => vec![message],
+        };
+        let top = rows.saturating_sub(lines.len() as u16) / 2;
+        for (i, line) in lines.iter().enumerate().take(rows as usize) {
+            let line: String = line.chars().take(cols as usize).collect();
+            let left = cols.saturating_sub(line.chars().count() as u16) / 2;
+            self.stdout.execute(MoveTo(left, top + i as u16))?;
+            self.stdout.execute(Print(line))?;
         }
         Ok(())
     }
-}
 
-impl Drop for Paint2D {
-    fn drop(&mut self) {
-        let _ = terminal::disable_raw_mode();
-        let _ = self.stdout.execute(cursor::Show);
-        let _ = self.stdout.execute(event::DisableMouseCapture);
-        let _ = self.stdout.execute(event::PopKeyboardEnhancementFlags);
-        let _ = self.stdout.execute(terminal::LeaveAlternateScreen);
-        let _ = self
-            .stdout
-            .execute(cursor::SetCursorStyle::DefaultUserShape);
+    /// Shows performance measurements in the bottom-right corner of the canvas area
+    fn draw_debug_overlay(&mut self) -> std::io::Result<()> {
+        let Some(debug) = &mut self.debug else {
+            return Ok(());
+        };
+        let changed_cells = self.canvas.changed_cells();
+        // A canvas that's been opened or made since the last frame starts counting from zero
+        let dirty_cells = changed_cells
+            .checked_sub(debug.previous_changed_cells)
+            .unwrap_or(changed_cells);
+        let lines = [
+            format!("Last frame: {:.2?}", debug.render_time),
+            format!("Events: {}", debug.events),
+            format!("Canvas: {}x{}", self.canvas.width(), self.canvas.height()),
+            format!("Changed cells: {}", dirty_cells),
+        ];
+        debug.events = 0;
+        debug.previous_changed_cells = changed_cells;
+
+        let (view_cols, view_rows) = self.viewport_size();
+        let width = lines.iter().map(|line| line.len()).max().unwrap_or(0) as u16 + 2;
+        let top = view_rows.saturating_sub(lines.len() as u16);
+        self.terminal_colors
+            .set_background(&mut self.stdout, Color::Black)?;
+        self.terminal_colors
+            .set_foreground(&mut self.stdout, Color::Yellow)?;
+        for (i, line) in lines.iter().enumerate() {
+            self.stdout
+                .execute(MoveTo(view_cols.saturating_sub(width), top + i as u16))?;
+            write!(self.stdout, " {:<1$} ", line, width as usize - 2)?;
+        }
+        self.terminal_colors.reset(&mut self.stdout)?;
+        Ok(())
     }
-}
 
-fn main() -> std::io::Result<()> {
-    let terminal_size: (u16, u16) = terminal::size().unwrap_or((1, 1));
-    let mut app = Paint2D::new(&terminal_size);
-    app.setup()?;
-    app.run()?;
-    Ok(())
+    /// Draws a swatch for each colour slot in the top-right corner of the screen
+    fn draw_color_slots(&mut self) -> std::io::Result<()> {
+        const WIDTH: u16 = 9;
+        let col = self.terminal_size.0.saturating_sub(WIDTH);
+        for (i, slot) in self.color_slots.iter().enumerate() {
+            self.stdout.execute(MoveTo(col, i as u16))?;
+            self.terminal_colors
+                .set_background(&mut self.stdout, Color::Black)?;
+            self.terminal_colors
+                .set_foreground(&mut self.stdout, Color::White)?;
+            write!(self.stdout, " F{} ", i + 1)?;
+            match slot {
+                Some(Color::Reset) => write!(self.stdout, "ERAS")?,
+                Some(color) => {
+                    self.terminal_colors
+                        .set_background(&mut self.stdout, *color)?;
+                    write!(self.stdout, "    ")?;
+                }
+                None => write!(self.stdout, " -- ")?,
+            }
+            self.terminal_colors
+                .set_background(&mut self.stdout, Color::Black)?;
+            write!(self.stdout, " ")?;
+        }
+        self.terminal_colors.reset(&mut self.stdout)?;
+        Ok(())
+    }
+
+    /// Draws a shrunk-down view of the whole canvas in the bottom-left corner of the screen,
+    /// with the part that's on the screen outlined
+    fn draw_minimap(&mut self) -> std::io::Result<()> {
+        let (width, height) = (self.canvas.width(), self.canvas.height());
+        let (view_cols, view_rows) = self.visible_canvas_size();
+        // Shrink both directions by the same amount, so that the shape stays the same
+        let scale = width
+            .div_ceil(MINIMAP_MAX_SIZE.0)
+            .max(height.div_ceil(MINIMAP_MAX_SIZE.1));
+        let size = (width.div_ceil(scale), height.div_ceil(scale));
+        if size.1 >= view_rows || size.0 >= view_cols {
+            return Ok(());
+        }
+        // Only shrink the canvas again if it's changed
+        let version = self.canvas.version();
+        if self
+            .minimap
+            .as_ref()
+            .is_none_or(|(drawn, _)| *drawn != version)
+        {
+            self.minimap = Some((version, self.canvas.downscaled(size.0, size.1)));
+        }
+        let Some((_, cells)) = self.minimap.take() else {
+            return Ok(());
+        };
+
+        // The part of the canvas that's on the screen, in minimap cells
+        let to_minimap = |position: u16, canvas_size: u16, minimap_size: u16| {
+            (position.min(canvas_size - 1) as u32 * minimap_size as u32 / canvas_size as u32) as u16
+        };
+        let top = to_minimap(self.scroll.0, height, size.1);
+        let bottom = to_minimap(self.scroll.0 + view_rows - 1, height, size.1);
+        let left = to_minimap(self.scroll.1, width, size.0);
+        let right = to_minimap(self.scroll.1 + view_cols - 1, width, size.0);
+
+        let screen_top = view_rows - size.1;
+        for (row, cells_row) in cells.iter().enumerate() {
+            let row = row as u16;
+            self.stdout.execute(MoveTo(0, screen_top + row))?;
+            for (col, cell) in cells_row.iter().enumerate() {
+                let col = col as u16;
+                let background = match cell {
+                    Some(Color::Reset) | None => Color::Black,
+                    Some(color) => *color,
+                };
+                let on_outline = (top..=bottom).contains(&row)
+                    && (left..=right).contains(&col)
+                    && (row == top || row == bottom || col == left || col == right);
+                self.terminal_colors
+                    .set_background(&mut self.stdout, background)?;
+                if on_outline {
+                    self.terminal_colors
+                        .set_foreground(&mut self.stdout, contrasting_color(background))?;
+                    write!(self.stdout, "░")?;
+                } else {
+                    write!(self.stdout, " ")?;
+                }
+            }
+        }
+        self.terminal_colors.reset(&mut self.stdout)?;
+        self.minimap = Some((version, cells));
+        Ok(())
+    }
+
+    /// Draws the colours chosen by `:quantize` in the top-right corner, if there are any
+    fn draw_quantized_palette(&mut self) -> std::io::Result<()> {
+        const WIDTH: u16 = 14;
+        let Some(palette) = &self.quantized_palette else {
+            return Ok(());
+        };
+        let col = self.terminal_size.0.saturating_sub(WIDTH);
+        let (_, view_rows) = self.viewport_size();
+        for (i, color) in palette.iter().take(view_rows.into()).enumerate() {
+            self.stdout.execute(MoveTo(col, i as u16))?;
+            self.terminal_colors
+                .set_background(&mut self.stdout, *color)?;
+            write!(self.stdout, "  ")?;
+            self.terminal_colors
+                .set_background(&mut self.stdout, Color::Black)?;
+            self.terminal_colors
+                .set_foreground(&mut self.stdout, Color::White)?;
+            write!(self.stdout, " {:<11}", color_name(*color))?;
+        }
+        self.terminal_colors.reset(&mut self.stdout)?;
+        Ok(())
+    }
+
+    /// Draws a swatch for each colour in the palette down the right edge of the screen,
+    /// marking the paintbrush's colour
+    fn draw_palette_strip(&mut self) -> std::io::Result<()> {
+        let (view_cols, _) = self.viewport_size();
+        for (i, color) in self.palette_strip_colors().into_iter().enumerate() {
+            self.stdout.execute(MoveTo(view_cols, i as u16))?;
+            let is_current = color == self.cursor.color;
+            if color == Color::Reset {
+                // The eraser doesn't have a colour, so it's shaded like the canvas edges
+                self.terminal_colors
+                    .set_foreground(&mut self.stdout, Color::DarkGrey)?;
+                write!(self.stdout, "{}", if is_current { "<>" } else { "░░" })?;
+            } else {
+                self.terminal_colors
+                    .set_background(&mut self.stdout, color)?;
+                self.terminal_colors
+                    .set_foreground(&mut self.stdout, make_dark(color))?;
+                write!(self.stdout, "{}", if is_current { "<>" } else { "  " })?;
+            }
+            self.terminal_colors.reset(&mut self.stdout)?;
+        }
+        Ok(())
+    }
+
+    /// Draws the name of each tool down the right edge of the screen, highlighting the
+    /// current one
+    fn draw_tool_sidebar(&mut self) -> std::io::Result<()> {
+        let left = self.terminal_size.0.saturating_sub(TOOL_SIDEBAR_WIDTH);
+        let (_, view_rows) = self.viewport_size();
+        let current = std::mem::discriminant(&self.tool);
+        for (i, tool) in Tool::all().iter().enumerate().take(view_rows.into()) {
+            self.stdout.execute(MoveTo(left, i as u16))?;
+            let is_current = std::mem::discriminant(tool) == current;
+            if is_current {
+                self.stdout.execute(SetAttribute(Attribute::Reverse))?;
+            }
+            write!(
+                self.stdout,
+                " {:<width$}",
+                tool.name(),
+                width = TOOL_SIDEBAR_WIDTH as usize - 1
+            )?;
+            if is_current {
+                self.stdout.execute(SetAttribute(Attribute::NoReverse))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Switches to the tool that's `offset` places after the current one in the tool
+    /// sidebar, wrapping around at the ends
+    fn cycle_tool(&mut self, offset: isize) -> std::io::Result<()> {
+        let tools = Tool::all();
+        let current = std::mem::discriminant(&self.tool);
+        let index = tools
+            .iter()
+            .position(|tool| std::mem::discriminant(tool) == current)
+            .unwrap_or(0);
+        let index = (index as isize + offset).rem_euclid(tools.len() as isize);
+        self.tool = tools.into_iter().nth(index as usize).unwrap_or(Tool::Brush);
+        if !self.settings.tool_sidebar {
+            self.status_message = Some(format!("Switched to the {} tool", self.tool.name()));
+        }
+        self.redraw_screen()
+    }
+
+    /// Draws the list of recent files in the top-left corner, if it's open
+    fn draw_recent_files(&mut self) -> std::io::Result<()> {
+        let Some((recent_files, selected)) = &self.recent_files else {
+            return Ok(());
+        };
+        let max_width = usize::from(self.terminal_size.0.saturating_sub(4)).max(1);
+        let width = recent_files
+            .iter()
+            .map(|path| path.chars().count())
+            .max()
+            .unwrap_or(0)
+            .min(max_width);
+        for (i, path) in recent_files.iter().enumerate() {
+            // Cut off the start of long paths, since the end is the interesting part
+            let length = path.chars().count();
+            let path: String = if length > width {
+                let rest: String = path.chars().skip(length - width + 1).collect();
+                format!("…{}", rest)
+            } else {
+                path.clone()
+            };
+            let (foreground, background) = if i == *selected {
+                (Color::Black, Color::White)
+            } else {
+                (Color::White, Color::Black)
+            };
+            self.stdout.execute(MoveTo(0, i as u16))?;
+            self.terminal_colors
+                .set_background(&mut self.stdout, background)?;
+            self.terminal_colors
+                .set_foreground(&mut self.stdout, foreground)?;
+            write!(self.stdout, " {:<1$} ", path, width)?;
+        }
+        self.terminal_colors.reset(&mut self.stdout)?;
+        Ok(())
+    }
+
+    /// Handles a key press while the list of recent files is open
+    fn on_recent_files_key(&mut self, key: KeyEvent) -> std::io::Result<()> {
+        let Some((recent_files, selected)) = &mut self.recent_files else {
+            return Ok(());
+        };
+        match key.code {
+            event::KeyCode::Up if *selected > 0 => *selected -= 1,
+            event::KeyCode::Down if *selected + 1 < recent_files.len() => *selected += 1,
+            event::KeyCode::Enter => {
+                let path = recent_files[*selected].clone();
+                self.recent_files = None;
+                match self.open_session(&path) {
+                    Ok(()) => self.status_message = Some(format!("Opened {}", path)),
+                    Err(error) => {
+                        log::warn!("Couldn't open {}: {}", path, error);
+                        self.status_message = Some(error);
+                        self.reject()?;
+                    }
+                }
+            }
+            event::KeyCode::Esc => self.recent_files = None,
+            _ => self.reject()?,
+        }
+        self.redraw_screen()
+    }
+
+    /// Draws the colour mixer in the top-left corner, if it's open. There's a slider for
+    /// each of red, green and blue, and a swatch of the colour underneath.
+    fn draw_mixer(&mut self) -> std::io::Result<()> {
+        let Some(ColorMixer { rgb, channel }) = self.mixer else {
+            return Ok(());
+        };
+        // The slider plus the label before it and the number after it
+        let width = MIXER_SLIDER_WIDTH as usize + 8;
+        let names = ['R', 'G', 'B'];
+        let slider_colors = [Color::Red, Color::Green, Color::Blue];
+        for (i, value) in rgb.iter().enumerate() {
+            self.stdout.execute(MoveTo(0, i as u16))?;
+            let (foreground, background) = if i == channel {
+                (Color::Black, Color::White)
+            } else {
+                (Color::White, Color::Black)
+            };
+            self.terminal_colors
+                .set_background(&mut self.stdout, background)?;
+            self.terminal_colors
+                .set_foreground(&mut self.stdout, foreground)?;
+            write!(self.stdout, " {} ", names[i])?;
+            let filled = (*value as usize * MIXER_SLIDER_WIDTH as usize + 127) / 255;
+            self.terminal_colors
+                .set_background(&mut self.stdout, Color::Black)?;
+            self.terminal_colors
+                .set_foreground(&mut self.stdout, slider_colors[i])?;
+            write!(self.stdout, "{}", "█".repeat(filled))?;
+            self.terminal_colors
+                .set_foreground(&mut self.stdout, Color::DarkGrey)?;
+            let empty = MIXER_SLIDER_WIDTH as usize - filled;
+            write!(self.stdout, "{}", "░".repeat(empty))?;
+            self.terminal_colors
+                .set_foreground(&mut self.stdout, Color::White)?;
+            write!(self.stdout, " {:>3} ", value)?;
+        }
+        let [r, g, b] = rgb;
+        for row in 3..6 {
+            self.stdout.execute(MoveTo(0, row))?;
+            self.terminal_colors
+                .set_background(&mut self.stdout, Color::Rgb { r, g, b })?;
+            write!(self.stdout, "{}", " ".repeat(width))?;
+        }
+        self.terminal_colors.reset(&mut self.stdout)?;
+        Ok(())
+    }
+
+    /// Draws the list of controls in the top-left corner, cutting it off if the screen is
+    /// too small to fit it all
+    fn draw_help(&mut self) -> std::io::Result<()> {
+        let lines = controls_lines(&self.settings);
+        let width = lines
+            .iter()
+            .map(|line| line.chars().count() + 2)
+            .max()
+            .unwrap_or(0)
+            .min(self.terminal_size.0.into());
+        let (_, view_rows) = self.viewport_size();
+        self.terminal_colors
+            .set_background(&mut self.stdout, Color::Black)?;
+        self.terminal_colors
+            .set_foreground(&mut self.stdout, Color::White)?;
+        for (i, line) in lines.iter().take(view_rows.into()).enumerate() {
+            self.stdout.execute(MoveTo(0, i as u16))?;
+            let line: String = line.chars().take(width).collect();
+            write!(self.stdout, "{:<1$}", line, width)?;
+        }
+        self.terminal_colors.reset(&mut self.stdout)?;
+        Ok(())
+    }
+
+    /// Handles a key press while the colour mixer is open
+    fn on_mixer_key(&mut self, key: KeyEvent) -> std::io::Result<()> {
+        let Some(mixer) = &mut self.mixer else {
+            return Ok(());
+        };
+        let step = if key.modifiers.contains(event::KeyModifiers::CONTROL) {
+            MIXER_FAST_STEP
+        } else {
+            1
+        };
+        let value = &mut mixer.rgb[mixer.channel];
+        match key.code {
+            event::KeyCode::Up if mixer.channel > 0 => mixer.channel -= 1,
+            event::KeyCode::Down if mixer.channel < 2 => mixer.channel += 1,
+            event::KeyCode::Left => *value = value.saturating_sub(step),
+            event::KeyCode::Right => *value = value.saturating_add(step),
+            event::KeyCode::Enter => {
+                let [r, g, b] = mixer.rgb;
+                self.choose_color(Color::Rgb { r, g, b });
+                self.mixer = None;
+            }
+            event::KeyCode::Esc => self.mixer = None,
+            _ => self.reject()?,
+        }
+        self.redraw_screen()
+    }
+
+    /// The colour and glyph to show for a cell. This is usually what's on the canvas, but
+    /// can be different while a selection is being moved.
+    fn displayed_cell(&self, row: u16, col: u16) -> (Option<Color>, Option<Glyph>) {
+        let (mut row, mut col) = (row, col);
+        if let Some(grab) = self.grab
+            && self.canvas.is_paintable(row, col)
+        {
+            let destination = grab.area.moved_to(grab.to);
+            if destination.contains(row, col) {
+                // Show the cell that would be moved here
+                row = row - grab.to.0 + grab.area.top;
+                col = col - grab.to.1 + grab.area.left;
+                if !self.canvas.is_paintable(row, col) {
+                    return (None, None);
+                }
+            } else if grab.area.contains(row, col) {
+                return (None, None);
+            }
+        }
+        (
+            self.canvas.get_cell(row, col).flatten(),
+            self.canvas.get_glyph(row, col).flatten(),
+        )
+    }
+
+    /// Marks the vertices of the polygon that's being built, or the start of the line
+    fn draw_polygon_vertices(&mut self) -> std::io::Result<()> {
+        let vertices = match &self.tool {
+            Tool::Polygon { vertices } => vertices.as_slice(),
+            Tool::Line { start } | Tool::Ruler { start } => start.as_slice(),
+            Tool::Curve { points } => points.as_slice(),
+            Tool::Brush | Tool::Smudge | Tool::Connector => return Ok(()),
+        };
+        if let Tool::Curve { points } = &self.tool {
+            // Faintly show the lines between the curve's points, and on to the cursor
+            let mut lines = points.clone();
+            lines.push((self.cursor.row, self.cursor.col));
+            self.terminal_colors
+                .set_foreground(&mut self.stdout, Color::DarkGrey)?;
+            for line in lines.windows(2) {
+                let (from, to) = (line[0], line[1]);
+                let from = (from.0.into(), from.1.into());
+                for (row, col) in line_points(from, (to.0.into(), to.1.into())) {
+                    if let Some((screen_row, screen_col)) =
+                        self.canvas_to_screen(row as u16, col as u16)
+                    {
+                        self.stdout.execute(MoveTo(screen_col, screen_row))?;
+                        self.stdout.execute(Print("·"))?;
+                    }
+                }
+            }
+        }
+        self.terminal_colors
+            .set_foreground(&mut self.stdout, self.cursor.color)?;
+        for (row, col) in vertices.iter() {
+            if let Some((screen_row, screen_col)) = self.canvas_to_screen(*row, *col) {
+                self.stdout.execute(MoveTo(screen_col, screen_row))?;
+                self.stdout.execute(Print("●"))?;
+            }
+        }
+        self.terminal_colors.reset(&mut self.stdout)?;
+        Ok(())
+    }
+
+    /// Faintly shows the measurement pinned with the ruler tool, and the one that it's in the
+    /// middle of making, as dotted lines
+    fn draw_measurements(&mut self) -> std::io::Result<()> {
+        let mut measurements: Vec<_> = self.measurement.into_iter().collect();
+        if let Tool::Ruler { start: Some(start) } = self.tool {
+            measurements.push((start, (self.cursor.row, self.cursor.col)));
+        }
+        self.terminal_colors
+            .set_foreground(&mut self.stdout, Color::Grey)?;
+        for (from, to) in measurements {
+            let from = (from.0.into(), from.1.into());
+            for (row, col) in line_points(from, (to.0.into(), to.1.into())) {
+                if let Some((screen_row, screen_col)) =
+                    self.canvas_to_screen(row as u16, col as u16)
+                {
+                    self.stdout.execute(MoveTo(screen_col, screen_row))?;
+                    self.stdout.execute(Print("·"))?;
+                }
+            }
+        }
+        self.terminal_colors.reset(&mut self.stdout)?;
+        Ok(())
+    }
+
+    /// Draws a box around the selection, on the cells just outside of it. Sides of the
+    /// selection that are at the edge of the canvas have nowhere outside to go, so they're
+    /// drawn on the selection's own edge cells instead, in reverse video.
+    fn draw_selection_border(&mut self) -> std::io::Result<()> {
+        let Some(selection) = self.selection else {
+            return Ok(());
+        };
+        let top = selection.top.checked_sub(1).unwrap_or(selection.top);
+        let left = selection.left.checked_sub(1).unwrap_or(selection.left);
+        let bottom = if selection.bottom + 1 < self.canvas.height() {
+            selection.bottom + 1
+        } else {
+            selection.bottom
+        };
+        let right = if selection.right + 1 < self.canvas.width() {
+            selection.right + 1
+        } else {
+            selection.right
+        };
+        let mut edge_cells = Vec::new();
+        for col in left..=right {
+            edge_cells.push((top, col));
+            edge_cells.push((bottom, col));
+        }
+        for row in top + 1..bottom {
+            edge_cells.push((row, left));
+            edge_cells.push((row, right));
+        }
+        for (row, col) in edge_cells {
+            let Some((screen_row, screen_col)) = self.canvas_to_screen(row, col) else {
+                continue;
+            };
+            let char = match (row == top, row == bottom, col == left, col == right) {
+                (true, _, true, _) => '┌',
+                (true, _, _, true) => '┐',
+                (_, true, true, _) => '└',
+                (_, true, _, true) => '┘',
+                (true, _, _, _) | (_, true, _, _) => '─',
+                _ => '│',
+            };
+            let mut text = char.to_string();
+            if self.settings.square_pixels {
+                // Keep the lines joined up across both columns of the pixel
+                text = if col == left {
+                    format!(" {}", char)
+                } else if col == right {
+                    format!("{} ", char)
+                } else {
+                    format!("{}{}", char, char)
+                };
+            }
+            let is_inside = selection.contains(row, col);
+            self.stdout.execute(MoveTo(screen_col, screen_row))?;
+            if !self.mono {
+                let background = self.displayed_cell(row, col).0.unwrap_or(Color::Reset);
+                self.terminal_colors
+                    .set_background(&mut self.stdout, background)?;
+                self.terminal_colors
+                    .set_foreground(&mut self.stdout, contrasting_color(background))?;
+            }
+            if is_inside {
+                self.stdout.execute(SetAttribute(Attribute::Reverse))?;
+            }
+            self.stdout.execute(Print(text))?;
+            if is_inside {
+                self.stdout.execute(SetAttribute(Attribute::NoReverse))?;
+            }
+        }
+        self.terminal_colors.reset(&mut self.stdout)?;
+        Ok(())
+    }
+
+    /// Marks the cells that are different from the canvas being compared with by `:diff`
+    fn draw_diff(&mut self) -> std::io::Result<()> {
+        let Some(mut diff) = self.diff.take() else {
+            return Ok(());
+        };
+        // Compare again if the canvas has changed since last time
+        let version = self.canvas.version();
+        if diff.version != version {
+            diff.differences = self.canvas.diff(&diff.other);
+            diff.version = version;
+        }
+        for (row, col) in diff.differences.iter() {
+            let Some((screen_row, screen_col)) = self.canvas_to_screen(*row, *col) else {
+                continue;
+            };
+            let background = self.canvas.get_cell(*row, *col).flatten();
+            let background = background.unwrap_or(Color::Reset);
+            self.stdout.execute(MoveTo(screen_col, screen_row))?;
+            self.terminal_colors
+                .set_background(&mut self.stdout, background)?;
+            self.terminal_colors
+                .set_foreground(&mut self.stdout, contrasting_color(background))?;
+            self.stdout.execute(Print("×"))?;
+        }
+        self.terminal_colors.reset(&mut self.stdout)?;
+        self.diff = Some(diff);
+        Ok(())
+    }
+
+    /// The size of the area of the screen that the canvas is drawn in, as `(cols, rows)`
+    fn viewport_size(&self) -> (u16, u16) {
+        let mut strip_width = 0;
+        if self.settings.palette_strip {
+            strip_width += PALETTE_STRIP_WIDTH;
+        }
+        if self.settings.tool_sidebar {
+            strip_width += TOOL_SIDEBAR_WIDTH;
+        }
+        (
+            self.terminal_size.0.saturating_sub(strip_width),
+            self.terminal_size.1.saturating_sub(BOTTOM_BAR_HEIGHT),
+        )
+    }
+
+    /// The colours shown in the palette strip, from top to bottom: the colours on the
+    /// number keys, then any other colours stored in the colour slots
+    fn palette_strip_colors(&self) -> Vec<Color> {
+        let mut colors: Vec<Color> = self
+            .palette
+            .keys
+            .iter()
+            .map(|color_key| color_key.color)
+            .collect();
+        for color in self.color_slots.iter().flatten() {
+            if !colors.contains(color) {
+                colors.push(*color);
+            }
+        }
+        let (_, view_rows) = self.viewport_size();
+        colors.truncate(view_rows.into());
+        colors
+    }
+
+    /// How many columns of the screen each canvas cell takes up
+    fn pixel_width(&self) -> u16 {
+        if self.settings.square_pixels { 2 } else { 1 }
+    }
+
+    /// How many canvas cells fit on the screen at once, as `(cols, rows)`
+    fn visible_canvas_size(&self) -> (u16, u16) {
+        let (view_cols, view_rows) = self.viewport_size();
+        (view_cols / self.pixel_width(), view_rows)
+    }
+
+    /// Works out where a `(row, col)` position on the canvas is on the screen,
+    /// if it's currently visible. With `square-pixels` on, this is the left column of the cell.
+    fn canvas_to_screen(&self, row: u16, col: u16) -> Option<(u16, u16)> {
+        let (view_cols, view_rows) = self.visible_canvas_size();
+        let screen_row = row.checked_sub(self.scroll.0)?;
+        let view_col = col.checked_sub(self.scroll.1)?;
+        (screen_row < view_rows && view_col < view_cols)
+            .then_some((screen_row, view_col * self.pixel_width()))
+    }
+
+    /// Works out which `(row, col)` position on the canvas is at a position on the screen,
+    /// if the canvas is there
+    fn screen_to_canvas(&self, row: u16, col: u16) -> Option<(u16, u16)> {
+        let (view_cols, view_rows) = self.visible_canvas_size();
+        let col = col / self.pixel_width();
+        if row >= view_rows || col >= view_cols {
+            return None;
+        }
+        let canvas_row = row + self.scroll.0;
+        let canvas_col = col + self.scroll.1;
+        (canvas_row < self.canvas.height() && canvas_col < self.canvas.width())
+            .then_some((canvas_row, canvas_col))
+    }
+
+    /// Converts a `(row, col)` on the canvas to the `(x, y)` coordinates shown to the user
+    fn position_to_coordinates(&self, (row, col): (u16, u16)) -> (u16, u16) {
+        match self.settings.origin {
+            Origin::TopLeft => (col, row),
+            Origin::BottomLeft => (col, self.canvas.height() - 1 - row),
+        }
+    }
+
+    /// Converts `(x, y)` coordinates typed in by the user to a `(row, col)` on the canvas
+    fn coordinates_to_position(&self, (x, y): (u16, u16)) -> Result<(u16, u16), String> {
+        let (width, height) = (self.canvas.width(), self.canvas.height());
+        if (x >= width || y >= height) && self.settings.bounds == Bounds::Strict {
+            return Err(format!("{},{} is outside the canvas", x, y));
+        }
+        let (x, y) = (x.min(width - 1), y.min(height - 1));
+        Ok(match self.settings.origin {
+            Origin::TopLeft => (y, x),
+            Origin::BottomLeft => (self.canvas.height() - 1 - y, x),
+        })
+    }
+
+    /// Scrolls the canvas so that the cursor is on the screen
+    fn scroll_to_cursor(&mut self) {
+        /// Works out the scroll position along one axis, keeping at least `margin` cells
+        /// between the cursor and the edges of the view (unless it's at the canvas's edge)
+        fn scroll_axis(
+            scroll: u16,
+            cursor: u16,
+            view_length: u16,
+            canvas_length: u16,
+            margin: u16,
+        ) -> u16 {
+            // The margins can't overlap, otherwise the view would never settle
+            let margin = margin.min(view_length.saturating_sub(1) / 2);
+            let scroll = if cursor < scroll + margin {
+                cursor.saturating_sub(margin)
+            } else if view_length > 0 && cursor + margin >= scroll + view_length {
+                cursor + margin + 1 - view_length
+            } else {
+                scroll
+            };
+            // Don't scroll further than needed to show the end of the canvas
+            scroll.min(canvas_length.saturating_sub(view_length))
+        }
+        let (view_cols, view_rows) = self.visible_canvas_size();
+        self.scroll = (
+            scroll_axis(
+                self.scroll.0,
+                self.cursor.row,
+                view_rows,
+                self.canvas.height(),
+                self.settings.scroll_margin,
+            ),
+            scroll_axis(
+                self.scroll.1,
+                self.cursor.col,
+                view_cols,
+                self.canvas.width(),
+                self.settings.scroll_margin,
+            ),
+        );
+    }
+
+    /// Switches the colours on the number keys. If the paintbrush is using one of the old
+    /// palette's colours, it switches to the colour on the same key in the new palette.
+    fn set_palette(&mut self, palette: &'static Palette) {
+        let key = self
+            .palette
+            .keys
+            .iter()
+            .find(|color_key| color_key.color == self.cursor.color)
+            .map(|color_key| color_key.key);
+        if let Some(color_key) = palette
+            .keys
+            .iter()
+            .find(|color_key| Some(color_key.key) == key)
+        {
+            self.cursor.color = color_key.color;
+        }
+        self.palette = palette;
+    }
+
+    /// Shrinks the canvas to an area (see `Canvas::crop`), keeping the cursors over the same
+    /// part of the picture
+    fn crop_canvas(&mut self, area: Selection) {
+        self.canvas.crop(area);
+        let shift = |(row, col): (u16, u16)| {
+            Some((row.checked_sub(area.top)?, col.checked_sub(area.left)?))
+        };
+        let main_cursor = (self.cursor.row, self.cursor.col);
+        (self.cursor.row, self.cursor.col) = shift(main_cursor).unwrap_or((0, 0));
+        self.extra_cursors = self
+            .extra_cursors
+            .iter()
+            .copied()
+            .filter_map(shift)
+            .collect();
+        // The selection would be somewhere else on the picture after moving to the top-left
+        self.selection = None;
+        self.canvas_follows_terminal = false;
+        self.on_canvas_resized();
+    }
+
+    /// Keeps the cursor, selection and scroll position within the canvas after it's resized
+    fn on_canvas_resized(&mut self) {
+        let (width, height) = (self.canvas.width(), self.canvas.height());
+        self.cursor.set_canvas_size(&(width, height));
+        self.cursor.row = self.cursor.row.min(height - 1);
+        self.cursor.col = self.cursor.col.min(width - 1);
+        self.extra_cursors
+            .retain(|(row, col)| *row < height && *col < width);
+        // Points that tools have already placed need to stay on the canvas too
+        let clamp = |(row, col): &mut (u16, u16)| {
+            (*row, *col) = ((*row).min(height - 1), (*col).min(width - 1));
+        };
+        match &mut self.tool {
+            Tool::Polygon { vertices } => vertices.iter_mut().for_each(clamp),
+            Tool::Line { start } | Tool::Ruler { start } => start.iter_mut().for_each(clamp),
+            Tool::Curve { points } => points.iter_mut().for_each(clamp),
+            Tool::Brush | Tool::Smudge | Tool::Connector => {}
+        }
+        self.drag_start.iter_mut().for_each(clamp);
+        if let Some((from, to)) = &mut self.measurement {
+            clamp(from);
+            clamp(to);
+        }
+        // The selection being moved might not fit any more
+        self.grab = None;
+        self.selection = self
+            .selection
+            .and_then(|selection| selection.intersection(&self.canvas.full_area()));
+        self.scroll_to_cursor();
+    }
+
+    /// Moves the main cursor and all the extra cursors in the same way
+    fn move_cursors(&mut self, movement: impl Fn(&mut PaintCursor)) {
+        let main_cursor = (self.cursor.row, self.cursor.col);
+        let mut moves = Vec::new();
+        for extra_cursor in self.extra_cursors.iter_mut() {
+            (self.cursor.row, self.cursor.col) = *extra_cursor;
+            movement(&mut self.cursor);
+            moves.push((*extra_cursor, (self.cursor.row, self.cursor.col)));
+            *extra_cursor = (self.cursor.row, self.cursor.col);
+        }
+        (self.cursor.row, self.cursor.col) = main_cursor;
+        movement(&mut self.cursor);
+        moves.push((main_cursor, (self.cursor.row, self.cursor.col)));
+        if matches!(self.tool, Tool::Smudge) {
+            for (from, to) in moves {
+                self.smudge_along(from, to, &movement);
+            }
+        }
+    }
+
+    /// Smudges along the path that a cursor took from `from` to `to`. If it wrapped around
+    /// an edge, the path is split there, instead of smudging straight across the canvas.
+    fn smudge_along(
+        &mut self,
+        from: (u16, u16),
+        to: (u16, u16),
+        movement: &impl Fn(&mut PaintCursor),
+    ) {
+        // Where the cursor would have stopped if it couldn't wrap
+        let mut stopping = PaintCursor {
+            row: from.0,
+            col: from.1,
+            wrap_h: false,
+            wrap_v: false,
+            ..self.cursor
+        };
+        movement(&mut stopping);
+        let edge = (stopping.row, stopping.col);
+        if edge == to {
+            return self.canvas.smudge(from, to);
+        }
+        self.canvas.smudge(from, edge);
+        // Carry on from the opposite edge along any axis that it wrapped around
+        let across = |stopped: u16, to: u16, length: u16| {
+            if stopped == to {
+                to
+            } else if stopped == 0 {
+                length - 1
+            } else {
+                0
+            }
+        };
+        let start = (
+            across(edge.0, to.0, self.canvas.height()),
+            across(edge.1, to.1, self.canvas.width()),
+        );
+        self.canvas.smudge(start, to);
+    }
+
+    fn on_cursor_move(&mut self) -> std::io::Result<()> {
+        let old_scroll = self.scroll;
+        self.scroll_to_cursor();
+        // If Space is being held, then immediately splat some paint down
+        if self.space_button_held && matches!(self.tool, Tool::Brush | Tool::Connector) {
+            self.use_tool()?;
+            return self.redraw_screen();
+        }
+        if self.scroll == old_scroll && self.can_redraw_just_cursors() {
+            self.cursors_moved = true;
+            Ok(())
+        } else {
+            self.redraw_screen()
+        }
+    }
+
+    /// Checks if moving the cursors only changes the cells around them on the screen, so
+    /// that the rest of the screen doesn't need to be redrawn. Anything that's drawn on top
+    /// of the canvas (or that changes as the cursor moves) needs a full redraw.
+    fn can_redraw_just_cursors(&self) -> bool {
+        !self.needs_redraw
+            && matches!(self.tool, Tool::Brush)
+            && self.grab.is_none()
+            && self.recent_files.is_none()
+            && self.mixer.is_none()
+            && !self.show_help
+            && !self.settings.minimap
+            && !self.settings.crosshair
+            && self.diff.is_none()
+            && self.selection.is_none()
+            && self.measurement.is_none()
+            && !self.is_terminal_too_small()
+            && !self.show_color_slots
+            && self.quantized_palette.is_none()
+            && self.debug.is_none()
+    }
+
+    /// Draws over where the cursors used to be, then draws them in their new places.
+    /// This is much quicker than redrawing the whole screen.
+    fn redraw_cursors(&mut self) -> std::io::Result<()> {
+        self.cursors_moved = false;
+        self.terminal_colors = TerminalColors::default();
+        for (row, col) in std::mem::take(&mut self.drawn_cursors) {
+            // Each cursor covers the cell on either side of it too
+            for col in col.saturating_sub(1)..=col.saturating_add(1) {
+                if let Some((screen_row, screen_col)) = self.canvas_to_screen(row, col) {
+                    self.stdout.execute(MoveTo(screen_col, screen_row))?;
+                    self.draw_cell(row, col, true)?;
+                }
+            }
+        }
+        self.terminal_colors.reset(&mut self.stdout)?;
+        self.draw_cursor()?;
+        // The bottom bar shows the cursor's position
+        self.stdout.execute(MoveTo(0, self.terminal_size.1 - 1))?;
+        self.stdout.execute(Clear(ClearType::CurrentLine))?;
+        self.draw_bottom_bar()
+    }
+
+    /// Does whatever the current tool does when Space is pressed
+    fn use_tool(&mut self) -> std::io::Result<()> {
+        let stamping = self.is_stamping();
+        match &mut self.tool {
+            Tool::Brush => self.paint_at_cursor()?,
+            Tool::Polygon { vertices } => vertices.push((self.cursor.row, self.cursor.col)),
+            Tool::Line { start } => match start.take() {
+                Some(start) if stamping => {
+                    let end = (self.cursor.row, self.cursor.col);
+                    let path = line_points(
+                        (start.0.into(), start.1.into()),
+                        (end.0.into(), end.1.into()),
+                    );
+                    self.stamp_along(&path);
+                }
+                Some(start) => self.canvas.draw_line(
+                    start,
+                    (self.cursor.row, self.cursor.col),
+                    self.cursor.color,
+                    self.settings.smooth_lines,
+                ),
+                None => *start = Some((self.cursor.row, self.cursor.col)),
+            },
+            Tool::Curve { points } => {
+                points.push((self.cursor.row, self.cursor.col));
+                if let [start, control, end] = points[..] {
+                    points.clear();
+                    if stamping {
+                        let point = |(row, col): (u16, u16)| (row.into(), col.into());
+                        let path = curve_points(point(start), point(control), point(end));
+                        self.stamp_along(&path);
+                    } else {
+                        self.canvas
+                            .draw_curve(start, control, end, self.cursor.color);
+                    }
+                }
+            }
+            Tool::Smudge => self.reject()?,
+            Tool::Connector => self.place_connectors(),
+            Tool::Ruler { start } => match start.take() {
+                Some(start) => self.measurement = Some((start, (self.cursor.row, self.cursor.col))),
+                None => *start = Some((self.cursor.row, self.cursor.col)),
+            },
+        }
+        Ok(())
+    }
+
+    /// Places a box-drawing character under each cursor, joining it up with its neighbours
+    fn place_connectors(&mut self) {
+        let mut cursors = vec![(self.cursor.row, self.cursor.col)];
+        cursors.extend_from_slice(&self.extra_cursors);
+        for (row, col) in cursors {
+            self.canvas.place_connector(row, col, self.cursor.color);
+        }
+        self.last_painted_cell = Some((self.cursor.row, self.cursor.col));
+    }
+
+    /// Checks if lines and curves should be drawn by stamping the brush along them, rather
+    /// than as thin, solid strokes
+    fn is_stamping(&self) -> bool {
+        self.settings.brush_size > 1 || self.settings.stamp_spacing > 1
+    }
+
+    /// Stamps the paintbrush along a path, using the `brush-size` and `stamp-spacing` settings
+    fn stamp_along(&mut self, path: &[(i32, i32)]) {
+        self.canvas.stamp_along(
+            path,
+            self.settings.brush_size,
+            self.settings.stamp_spacing,
+            self.cursor.color,
+        );
+    }
+
+    /// Draws the polygon that's being built, and goes back to the brush.
+    /// Returns false if there wasn't a polygon to draw.
+    fn finish_polygon(&mut self) -> bool {
+        let Tool::Polygon { vertices } = std::mem::replace(&mut self.tool, Tool::Brush) else {
+            return false;
+        };
+        if vertices.is_empty() {
+            return false;
+        }
+        self.canvas
+            .draw_polygon(&vertices, self.cursor.color, self.settings.fill_polygons);
+        true
+    }
+
+    /// Paints under the main cursor and all the extra cursors
+    fn paint_at_cursor(&mut self) -> std::io::Result<()> {
+        let mut cursors = vec![(self.cursor.row, self.cursor.col)];
+        cursors.extend_from_slice(&self.extra_cursors);
+        let size = self.current_brush_size();
+        let (width, height) = (self.canvas.width(), self.canvas.height());
+        // Brushes can hang off the edge of the canvas, but the cursors shouldn't be off it
+        let all_on_canvas = cursors
+            .iter()
+            .all(|(row, col)| *row < height && *col < width);
+        let points: Vec<(u16, u16)> = cursors
+            .iter()
+            .flat_map(|(row, col)| brush_points(((*row).into(), (*col).into()), size))
+            .filter_map(|(row, col)| Some((u16::try_from(row).ok()?, u16::try_from(col).ok()?)))
+            .filter(|(row, col)| *row < height && *col < width)
+            .collect();
+        let color = self.next_paint_color();
+        self.canvas.paint_many(&points, color);
+        self.last_painted_cell = Some((self.cursor.row, self.cursor.col));
+        if !all_on_canvas && self.settings.bounds == Bounds::Strict {
+            self.reject()?;
+        }
+        Ok(())
+    }
+
+    /// The colour for the next dab of paint, which is the paintbrush's colour unless
+    /// `rainbow` is on. The eraser always erases.
+    fn next_paint_color(&mut self) -> Color {
+        match self.settings.rainbow {
+            Some(step) if self.cursor.color != Color::Reset => {
+                let color = hue_to_color(self.rainbow_hue);
+                self.rainbow_hue = (self.rainbow_hue + step) % 360;
+                color
+            }
+            _ => self.cursor.color,
+        }
+    }
+
+    /// How big the paintbrush should be for the next dab of paint. This is usually the
+    /// `brush-size` setting, but with `pressure` on it depends on how quickly the last few
+    /// dabs were painted.
+    fn current_brush_size(&mut self) -> u16 {
+        let max_size = self.settings.brush_size;
+        if !self.settings.pressure {
+            return max_size;
+        }
+        let now = Instant::now();
+        let size = match self.last_paint {
+            Some((time, size)) if now - time < PRESSURE_FAST => (size + 1).min(max_size),
+            Some((time, size)) if now - time < PRESSURE_SLOW => size.min(max_size),
+            Some((_, size)) => size.saturating_sub(1).max(1),
+            None => 1,
+        };
+        self.last_paint = Some((now, size));
+        size
+    }
+
+    /// Quits the program, unless there are unsaved changes that the user should be asked
+    /// about first
+    fn request_quit(&mut self) {
+        if self.settings.confirm_quit && self.canvas.has_unsaved_changes() {
+            self.confirming_quit = true;
+            self.status_message = Some(
+                "You have unsaved changes! Press Y to quit anyway, or any other key to keep painting"
+                    .to_string(),
+            );
+        } else {
+            self.running.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Handles Ctrl+C, which can be pressed at any time. Pressing it again while being
+    /// asked about unsaved changes quits anyway.
+    fn on_ctrl_c(&mut self) -> std::io::Result<()> {
+        self.status_message = None;
+        if self.confirming_quit {
+            self.running.store(false, Ordering::SeqCst);
+            return Ok(());
+        }
+        if self.settings.ctrl_c == CtrlC::Quit || !self.cancel_pending() {
+            self.request_quit();
+        }
+        self.redraw_screen()
+    }
+
+    /// Cancels whatever the user is in the middle of, like pressing Esc would. Returns false
+    /// if there wasn't anything to cancel.
+    fn cancel_pending(&mut self) -> bool {
+        if self.command_line.take().is_some() {
+            self.history_position = None;
+            return true;
+        }
+        if let Some(grab) = self.grab.take() {
+            self.selection = Some(grab.area);
+            return true;
+        }
+        if self.recent_files.take().is_some()
+            || self.mixer.take().is_some()
+            || self.awaiting_register.take().is_some()
+            || std::mem::take(&mut self.show_help)
+        {
+            return true;
+        }
+        if !matches!(self.tool, Tool::Brush) {
+            self.tool = Tool::Brush;
+            return true;
+        }
+        self.selection.take().is_some() | self.measurement.take().is_some()
+    }
+
+    /// Handles a key press while the command prompt is open
+    fn on_command_line_key(&mut self, key: event::KeyEvent) -> std::io::Result<()> {
+        let Some(command_line) = &mut self.command_line else {
+            return Ok(());
+        };
+        match key.code {
+            event::KeyCode::Char(char) => command_line.push(char),
+            // Backspacing past the start of the prompt closes it, like in Vim
+            event::KeyCode::Backspace if command_line.pop().is_none() => {
+                self.command_line = None;
+                self.history_position = None;
+            }
+            event::KeyCode::Up => {
+                let position = match &self.history_position {
+                    Some((0, _)) => return self.reject(),
+                    Some((position, _)) => position - 1,
+                    None if self.command_history.is_empty() => return self.reject(),
+                    None => self.command_history.len() - 1,
+                };
+                let typed = match self.history_position.take() {
+                    Some((_, typed)) => typed,
+                    None => command_line.clone(),
+                };
+                *command_line = self.command_history[position].clone();
+                self.history_position = Some((position, typed));
+            }
+            event::KeyCode::Down => match self.history_position.take() {
+                Some((position, typed)) if position + 1 < self.command_history.len() => {
+                    *command_line = self.command_history[position + 1].clone();
+                    self.history_position = Some((position + 1, typed));
+                }
+                // Going past the newest command brings back what was being typed
+                Some((_, typed)) => *command_line = typed,
+                None => return self.reject(),
+            },
+            event::KeyCode::Enter => {
+                let command = self.command_line.take().unwrap_or_default();
+                self.history_position = None;
+                self.add_to_command_history(command.trim());
+                if let Err(error) = self.run_command(command.trim()) {
+                    log::warn!("Command failed: {}: {}", command.trim(), error);
+                    self.status_message = Some(error);
+                    self.reject()?;
+                }
+            }
+            event::KeyCode::Esc => {
+                self.command_line = None;
+                self.history_position = None;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Remembers a command that's been run, so that it can be brought back with Up
+    fn add_to_command_history(&mut self, command: &str) {
+        if command.is_empty()
+            || self
+                .command_history
+                .last()
+                .is_some_and(|last| last == command)
+        {
+            return;
+        }
+        self.command_history.push(command.to_string());
+        let excess = self
+            .command_history
+            .len()
+            .saturating_sub(MAX_COMMAND_HISTORY);
+        self.command_history.drain(..excess);
+        // The history is only a convenience, so it's fine if it can't be saved
+        let _ = save_command_history(&self.command_history);
+    }
+
+    /// Runs a command typed into the command prompt
+    fn run_command(&mut self, command: &str) -> Result<(), String> {
+        let mut words = command.split_whitespace();
+        match words.next() {
+            Some("set") => {
+                let (Some(name), Some(value)) = (words.next(), words.next()) else {
+                    return Err("Usage: set <setting> <value>".to_string());
+                };
+                // Values with spaces in them can be put in double quotes. Otherwise, the
+                // rest of the words make up the value (e.g. `set grid 4 16`).
+                let value = match value.strip_prefix('"') {
+                    Some(_) => {
+                        let (_, quoted) = command
+                            .split_once('"')
+                            .ok_or("Usage: set <setting> <value>")?;
+                        quoted
+                            .strip_suffix('"')
+                            .ok_or("The value is missing its closing quote")?
+                            .to_string()
+                    }
+                    None => std::iter::once(value)
+                        .chain(words)
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                };
+                self.set_option(name, &value)
+            }
+            Some("resize") => {
+                let (Some(width), Some(height)) = (words.next(), words.next()) else {
+                    return Err("Usage: resize <width> <height>".to_string());
+                };
+                let width: u16 = width
+                    .parse()
+                    .map_err(|_| format!("Invalid width: {}", width))?;
+                let height: u16 = height
+                    .parse()
+                    .map_err(|_| format!("Invalid height: {}", height))?;
+                self.canvas
+                    .resize(width, height, self.settings.resize_mode)?;
+                self.canvas_follows_terminal = false;
+                self.on_canvas_resized();
+                self.status_message = Some(format!(
+                    "Resized the canvas to {}x{}",
+                    self.canvas.width(),
+                    self.canvas.height()
+                ));
+                Ok(())
+            }
+            Some("trim") => {
+                let Some(bounds) = self.canvas.content_bounds() else {
+                    return Err("There's nothing on the canvas to trim around".to_string());
+                };
+                if bounds == self.canvas.full_area() {
+                    return Err("There aren't any empty rows or columns to trim".to_string());
+                }
+                self.crop_canvas(bounds);
+                self.status_message = Some(format!(
+                    "Trimmed the canvas to {}x{}",
+                    self.canvas.width(),
+                    self.canvas.height()
+                ));
+                Ok(())
+            }
+            Some("crop") => {
+                let Some(selection) = self.selection else {
+                    return Err("Select the area to crop to first".to_string());
+                };
+                self.crop_canvas(selection);
+                self.status_message = Some(format!(
+                    "Cropped the canvas to {}x{}",
+                    self.canvas.width(),
+                    self.canvas.height()
+                ));
+                Ok(())
+            }
+            Some("goto") => {
+                let (Some(x), Some(y)) = (words.next(), words.next()) else {
+                    return Err("Usage: goto <x> <y>".to_string());
+                };
+                let x: u16 = x.parse().map_err(|_| format!("Invalid x: {}", x))?;
+                let y: u16 = y.parse().map_err(|_| format!("Invalid y: {}", y))?;
+                (self.cursor.row, self.cursor.col) = self.coordinates_to_position((x, y))?;
+                self.scroll_to_cursor();
+                Ok(())
+            }
+            Some(command @ ("paint" | "erase")) => {
+                let color = match (command, words.next()) {
+                    ("erase", _) => Color::Reset,
+                    ("paint", Some(name)) => parse_color(name)
+                        .ok_or_else(|| format!("Expected a colour, got \"{}\"", name))?,
+                    _ => self.cursor.color,
+                };
+                // Paint like pressing Space would, but as its own change for undoing
+                let brush_color = std::mem::replace(&mut self.cursor.color, color);
+                self.canvas.end_stroke();
+                let result = self.paint_at_cursor();
+                self.canvas.end_stroke();
+                self.cursor.color = brush_color;
+                result.map_err(|error| error.to_string())
+            }
+            Some("import-ascii") => {
+                let Some(art_path) = words.next() else {
+                    return Err("Usage: import-ascii <art.txt> [map.toml]".to_string());
+                };
+                let art = std::fs::read_to_string(art_path)
+                    .map_err(|error| format!("Couldn't read {}: {}", art_path, error))?;
+                let colors = match words.next() {
+                    Some(map_path) => std::fs::read_to_string(map_path)
+                        .map_err(|error| format!("Couldn't read {}: {}", map_path, error))
+                        .and_then(|map| parse_color_map(&map))?,
+                    None => HashMap::new(),
+                };
+                self.canvas.import_text(
+                    &art,
+                    &colors,
+                    self.settings.ascii_overflow,
+                    self.settings.glyph_background,
+                );
+                self.status_message = Some(format!("Imported {}", art_path));
+                Ok(())
+            }
+            Some("save") => {
+                let Some(path) = words.next().or(self.session_path.as_deref()) else {
+                    return Err("Usage: save <file.json>".to_string());
+                };
+                let path = path.to_string();
+                self.save_session(&path)?;
+                self.status_message = Some(format!("Saved to {}", path));
+                Ok(())
+            }
+            Some(command @ ("open" | "open!")) => {
+                let Some(path) = words.next() else {
+                    return Err("Usage: open <file.json>".to_string());
+                };
+                if command == "open"
+                    && self.settings.confirm_quit
+                    && self.canvas.has_unsaved_changes()
+                {
+                    return Err("You have unsaved changes! Use :open! to open anyway".to_string());
+                }
+                self.open_session(path)?;
+                self.status_message = Some(format!("Opened {}", path));
+                Ok(())
+            }
+            Some(command @ ("quicksave" | "quickload")) => {
+                let slot = match words.next().map(str::parse) {
+                    Some(Ok(slot @ 1..=9)) => slot,
+                    _ => return Err(format!("Usage: {} <1-9>", command)),
+                };
+                match command {
+                    "quicksave" => self.quick_save(slot),
+                    _ => self.quick_load(slot),
+                }
+            }
+            Some(command @ ("recent" | "recent!")) => {
+                if command == "recent"
+                    && self.settings.confirm_quit
+                    && self.canvas.has_unsaved_changes()
+                {
+                    return Err(
+                        "You have unsaved changes! Use :recent! to open a file anyway".to_string(),
+                    );
+                }
+                let recent_files = read_recent_files();
+                if recent_files.is_empty() {
+                    return Err("There aren't any recent files yet".to_string());
+                }
+                self.recent_files = Some((recent_files, 0));
+                Ok(())
+            }
+            Some("export-sizes") => {
+                let Some(prefix) = words.next() else {
+                    return Err("Usage: export-sizes <name> <scale>...".to_string());
+                };
+                let scales = words
+                    .map(|scale| match scale.parse() {
+                        Ok(scale) if scale > 0 => Ok(scale),
+                        _ => Err(format!(
+                            "Scales should be positive whole numbers, not {}",
+                            scale
+                        )),
+                    })
+                    .collect::<Result<Vec<u16>, String>>()?;
+                self.export_scaled(prefix, &scales)?;
+                self.status_message = Some(format!(
+                    "Exported canvas to {} sizes of \"{}\"",
+                    scales.len(),
+                    prefix
+                ));
+                Ok(())
+            }
+            Some("export-rs") => {
+                let (Some(path), Some(name)) = (words.next(), words.next()) else {
+                    return Err("Usage: export-rs <file.rs> <NAME>".to_string());
+                };
+                let source = rust_source::to_rust_source(&self.canvas_to_export(), name)?;
+                std::fs::write(path, source)
+                    .map_err(|error| format!("Couldn't write {}: {}", path, error))?;
+                self.canvas.mark_saved();
+                self.status_message = Some(format!("Exported canvas to {} as {}", path, name));
+                Ok(())
+            }
+            Some("export-palette") => {
+                let Some(path) = words.next() else {
+                    return Err("Usage: export-palette <file.txt> [frequency]".to_string());
+                };
+                let mut counts = self.canvas.color_counts();
+                match words.next() {
+                    Some("frequency") => counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count)),
+                    Some(order) => {
+                        return Err(format!("Expected frequency or nothing, got \"{}\"", order));
+                    }
+                    None => {}
+                }
+                let mut text = String::new();
+                for (color, _) in &counts {
+                    let [r, g, b] = color_to_rgb(*color);
+                    text += &format!("#{:02x}{:02x}{:02x}\n", r, g, b);
+                }
+                std::fs::write(path, text)
+                    .map_err(|error| format!("Couldn't write {}: {}", path, error))?;
+                self.status_message =
+                    Some(format!("Exported {} colours to {}", counts.len(), path));
+                Ok(())
+            }
+            Some("export-html") => {
+                let Some(path) = words.next() else {
+                    return Err("Usage: export-html <file.html>".to_string());
+                };
+                std::fs::write(path, html::to_html(&self.canvas_to_export()))
+                    .map_err(|error| format!("Couldn't write {}: {}", path, error))?;
+                self.canvas.mark_saved();
+                self.status_message = Some(format!("Exported canvas to {}", path));
+                Ok(())
+            }
+            Some("copy") => {
+                let area = self.operation_area();
+                clipboard::copy(&ansi::to_ansi(&self.canvas, area))?;
+                self.status_message = Some("Copied to the system clipboard".to_string());
+                Ok(())
+            }
+            Some("paste") => {
+                let clip = ansi::from_ansi(&clipboard::paste()?);
+                if clip.width() == 0 || clip.height() == 0 {
+                    return Err("There's nothing on the clipboard to paste".to_string());
+                }
+                self.canvas.paste(
+                    &clip,
+                    (self.cursor.row, self.cursor.col),
+                    self.settings.transparent_paste,
+                    self.settings.paste_edges,
+                );
+                Ok(())
+            }
+            Some("pattern-fill") => {
+                let Some(clip) = &self.clipboard else {
+                    return Err("Nothing has been copied yet. Press C to copy".to_string());
+                };
+                self.canvas.pattern_fill(
+                    clip,
+                    self.operation_area(),
+                    self.settings.transparent_paste,
+                );
+                Ok(())
+            }
+            Some("palette") => {
+                let Some(name) = words.next() else {
+                    return Err(format!(
+                        "Usage: palette <name> (using {} now)",
+                        self.palette.name
+                    ));
+                };
+                self.set_palette(find_palette(name)?);
+                self.status_message = Some(format!("Switched to the {} palette", name));
+                Ok(())
+            }
+            Some("diff") => {
+                let Some(path) = words.next() else {
+                    if self.diff.take().is_some() {
+                        self.status_message = Some("Stopped comparing".to_string());
+                        return Ok(());
+                    }
+                    return Err("Usage: diff <session.json> (or just diff to stop)".to_string());
+                };
+                let (other, _, _) = Self::read_session_file(Path::new(path))?;
+                let differences = self.canvas.diff(&other);
+                self.status_message = Some(match differences.len() {
+                    0 => format!("The canvas is the same as {}", path),
+                    1 => format!("1 cell is different from {}", path),
+                    count => format!("{} cells are different from {}", count, path),
+                });
+                self.diff = Some(Diff {
+                    path: path.to_string(),
+                    other,
+                    version: self.canvas.version(),
+                    differences,
+                });
+                Ok(())
+            }
+            Some("check-tile") => {
+                self.status_message = Some(match self.canvas.edge_mismatches() {
+                    (0, 0) => {
+                        "The opposite edges match, so the canvas tiles seamlessly".to_string()
+                    }
+                    (sides, ends) => format!(
+                        "The edges don't match: {} cells on the left and right, {} on the top and bottom",
+                        sides, ends
+                    ),
+                });
+                Ok(())
+            }
+            Some("quantize") => {
+                let count = match words.next().map(str::parse) {
+                    Some(Ok(count @ 1..=MAX_QUANTIZE_COLORS)) => count,
+                    _ => {
+                        return Err(format!(
+                            "Usage: quantize <number of colours, from 1 to {}>",
+                            MAX_QUANTIZE_COLORS
+                        ));
+                    }
+                };
+                let palette = self.canvas.quantize(self.operation_area(), count);
+                if palette.is_empty() {
+                    return Err("There aren't any painted cells to quantize".to_string());
+                }
+                self.status_message = Some(format!(
+                    "Reduced the picture to {} colours. Press any key to hide them",
+                    palette.len()
+                ));
+                self.quantized_palette = Some(palette);
+                Ok(())
+            }
+            Some(command @ ("brightness" | "warmth")) => {
+                let amount = match words.next().map(str::parse::<i16>) {
+                    None => COLOR_ADJUSTMENT_STEP,
+                    Some(Ok(amount @ -255..=255)) => amount,
+                    Some(_) => {
+                        return Err(format!("Usage: {} [amount, from -255 to 255]", command));
+                    }
+                };
+                let (brightness, warmth) = match command {
+                    "brightness" => (amount, 0),
+                    _ => (0, amount),
+                };
+                self.canvas
+                    .adjust_colors(self.operation_area(), brightness, warmth);
+                Ok(())
+            }
+            Some("fill-background") => {
+                let color = match words.next() {
+                    Some(name) => parse_color(name)
+                        .ok_or_else(|| format!("Expected a colour, got \"{}\"", name))?,
+                    None => self.cursor.color,
+                };
+                let filled = self.canvas.fill_empty(self.operation_area(), color);
+                if filled == 0 {
+                    return Err("There aren't any empty cells to fill".to_string());
+                }
+                self.status_message = Some(format!("Filled {} empty cells", filled));
+                Ok(())
+            }
+            Some("inpaint") => {
+                let filled = self.canvas.inpaint(self.operation_area());
+                self.status_message = Some(format!("Filled {} gaps", filled));
+                Ok(())
+            }
+            Some("q" | "quit") => {
+                self.request_quit();
+                Ok(())
+            }
+            Some("q!" | "quit!") => {
+                self.running.store(false, Ordering::SeqCst);
+                Ok(())
+            }
+            Some(name) => Err(format!("Unknown command: {}", name)),
+            None => Ok(()),
+        }
+    }
+
+    fn set_option(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "confirm" => self.settings.confirm_quit = parse_toggle(value)?,
+            "wrap" => {
+                let wrap = parse_toggle(value)?;
+                self.cursor.wrap_h = wrap;
+                self.cursor.wrap_v = wrap;
+            }
+            "wrap-h" => self.cursor.wrap_h = parse_toggle(value)?,
+            "wrap-v" => self.cursor.wrap_v = parse_toggle(value)?,
+            "idle-timeout" => {
+                self.settings.idle_timeout = match (value, value.parse()) {
+                    ("off", _) => None,
+                    (_, Ok(seconds)) if seconds > 0 => Some(seconds),
+                    _ => {
+                        return Err(format!(
+                            "Expected off or a positive whole number, got \"{}\"",
+                            value
+                        ));
+                    }
+                }
+            }
+            "max-acceleration" => {
+                self.settings.max_acceleration = match value.parse() {
+                    Ok(value) if value > 0 => value,
+                    _ => {
+                        return Err(format!(
+                            "Expected a positive whole number, got \"{}\"",
+                            value
+                        ));
+                    }
+                }
+            }
+            "acceleration-base" | "acceleration-ramp" => {
+                let number = match value.parse() {
+                    Ok(number) if number > 0 => number,
+                    _ => {
+                        return Err(format!(
+                            "Expected a positive whole number, got \"{}\"",
+                            value
+                        ));
+                    }
+                };
+                match name {
+                    "acceleration-base" => self.settings.acceleration_base = number,
+                    _ => self.settings.acceleration_ramp = number,
+                }
+            }
+            "acceleration-curve" => {
+                self.settings.acceleration_curve = match value {
+                    "linear" => AccelerationCurve::Linear,
+                    "doubling" => AccelerationCurve::Doubling,
+                    _ => {
+                        return Err(format!("Expected linear or doubling, got \"{}\"", value));
+                    }
+                }
+            }
+            "max-fps" => {
+                self.settings.max_fps = match value.parse() {
+                    Ok(fps) if (1..=MAX_MAX_FPS).contains(&fps) => fps,
+                    _ => {
+                        return Err(format!(
+                            "Expected a whole number from 1 to {}, got \"{}\"",
+                            MAX_MAX_FPS, value
+                        ));
+                    }
+                }
+            }
+            "scroll-margin" => {
+                self.settings.scroll_margin = value
+                    .parse()
+                    .map_err(|_| format!("Expected a whole number, got \"{}\"", value))?;
+                self.scroll_to_cursor();
+            }
+            "brush-size" => {
+                self.settings.brush_size = match value.parse() {
+                    Ok(size) if (1..=MAX_BRUSH_SIZE).contains(&size) => size,
+                    _ => {
+                        return Err(format!(
+                            "Expected a whole number from 1 to {}, got \"{}\"",
+                            MAX_BRUSH_SIZE, value
+                        ));
+                    }
+                }
+            }
+            "pressure" => self.settings.pressure = parse_toggle(value)?,
+            "stamp-spacing" => {
+                self.settings.stamp_spacing = match value.parse() {
+                    Ok(spacing) if (1..=MAX_STAMP_SPACING).contains(&spacing) => spacing,
+                    _ => {
+                        return Err(format!(
+                            "Expected a whole number from 1 to {}, got \"{}\"",
+                            MAX_STAMP_SPACING, value
+                        ));
+                    }
+                }
+            }
+            "rainbow" => {
+                self.settings.rainbow = match (value, value.parse()) {
+                    ("off", _) => None,
+                    ("on", _) => Some(DEFAULT_RAINBOW_STEP),
+                    (_, Ok(step)) if (1..=180).contains(&step) => Some(step),
+                    _ => {
+                        return Err(format!(
+                            "Expected on, off, or a whole number from 1 to 180, got \"{}\"",
+                            value
+                        ));
+                    }
+                };
+                // Each rainbow stroke starts at red
+                self.rainbow_hue = 0;
+            }
+            "export-margin" => {
+                self.settings.export_margin = match (value, value.parse()) {
+                    ("off", _) => None,
+                    (_, Ok(margin)) if margin <= MAX_EXPORT_MARGIN => Some(margin),
+                    _ => {
+                        return Err(format!(
+                            "Expected off or a whole number up to {}, got \"{}\"",
+                            MAX_EXPORT_MARGIN, value
+                        ));
+                    }
+                }
+            }
+            "undo-memory" => {
+                self.settings.undo_memory_mb = match value.parse() {
+                    Ok(value) if value > 0 => value,
+                    _ => {
+                        return Err(format!(
+                            "Expected a positive whole number, got \"{}\"",
+                            value
+                        ));
+                    }
+                };
+                self.canvas.set_undo_memory_limit(self.undo_memory_limit());
+            }
+            "remember-view" => self.settings.remember_view = parse_toggle(value)?,
+            "polygon-fill" => self.settings.fill_polygons = parse_toggle(value)?,
+            "paste-transparent" => self.settings.transparent_paste = parse_toggle(value)?,
+            "line-smooth" => self.settings.smooth_lines = parse_toggle(value)?,
+            "coverage" => self.settings.show_coverage = parse_toggle(value)?,
+            "minimap" => self.settings.minimap = parse_toggle(value)?,
+            "crosshair" => self.settings.crosshair = parse_toggle(value)?,
+            "shade-ramp" => {
+                self.settings.shade_ramp = match value {
+                    "default" => DEFAULT_SHADE_RAMP.to_vec(),
+                    _ => parse_shade_ramp(value)?,
+                }
+            }
+            "wrap-preview" => self.settings.wrap_preview = parse_toggle(value)?,
+            "square-pixels" => {
+                self.settings.square_pixels = parse_toggle(value)?;
+                // The canvas is left the same size, so that none of the picture gets cut off
+                self.scroll_to_cursor();
+            }
+            "palette-strip" | "tool-sidebar" => {
+                let enabled = parse_toggle(value)?;
+                match name {
+                    "palette-strip" => self.settings.palette_strip = enabled,
+                    _ => self.settings.tool_sidebar = enabled,
+                }
+                // Less (or more) of the canvas fits on the screen, but it stays the same size
+                self.scroll_to_cursor();
+            }
+            "min-terminal-size" => {
+                let size = value
+                    .split_once('x')
+                    .and_then(|(cols, rows)| Some((cols.parse().ok()?, rows.parse().ok()?)));
+                self.settings.min_terminal_size = match size {
+                    Some((cols, rows)) if cols > 0 && rows > BOTTOM_BAR_HEIGHT => (cols, rows),
+                    _ => {
+                        return Err(format!(
+                            "Expected a size like 20x5, at least {} rows tall, got \"{}\"",
+                            BOTTOM_BAR_HEIGHT + 1,
+                            value
+                        ));
+                    }
+                }
+            }
+            "origin" => {
+                self.settings.origin = match value {
+                    "top-left" => Origin::TopLeft,
+                    "bottom-left" => Origin::BottomLeft,
+                    _ => {
+                        return Err(format!(
+                            "Expected top-left or bottom-left, got \"{}\"",
+                            value
+                        ));
+                    }
+                }
+            }
+            "grid" => {
+                let spacings: Vec<Option<u16>> = value
+                    .split_whitespace()
+                    .map(|spacing| spacing.parse().ok().filter(|spacing| *spacing > 0))
+                    .collect();
+                self.settings.grid = match spacings[..] {
+                    _ if value == "off" => None,
+                    [Some(minor)] => Some((minor, None)),
+                    [Some(minor), Some(major)] => Some((minor, Some(major))),
+                    _ => {
+                        return Err(format!(
+                            "Expected off, or one or two positive whole numbers, got \"{}\"",
+                            value
+                        ));
+                    }
+                }
+            }
+            "checkerboard" => {
+                self.settings.checkerboard = match (value, value.parse()) {
+                    ("off", _) => None,
+                    ("on", _) => Some(DEFAULT_CHECKERBOARD_SIZE),
+                    (_, Ok(size)) if (1..=MAX_CHECKERBOARD_SIZE).contains(&size) => Some(size),
+                    _ => {
+                        return Err(format!(
+                            "Expected on, off, or a whole number from 1 to {}, got \"{}\"",
+                            MAX_CHECKERBOARD_SIZE, value
+                        ));
+                    }
+                }
+            }
+            "cursor-color" => {
+                self.settings.cursor_color =
+                    match value {
+                        "auto" => None,
+                        _ => Some(parse_color(value).ok_or_else(|| {
+                            format!("Expected auto or a colour, got \"{}\"", value)
+                        })?),
+                    }
+            }
+            "glyph-background" => {
+                self.settings.glyph_background =
+                    match value {
+                        "off" => None,
+                        _ => Some(parse_color(value).ok_or_else(|| {
+                            format!("Expected off or a colour, got \"{}\"", value)
+                        })?),
+                    }
+            }
+            "block-cells" => self.settings.block_cells = parse_toggle(value)?,
+            "feedback" => {
+                self.settings.feedback = match value {
+                    "flash" => Feedback::Flash,
+                    "bell" => Feedback::Bell,
+                    "off" => Feedback::Off,
+                    _ => return Err(format!("Expected flash, bell or off, got \"{}\"", value)),
+                }
+            }
+            "bounds" => {
+                self.settings.bounds = match value {
+                    "strict" => Bounds::Strict,
+                    "lenient" => Bounds::Lenient,
+                    _ => return Err(format!("Expected strict or lenient, got \"{}\"", value)),
+                }
+            }
+            "ctrl-c" => {
+                self.settings.ctrl_c = match value {
+                    "quit" => CtrlC::Quit,
+                    "cancel" => CtrlC::Cancel,
+                    _ => return Err(format!("Expected quit or cancel, got \"{}\"", value)),
+                }
+            }
+            "quit-key" => {
+                let mut chars = value.chars();
+                self.settings.quit_key = match (chars.next(), chars.next()) {
+                    _ if value == "off" => None,
+                    (Some(key), None) => {
+                        // The quit key would take over whatever the key did before
+                        let previous = self.settings.quit_key.take();
+                        let press =
+                            KeyEvent::new(event::KeyCode::Char(key), event::KeyModifiers::NONE);
+                        if let Some(action) = self.key_action(&press) {
+                            self.settings.quit_key = previous;
+                            return Err(format!(
+                                "{} is already used for {}, so it can't be the quit key",
+                                key,
+                                action.name()
+                            ));
+                        }
+                        Some(key)
+                    }
+                    _ => return Err(format!("Expected a single key or off, got \"{}\"", value)),
+                }
+            }
+            "paste-edges" => {
+                self.settings.paste_edges = match value {
+                    "clip" => PasteEdges::Clip,
+                    "wrap" => PasteEdges::Wrap,
+                    _ => return Err(format!("Expected clip or wrap, got \"{}\"", value)),
+                }
+            }
+            "ascii-overflow" => {
+                self.settings.ascii_overflow = match value {
+                    "clip" => TextOverflow::Clip,
+                    "wrap" => TextOverflow::Wrap,
+                    _ => return Err(format!("Expected clip or wrap, got \"{}\"", value)),
+                }
+            }
+            "resize-mode" => {
+                self.settings.resize_mode = match value {
+                    "clip" => ResizeMode::Clip,
+                    "pad" => ResizeMode::Pad,
+                    "rescale" => ResizeMode::Rescale,
+                    _ => {
+                        return Err(format!("Expected clip, pad or rescale, got \"{}\"", value));
+                    }
+                }
+            }
+            _ => return Err(format!("Unknown setting: {}", name)),
+        }
+        self.status_message = Some(format!("Set {} to {}", name, value));
+        Ok(())
+    }
+
+    /// The area that canvas operations should affect: the selection if there is one,
+    /// otherwise the whole canvas
+    fn operation_area(&self) -> Selection {
+        self.selection.unwrap_or(self.canvas.full_area())
+    }
+
+    /// Writes the canvas and colour slots to a JSON file
+    fn write_session_file(&self, path: &Path) -> Result<(), String> {
+        let color_slots = self
+            .color_slots
+            .iter()
+            .map(|slot| slot.map(color_name).into())
+            .collect();
+        let session = Json::Object(vec![
+            ("version".to_string(), Json::Number(SESSION_VERSION as f64)),
+            ("canvas".to_string(), self.canvas.to_json()),
+            ("color_slots".to_string(), Json::Array(color_slots)),
+            ("settings".to_string(), self.working_setup_to_json()),
+        ]);
+        std::fs::write(path, session.to_string())
+            .map_err(|error| format!("Couldn't save to {}: {}", path.display(), error))
+    }
+
+    /// The paintbrush's colour, the tool, and the brush settings, for saving in session files
+    /// so that opening one puts everything back the way it was
+    fn working_setup_to_json(&self) -> Json {
+        let rainbow = match self.settings.rainbow {
+            Some(step) => step.into(),
+            None => Json::Null,
+        };
+        Json::Object(vec![
+            ("color".to_string(), color_name(self.cursor.color).into()),
+            ("tool".to_string(), self.tool.name().to_lowercase().into()),
+            ("brush_size".to_string(), self.settings.brush_size.into()),
+            ("pressure".to_string(), self.settings.pressure.into()),
+            (
+                "stamp_spacing".to_string(),
+                self.settings.stamp_spacing.into(),
+            ),
+            ("rainbow".to_string(), rainbow),
+        ])
+    }
+
+    /// Goes back to the colour, tool and brush settings saved in a session file. Anything
+    /// that's missing or invalid is left as it is.
+    fn apply_working_setup(&mut self, setup: &Json) {
+        if let Some(color) = setup
+            .get("color")
+            .and_then(Json::as_str)
+            .and_then(parse_color)
+        {
+            self.cursor.color = color;
+        }
+        if let Some(tool) = setup
+            .get("tool")
+            .and_then(Json::as_str)
+            .and_then(Tool::from_name)
+        {
+            self.tool = tool;
+        }
+        // The settings are checked in the same way as if they'd been typed in with `:set`
+        let mut values = Vec::new();
+        for (name, key) in [
+            ("brush-size", "brush_size"),
+            ("stamp-spacing", "stamp_spacing"),
+        ] {
+            if let Some(number) = setup.get(key).and_then(Json::as_u64) {
+                values.push((name, number.to_string()));
+            }
+        }
+        if let Some(pressure) = setup.get("pressure").and_then(Json::as_bool) {
+            values.push(("pressure", if pressure { "on" } else { "off" }.to_string()));
+        }
+        match setup.get("rainbow") {
+            Some(Json::Null) => values.push(("rainbow", "off".to_string())),
+            Some(step) => values.extend(step.as_u64().map(|step| ("rainbow", step.to_string()))),
+            None => {}
+        }
+        for (name, value) in values {
+            let _ = self.set_option(name, &value);
+        }
+    }
+
+    /// Reads the canvas, colour slots and working setup (see `working_setup_to_json`) from
+    /// a JSON file. The working setup is `Json::Null` for files saved before it was added.
+    fn read_session_file(
+        path: &Path,
+    ) -> Result<(Canvas, [Option<Color>; COLOR_SLOT_COUNT], Json), String> {
+        let path_name = path.display();
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| format!("Couldn't read {}: {}", path_name, error))?;
+        let session = Json::parse(&text).map_err(|error| format!("Invalid JSON: {}", error))?;
+        match session.get("version").and_then(Json::as_u64) {
+            Some(version) if version <= SESSION_VERSION => {}
+            Some(version) => {
+                return Err(format!(
+                    "{} was saved by a newer version of Paint 2D (format {})",
+                    path_name, version
+                ));
+            }
+            None => return Err(format!("{} isn't a Paint 2D session", path_name)),
+        }
+        let canvas = Canvas::from_json(session.get("canvas").unwrap_or(&Json::Null))?;
+        let mut color_slots = [None; COLOR_SLOT_COUNT];
+        if let Some(saved_slots) = session.get("color_slots").and_then(Json::as_array) {
+            for (slot, saved) in color_slots.iter_mut().zip(saved_slots) {
+                *slot = saved.as_str().and_then(parse_color);
+            }
+        }
+        let setup = session.get("settings").cloned().unwrap_or(Json::Null);
+        Ok((canvas, color_slots, setup))
+    }
+
+    /// Saves the canvas and colour slots to a JSON file
+    fn save_session(&mut self, path: &str) -> Result<(), String> {
+        self.write_session_file(Path::new(path))?;
+        self.canvas.mark_saved();
+        self.session_path = Some(path.to_string());
+        // The list is only a convenience, so it's fine if it can't be saved
+        let _ = add_recent_file(path);
+        Ok(())
+    }
+
+    /// The `undo-memory` setting in bytes. Huge settings can't be counted in bytes, so
+    /// they're as good as no limit at all.
+    fn undo_memory_limit(&self) -> usize {
+        self.settings.undo_memory_mb.saturating_mul(1024 * 1024)
+    }
+
+    /// Replaces the canvas and colour slots with the ones saved in a JSON file
+    fn open_session(&mut self, path: &str) -> Result<(), String> {
+        let (canvas, color_slots, setup) = Self::read_session_file(Path::new(path))?;
+
+        // Remember where we were in the old session before leaving it
+        let _ = self.save_view_state();
+        self.canvas = canvas;
+        self.canvas.set_undo_memory_limit(self.undo_memory_limit());
+        self.color_slots = color_slots;
+        self.canvas_follows_terminal = false;
+        self.tool = Tool::Brush;
+        self.apply_working_setup(&setup);
+        self.session_path = Some(path.to_string());
+        let _ = add_recent_file(path);
+        // Start at the top-left of the picture, since it might be too big to see all at once
+        if let Some(bounds) = self.canvas.content_bounds() {
+            (self.cursor.row, self.cursor.col) = (bounds.top, bounds.left);
+            self.scroll = (bounds.top, bounds.left);
+        }
+        self.restore_view_state();
+        self.on_canvas_resized();
+        Ok(())
+    }
+
+    /// Saves a snapshot of the canvas and colour slots to a numbered quick-save slot,
+    /// without changing which file the session is saved to
+    fn quick_save(&mut self, slot: u8) -> Result<(), String> {
+        let path = quick_save_path(slot)
+            .ok_or_else(|| "Couldn't find a folder to put quick saves in".to_string())?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|error| format!("Couldn't create {}: {}", dir.display(), error))?;
+        }
+        self.write_session_file(&path)?;
+        self.status_message = Some(format!("Quick saved to slot {}", slot));
+        Ok(())
+    }
+
+    /// Goes back to the canvas and colour slots from a numbered quick-save slot.
+    /// This can be undone like any other change to the canvas.
+    fn quick_load(&mut self, slot: u8) -> Result<(), String> {
+        let path = quick_save_path(slot)
+            .filter(|path| path.is_file())
+            .ok_or_else(|| format!("Nothing has been quick saved to slot {}", slot))?;
+        let (canvas, color_slots, _) = Self::read_session_file(&path)?;
+        self.canvas.replace_contents(&canvas);
+        self.color_slots = color_slots;
+        self.canvas_follows_terminal = false;
+        self.on_canvas_resized();
+        self.status_message = Some(format!("Loaded quick save {}", slot));
+        Ok(())
+    }
+
+    /// Reads the view states that have been remembered for all sessions, by the
+    /// absolute path to each session file
+    fn read_view_states() -> Vec<(String, Json)> {
+        let states = view_state_path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|text| Json::parse(&text).ok());
+        match states.and_then(|states| states.get("sessions").cloned()) {
+            Some(Json::Object(sessions)) => sessions,
+            _ => Vec::new(),
+        }
+    }
+
+    /// The key that the current session's view state is remembered under
+    fn view_state_key(&self) -> Option<String> {
+        let path = std::fs::canonicalize(self.session_path.as_ref()?).ok()?;
+        Some(path.to_string_lossy().into_owned())
+    }
+
+    /// Remembers the cursor position, scroll position, colour and tool for the current
+    /// session file, if `remember-view` is on
+    fn save_view_state(&self) -> std::io::Result<()> {
+        let (true, Some(key), Some(state_path)) = (
+            self.settings.remember_view,
+            self.view_state_key(),
+            view_state_path(),
+        ) else {
+            return Ok(());
+        };
+        let tool = self.tool.name().to_lowercase();
+        let pair = |(a, b): (u16, u16)| Json::Array(vec![a.into(), b.into()]);
+        let state = Json::Object(vec![
+            (
+                "cursor".to_string(),
+                pair((self.cursor.row, self.cursor.col)),
+            ),
+            ("scroll".to_string(), pair(self.scroll)),
+            ("color".to_string(), color_name(self.cursor.color).into()),
+            ("tool".to_string(), tool.into()),
+        ]);
+        let mut sessions = Self::read_view_states();
+        sessions.retain(|(path, _)| *path != key);
+        sessions.push((key, state));
+        let states = Json::Object(vec![
+            ("version".to_string(), Json::Number(SESSION_VERSION as f64)),
+            ("sessions".to_string(), Json::Object(sessions)),
+        ]);
+        if let Some(dir) = state_path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(state_path, states.to_string())
+    }
+
+    /// Goes back to the view state that was remembered for the current session file,
+    /// if `remember-view` is on and there is one
+    fn restore_view_state(&mut self) {
+        if !self.settings.remember_view {
+            return;
+        }
+        let Some(key) = self.view_state_key() else {
+            return;
+        };
+        let sessions = Self::read_view_states();
+        let Some((_, state)) = sessions.iter().find(|(path, _)| *path == key) else {
+            return;
+        };
+        let pair = |key: &str| {
+            let items = state.get(key)?.as_array()?;
+            let [a, b] = items else { return None };
+            Some((
+                u16::try_from(a.as_u64()?).ok()?,
+                u16::try_from(b.as_u64()?).ok()?,
+            ))
+        };
+        // The positions get clamped to the canvas once it's been resized
+        if let Some((row, col)) = pair("cursor") {
+            self.cursor.row = row.min(self.canvas.height() - 1);
+            self.cursor.col = col.min(self.canvas.width() - 1);
+        }
+        if let Some(scroll) = pair("scroll") {
+            self.scroll = scroll;
+        }
+        if let Some(color) = state
+            .get("color")
+            .and_then(Json::as_str)
+            .and_then(parse_color)
+        {
+            self.cursor.color = color;
+        }
+        self.tool = state
+            .get("tool")
+            .and_then(Json::as_str)
+            .and_then(Tool::from_name)
+            .unwrap_or(Tool::Brush);
+    }
+
+    /// The canvas as it should be exported, which might be cropped
+    fn canvas_to_export(&self) -> Cow<'_, Canvas> {
+        match self.settings.export_margin {
+            Some(margin) => Cow::Owned(self.canvas.cropped(margin)),
+            None => Cow::Borrowed(&self.canvas),
+        }
+    }
+
+    /// Exports the canvas to a PNG file at several scales, where each cell becomes a
+    /// `scale` by `scale` block of pixels. Nothing is written if any of the scales are invalid.
+    fn export_scaled(&mut self, prefix: &str, scales: &[u16]) -> Result<(), String> {
+        if scales.is_empty() {
+            return Err("Give at least one scale to export at".to_string());
+        }
+        let canvas = self.canvas_to_export();
+        for scale in scales {
+            let (width, height) = canvas.image_size(*scale, *scale);
+            if u64::from(width) * u64::from(height) > MAX_EXPORT_PIXELS {
+                return Err(format!(
+                    "Exporting at {}x would make a {}x{} image, which is too big",
+                    scale, width, height
+                ));
+            }
+        }
+        for scale in scales {
+            let filename = format!("{}@{}x.png", prefix, scale);
+            self.save_image(&canvas, (*scale, *scale), &filename)
+                .map_err(|error| format!("Error exporting canvas to {}: {}", filename, error))?;
+        }
+        self.canvas.mark_saved();
+        Ok(())
+    }
+
+    /// Saves a canvas to an image file, with each cell becoming a block of pixels.
+    /// The image is greyscale in monochrome mode.
+    fn save_image(
+        &self,
+        canvas: &Canvas,
+        (block_width, block_height): (u16, u16),
+        filename: &str,
+    ) -> image::ImageResult<()> {
+        let image = canvas.to_image(block_width, block_height);
+        if self.mono {
+            image::imageops::grayscale(&image).save(filename)
+        } else {
+            image.save(filename)
+        }
+    }
+
+    fn export_canvas_to_image(&mut self) {
+        // Block widths and heights based on Kitty with default settings
+        const BLOCK_WIDTH: u16 = 9;
+        const BLOCK_HEIGHT: u16 = 20;
+        let time = Local::now().format("%Y-%m-%d %H_%M_%S");
+        let filename = format!("Paint 2D at {}.png", time);
+        let result = self.save_image(
+            &self.canvas_to_export(),
+            (BLOCK_WIDTH, BLOCK_HEIGHT),
+            &filename,
+        );
+        match result {
+            Ok(_) => {
+                self.canvas.mark_saved();
+                self.status_message = Some(format!("Exported canvas to \"{}\"", filename));
+            }
+            Err(error) => {
+                log::warn!("Couldn't export to {}: {}", filename, error);
+                self.status_message = Some(format!("Error exporting canvas to {}", filename));
+            }
+        }
+    }
+
+    fn run(&mut self) -> std::io::Result<()> {
+        self.render_screen()?;
+        while self.running.load(Ordering::SeqCst) {
+            if event::poll(self.poll_timeout())? {
+                // Handle everything that's waiting before rendering, so that the screen
+                // doesn't fall behind when lots of key repeats come in at once. If events
+                // keep on coming, the screen still gets rendered once per frame.
+                let drain_start = Instant::now();
+                loop {
+                    let event = event::read()?;
+                    self.handle_event(event)?;
+                    if drain_start.elapsed() >= self.frame_budget() || !event::poll(Duration::ZERO)?
+                    {
+                        break;
+                    }
+                }
+            }
+            #[cfg(feature = "ipc")]
+            self.handle_socket_requests()?;
+            // Adjust to the terminal's new size once it's stopped changing
+            if self
+                .pending_resize
+                .is_some_and(|(_, resized_at)| resized_at.elapsed() >= RESIZE_DEBOUNCE)
+            {
+                self.apply_pending_resize()?;
+            }
+            // Stop flashing the bottom bar once the flash is over
+            if self
+                .flash_until
+                .is_some_and(|flash_until| Instant::now() >= flash_until)
+            {
+                self.flash_until = None;
+                self.redraw_screen()?;
+            }
+            // Render any redraws that have been requested, if it's been long enough since
+            // the last render
+            if self.needs_redraw {
+                if self.last_render.elapsed() >= self.frame_budget() {
+                    self.render_screen()?;
+                }
+            } else if self.cursors_moved {
+                self.redraw_cursors()?;
+            }
+            self.stdout.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Replies to all the commands that have come in through the socket
+    #[cfg(feature = "ipc")]
+    fn handle_socket_requests(&mut self) -> std::io::Result<()> {
+        while let Some(request) = self.socket.as_ref().and_then(ipc::Server::next_request) {
+            log::debug!("Socket command: {}", request.command);
+            let reply = match self.run_socket_command(&request.command)? {
+                Ok(reply) if reply.is_empty() => "OK".to_string(),
+                Ok(reply) => format!("OK {}", reply),
+                Err(error) => format!("ERROR {}", error),
+            };
+            request.reply(reply);
+        }
+        Ok(())
+    }
+
+    /// Runs a command from the socket, giving back the rest of the reply after the `OK`
+    #[cfg(feature = "ipc")]
+    fn run_socket_command(&mut self, command: &str) -> std::io::Result<Result<String, String>> {
+        // Transparent and erased cells are both sent as "none"
+        fn cell_text(color: Option<Color>) -> String {
+            match color {
+                None | Some(Color::Reset) => "none".to_string(),
+                Some(color) => {
+                    let [r, g, b] = color_to_rgb(color);
+                    format!("#{:02x}{:02x}{:02x}", r, g, b)
+                }
+            }
+        }
+        let mut words = command.split_whitespace();
+        let Some(name) = words.next() else {
+            return Ok(Err("Expected a command".to_string()));
+        };
+        let (width, height) = (self.canvas.width(), self.canvas.height());
+        let mut position = || -> Result<(u16, u16), String> {
+            let mut number = || words.next().and_then(|word| word.parse::<u16>().ok());
+            match (number(), number()) {
+                (Some(row), Some(col)) if row < height && col < width => Ok((row, col)),
+                (Some(row), Some(col)) => Err(format!("{} {} is off the canvas", row, col)),
+                _ => Err(format!("Usage: {} <row> <col>", name)),
+            }
+        };
+        Ok(match name {
+            "GET_SIZE" => Ok(format!("{} {}", width, height)),
+            "GET_CELL" => {
+                position().map(|(row, col)| cell_text(self.canvas.get_cell(row, col).flatten()))
+            }
+            "SET_CELL" => {
+                let (row, col) = match position() {
+                    Ok(position) => position,
+                    Err(error) => return Ok(Err(error)),
+                };
+                let color = match words.next() {
+                    Some("none") => None,
+                    Some(name) => match parse_color(name) {
+                        Some(color) => Some(color),
+                        None => return Ok(Err(format!("Expected a colour, got \"{}\"", name))),
+                    },
+                    None => return Ok(Err("Usage: SET_CELL <row> <col> <colour>".to_string())),
+                };
+                // Each command can be undone on its own
+                self.canvas.end_stroke();
+                match color {
+                    Some(color) => self.canvas.paint(row, col, color),
+                    None => self.canvas.erase(row, col),
+                };
+                self.canvas.end_stroke();
+                self.redraw_screen()?;
+                Ok(String::new())
+            }
+            "DUMP" => {
+                let rows: Vec<String> = self
+                    .canvas
+                    .rows()
+                    .iter()
+                    .map(|row| {
+                        row.iter()
+                            .map(|cell| cell_text(*cell))
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    })
+                    .collect();
+                Ok(format!("{} {}\n{}", width, height, rows.join("\n")))
+            }
+            _ => Err(format!("Unknown command: {}", name)),
+        })
+    }
+
+    fn handle_event(&mut self, event: Event) -> std::io::Result<()> {
+        if let Some(debug) = &mut self.debug {
+            debug.events += 1;
+        }
+        if self.is_asleep() {
+            log::debug!(
+                "Waking up after {:.0?} without any input",
+                self.last_event.elapsed()
+            );
+        }
+        self.last_event = Instant::now();
+        // Anything other than another resize should see the terminal at its latest size
+        if !matches!(event, Event::Resize(..)) {
+            self.apply_pending_resize()?;
+        }
+        match event {
+            Event::Key(key) => {
+                log::debug!("{:?}", key);
+                self.handle_key(key)
+            }
+            Event::Resize(cols, rows) => {
+                self.pending_resize = Some(((cols, rows), Instant::now()));
+                Ok(())
+            }
+            Event::Mouse(mouse) => self.handle_mouse(mouse),
+            _ => Ok(()),
+        }
+    }
+
+    /// Adjusts to the latest size that the terminal was resized to, if it's been resized
+    fn apply_pending_resize(&mut self) -> std::io::Result<()> {
+        let Some(((cols, rows), _)) = self.pending_resize.take() else {
+            return Ok(());
+        };
+        log::info!("Terminal resized to {}x{}", cols, rows);
+        self.on_resize(cols, rows)
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> std::io::Result<()> {
+        if !self.playing_macro
+            && let Some((_, keys)) = &mut self.recording_macro
+        {
+            keys.push(key);
+        }
+
+        // We need to know if we receive key release events or not
+        // This is a hacky way of working that out
+        if key.kind != KeyEventKind::Press {
+            self.enhanced_key_events = true;
+        }
+
+        // Keep track of if the space button is being held or not
+        if key.code == event::KeyCode::Char(' ') && self.enhanced_key_events {
+            match key.kind {
+                KeyEventKind::Press => {
+                    self.space_button_held = true;
+                }
+                KeyEventKind::Release => {
+                    self.space_button_held = false;
+                    self.canvas.end_stroke();
+                }
+                KeyEventKind::Repeat => {}
+            }
+        }
+
+        // Prevents actions happening twice per key press
+        if key.kind == KeyEventKind::Release {
+            return Ok(());
+        }
+
+        if key.code == event::KeyCode::Char('c')
+            && key.modifiers.contains(event::KeyModifiers::CONTROL)
+        {
+            return self.on_ctrl_c();
+        }
+
+        // Painting carries on the same stroke (so that it can be undone in one go), and so
+        // does moving while it paints (with Space held down, or with the smudge tool), but
+        // anything else finishes it
+        let paints_while_moving = self.space_button_held || matches!(self.tool, Tool::Smudge);
+        let continues_stroke = match key.code {
+            event::KeyCode::Char(' ') => true,
+            event::KeyCode::Left
+            | event::KeyCode::Right
+            | event::KeyCode::Up
+            | event::KeyCode::Down => paints_while_moving,
+            _ => false,
+        };
+        if !continues_stroke {
+            self.canvas.end_stroke();
+        }
+
+        if self.command_line.is_some() {
+            self.on_command_line_key(key)?;
+            self.redraw_screen()?;
+            return Ok(());
+        }
+
+        // Status messages (and the quantized palette) only last until the next key press
+        let had_message = self.status_message.take().is_some();
+        let had_palette = self.quantized_palette.take().is_some();
+        if had_message || had_palette {
+            self.redraw_screen()?;
+        }
+
+        if self.confirming_quit {
+            self.confirming_quit = false;
+            if key.code == event::KeyCode::Char('y') {
+                self.running.store(false, Ordering::SeqCst);
+            }
+            return Ok(());
+        }
+
+        if let Some(action) = self.awaiting_register.take() {
+            if let event::KeyCode::Char(register) = key.code {
+                match action {
+                    MacroAction::Record => {
+                        self.recording_macro = Some((register, Vec::new()));
+                    }
+                    MacroAction::Play => self.play_macro(register)?,
+                }
+            }
+            self.redraw_screen()?;
+            return Ok(());
+        }
+
+        if self.recent_files.is_some() {
+            return self.on_recent_files_key(key);
+        }
+
+        if self.mixer.is_some() {
+            return self.on_mixer_key(key);
+        }
+
+        if self.show_help {
+            self.show_help = false;
+            return self.redraw_screen();
+        }
+
+        if let Some(grab) = self.grab {
+            return self.on_grab_key(key, grab);
+        }
+
+        let acceleration = self.movement_acceleration(key.code);
+        let is_speedy = key.modifiers.contains(event::KeyModifiers::CONTROL);
+        let is_super_speedy = is_speedy && key.modifiers.contains(event::KeyModifiers::ALT);
+        // Holding Alt (on its own) leaves the extra cursors where they are
+        let main_cursor_only = !is_speedy && key.modifiers.contains(event::KeyModifiers::ALT);
+        let horizontal_movement = if is_super_speedy {
+            20
+        } else if is_speedy {
+            8
+        } else {
+            acceleration
+        };
+        let vertical_movement = if is_super_speedy {
+            8
+        } else if is_speedy {
+            2
+        } else {
+            // Cells are about twice as tall as they are wide
+            acceleration.div_ceil(2)
+        };
+        // Moving without Shift finishes with a selection made using Shift+arrow keys. Other
+        // selections stay, so that the paintbrush can be moved around inside them.
+        let is_arrow_key = matches!(
+            key.code,
+            event::KeyCode::Left
+                | event::KeyCode::Right
+                | event::KeyCode::Up
+                | event::KeyCode::Down
+        );
+        if is_arrow_key
+            && !key.modifiers.contains(event::KeyModifiers::SHIFT)
+            && let Some((_, made)) = self.selection_anchor.take()
+            && self.selection == Some(made)
+        {
+            self.selection = None;
+            self.redraw_screen()?;
+        }
+        let Some(action) = self.key_action(&key) else {
+            return self.reject();
+        };
+        match action {
+            Action::Quit => {
+                self.request_quit();
+                self.redraw_screen()?;
+            }
+            Action::SaveOpen if key.code == event::KeyCode::Char('s') => {
+                // Save straight away if there's a file to save to, otherwise ask for one
+                match self.session_path.clone() {
+                    Some(path) => match self.save_session(&path) {
+                        Ok(()) => self.status_message = Some(format!("Saved to {}", path)),
+                        Err(error) => {
+                            self.status_message = Some(error);
+                            self.reject()?;
+                        }
+                    },
+                    None => self.command_line = Some("save ".to_string()),
+                }
+                self.redraw_screen()?;
+            }
+            Action::SaveOpen => {
+                self.command_line = Some("open ".to_string());
+                self.redraw_screen()?;
+            }
+            Action::SelectAll => {
+                self.selection = Some(self.canvas.full_area());
+                self.redraw_screen()?;
+            }
+            Action::JumpToEdge => {
+                // Jump to where the colour changes
+                let direction = match key.code {
+                    event::KeyCode::Left => (0, -1),
+                    event::KeyCode::Right => (0, 1),
+                    event::KeyCode::Up => (-1, 0),
+                    _ => (1, 0),
+                };
+                (self.cursor.row, self.cursor.col) = self
+                    .canvas
+                    .find_edge((self.cursor.row, self.cursor.col), direction);
+                self.on_cursor_move()?;
+            }
+            Action::ExtendSelection => {
+                // Select from where the paintbrush was to where it moves
+                let cursor = (self.cursor.row, self.cursor.col);
+                let anchor = match self.selection_anchor {
+                    Some((anchor, made)) if self.selection == Some(made) => anchor,
+                    _ => cursor,
+                };
+                match key.code {
+                    event::KeyCode::Left => self.cursor.left(horizontal_movement),
+                    event::KeyCode::Right => self.cursor.right(horizontal_movement),
+                    event::KeyCode::Up => self.cursor.up(vertical_movement),
+                    _ => self.cursor.down(vertical_movement),
+                }
+                let selection = Selection::from_corners(anchor, (self.cursor.row, self.cursor.col));
+                self.selection = Some(selection);
+                self.selection_anchor = Some((anchor, selection));
+                self.on_cursor_move()?;
+            }
+            Action::Move => {
+                let movement = |cursor: &mut PaintCursor| match key.code {
+                    event::KeyCode::Left => cursor.left(horizontal_movement),
+                    event::KeyCode::Right => cursor.right(horizontal_movement),
+                    event::KeyCode::Up => cursor.up(vertical_movement),
+                    _ => cursor.down(vertical_movement),
+                };
+                if main_cursor_only {
+                    movement(&mut self.cursor);
+                } else {
+                    self.move_cursors(movement);
+                }
+                self.on_cursor_move()?;
+            }
+            Action::Paint => {
+                self.use_tool()?;
+                self.redraw_screen()?;
+            }
+            Action::Line => {
+                self.tool = Tool::Line { start: None };
+                self.redraw_screen()?;
+            }
+            Action::Curve => {
+                self.tool = Tool::Curve { points: Vec::new() };
+                self.redraw_screen()?;
+            }
+            Action::Smudge => {
+                self.tool = Tool::Smudge;
+                self.redraw_screen()?;
+            }
+            Action::Connector => {
+                self.tool = Tool::Connector;
+                self.redraw_screen()?;
+            }
+            Action::Ruler => {
+                self.tool = Tool::Ruler { start: None };
+                self.redraw_screen()?;
+            }
+            Action::CycleTool if key.code == event::KeyCode::BackTab => self.cycle_tool(-1)?,
+            Action::CycleTool => self.cycle_tool(1)?,
+            Action::Polygon => {
+                self.tool = Tool::Polygon {
+                    vertices: Vec::new(),
+                };
+                self.redraw_screen()?;
+            }
+            Action::FinishPolygon => {
+                if self.finish_polygon() {
+                    self.redraw_screen()?;
+                } else {
+                    self.reject()?;
+                }
+            }
+            Action::Export => {
+                self.export_canvas_to_image();
+                self.redraw_screen()?;
+            }
+            Action::Macro if key.code == event::KeyCode::Char('Q') => {
+                if let Some((register, mut keys)) = self.recording_macro.take() {
+                    // Don't include the key press that stopped the recording
+                    keys.pop();
+                    self.macros.insert(register, keys);
+                    self.status_message = Some(format!("Recorded macro @{}", register));
+                } else {
+                    self.awaiting_register = Some(MacroAction::Record);
+                    self.status_message =
+                        Some("Press a key to choose a register to record into".to_string());
+                }
+                self.redraw_screen()?;
+            }
+            Action::Macro => {
+                self.awaiting_register = Some(MacroAction::Play);
+                self.status_message = Some("Press a key to choose a macro to play".to_string());
+                self.redraw_screen()?;
+            }
+            Action::StoreColor | Action::RecallColor => {
+                let event::KeyCode::F(number) = key.code else {
+                    unreachable!("colour slots are only on the function keys");
+                };
+                let slot = &mut self.color_slots[number as usize - 1];
+                if action == Action::StoreColor {
+                    *slot = Some(self.cursor.color);
+                    self.status_message = Some(format!("Stored the colour in F{}", number));
+                    self.redraw_screen()?;
+                } else if let Some(color) = *slot {
+                    self.choose_color(color);
+                    self.redraw_screen()?;
+                } else {
+                    self.status_message = Some(format!(
+                        "F{} is empty. Use Shift+F{} to fill it",
+                        number, number
+                    ));
+                    self.reject()?;
+                }
+            }
+            Action::ShowColorSlots => {
+                self.show_color_slots = !self.show_color_slots;
+                self.redraw_screen()?;
+            }
+            Action::Debug => {
+                self.debug = match self.debug {
+                    Some(_) => None,
+                    None => Some(DebugStats::default()),
+                };
+                self.redraw_screen()?;
+            }
+            Action::Command => {
+                self.command_line = Some(String::new());
+                self.redraw_screen()?;
+            }
+            Action::Help => {
+                self.show_help = true;
+                self.redraw_screen()?;
+            }
+            Action::SwapColor => match self.previous_color {
+                Some(color) => {
+                    self.choose_color(color);
+                    self.redraw_screen()?;
+                }
+                None => self.reject()?,
+            },
+            Action::Mix => {
+                self.mixer = Some(ColorMixer {
+                    rgb: color_to_rgb(self.cursor.color),
+                    channel: 0,
+                });
+                self.redraw_screen()?;
+            }
+            Action::Fill => {
+                let position = (self.cursor.row, self.cursor.col);
+                let area = self.operation_area();
+                if self
+                    .canvas
+                    .flood_fill(position, Some(self.cursor.color), area)
+                    == 0
+                {
+                    self.reject()?;
+                }
+                self.redraw_screen()?;
+            }
+            Action::Invert => {
+                self.canvas.invert_colors(self.operation_area());
+                self.redraw_screen()?;
+            }
+            Action::Mask if key.code == event::KeyCode::Char('m') => {
+                if let Some(area) = self.selection.take() {
+                    // Turn the selection into a stencil
+                    self.canvas.set_mask(Some(Mask {
+                        area,
+                        mode: MaskMode::Outside,
+                    }));
+                    self.redraw_screen()?;
+                } else if let Some(mask) = self.canvas.mask() {
+                    // Swap which side of the mask can be painted
+                    let mode = match mask.mode {
+                        MaskMode::Outside => MaskMode::Inside,
+                        MaskMode::Inside => MaskMode::Outside,
+                    };
+                    self.canvas.set_mask(Some(Mask { mode, ..mask }));
+                    self.redraw_screen()?;
+                } else {
+                    self.reject()?;
+                }
+            }
+            Action::CopyPaste if key.code == event::KeyCode::Char('c') => {
+                let clip = self.canvas.copy(self.operation_area());
+                self.status_message =
+                    Some(format!("Copied {}x{} cells", clip.width(), clip.height()));
+                self.clipboard = Some(clip);
+                self.redraw_screen()?;
+            }
+            Action::CopyPaste => match &self.clipboard {
+                Some(clip) => {
+                    let (row, col) = (self.cursor.row, self.cursor.col);
+                    self.canvas.paste(
+                        clip,
+                        (row, col),
+                        self.settings.transparent_paste,
+                        self.settings.paste_edges,
+                    );
+                    // Select what was pasted, so that it can be grabbed and nudged into place
+                    if clip.width() > 0 && clip.height() > 0 {
+                        let bottom_right = (
+                            row.saturating_add(clip.height() - 1),
+                            col.saturating_add(clip.width() - 1),
+                        );
+                        self.selection = Selection::from_corners((row, col), bottom_right)
+                            .intersection(&self.canvas.full_area());
+                    }
+                    self.redraw_screen()?;
+                }
+                None => self.reject()?,
+            },
+            Action::ExtraCursor if key.code == event::KeyCode::Char('+') => {
+                let position = (self.cursor.row, self.cursor.col);
+                if !self.extra_cursors.contains(&position) {
+                    self.extra_cursors.push(position);
+                }
+                self.status_message = Some(format!(
+                    "Added a cursor ({} in total). Hold Alt to move the main cursor on its own",
+                    self.extra_cursors.len() + 1
+                ));
+                self.redraw_screen()?;
+            }
+            Action::ExtraCursor => {
+                // Remove the extra cursor under the main one, or the newest one if there isn't one
+                let position = (self.cursor.row, self.cursor.col);
+                let index = self
+                    .extra_cursors
+                    .iter()
+                    .position(|extra_cursor| *extra_cursor == position)
+                    .or(self.extra_cursors.len().checked_sub(1));
+                match index {
+                    Some(index) => {
+                        self.extra_cursors.remove(index);
+                        self.redraw_screen()?;
+                    }
+                    None => self.reject()?,
+                }
+            }
+            Action::JumpBack => match self.last_painted_cell {
+                // The canvas might have shrunk since then
+                Some((row, col)) if row < self.canvas.height() && col < self.canvas.width() => {
+                    (self.cursor.row, self.cursor.col) = (row, col);
+                    self.on_cursor_move()?;
+                }
+                _ => {
+                    self.status_message = Some("You haven't painted anything yet".to_string());
+                    self.redraw_screen()?;
+                }
+            },
+            Action::Grab => match self.selection {
+                Some(area) => {
+                    self.grab = Some(Grab {
+                        area,
+                        to: (area.top, area.left),
+                    });
+                    self.redraw_screen()?;
+                }
+                None => self.reject()?,
+            },
+            Action::Mask => {
+                self.canvas.set_mask(None);
+                self.redraw_screen()?;
+            }
+            Action::Undo => {
+                if self.canvas.undo() {
+                    self.on_canvas_resized();
+                    self.redraw_screen()?;
+                } else {
+                    self.reject()?;
+                }
+            }
+            Action::Cancel => {
+                // Cancel the polygon if one is being built, otherwise clear the selection
+                // (and any measurement)
+                if matches!(self.tool, Tool::Brush) {
+                    self.selection = None;
+                    self.measurement = None;
+                }
+                self.tool = Tool::Brush;
+                self.redraw_screen()?;
+            }
+            // Ctrl saves to a quick-save slot, and Alt loads from one
+            Action::QuickSave => {
+                let event::KeyCode::Char(digit) = key.code else {
+                    unreachable!("quick-save slots are only on the number keys");
+                };
+                let slot = digit as u8 - b'0';
+                let result = if key.modifiers.contains(event::KeyModifiers::CONTROL) {
+                    self.quick_save(slot)
+                } else {
+                    self.quick_load(slot)
+                };
+                if let Err(error) = result {
+                    self.status_message = Some(error);
+                    self.reject()?;
+                }
+                self.redraw_screen()?;
+            }
+            Action::Color => {
+                if let Some(color_key) = self
+                    .palette
+                    .keys
+                    .iter()
+                    .find(|color_key| event::KeyCode::Char(color_key.key) == key.code)
+                {
+                    self.choose_color(color_key.color);
+                }
+                self.redraw_screen()?;
+            }
+            Action::Select => unreachable!("selecting is done with the mouse, not a key"),
+        }
+        Ok(())
+    }
+
+    /// Works out what a key does when it's pressed on the canvas (without a prompt or
+    /// overlay open), or None if it doesn't do anything. `handle_key` goes by this, and the
+    /// controls in `KEYMAP` are tested against it, so that the two can't drift apart.
+    fn key_action(&self, key: &KeyEvent) -> Option<Action> {
+        use event::KeyCode::{BackTab, Char, Down, Enter, Esc, F, Left, Right, Tab, Up};
+        let ctrl = key.modifiers.contains(event::KeyModifiers::CONTROL);
+        let shift = key.modifiers.contains(event::KeyModifiers::SHIFT);
+        let alt = key.modifiers.contains(event::KeyModifiers::ALT);
+        Some(match key.code {
+            // Ctrl+C is handled before anything else, even with a prompt open
+            Char('c') if ctrl => Action::Quit,
+            Char(char) if Some(char) == self.settings.quit_key => Action::Quit,
+            Char('s' | 'o') if ctrl => Action::SaveOpen,
+            Char('a') if ctrl => Action::SelectAll,
+            Left | Right | Up | Down if ctrl && shift => Action::JumpToEdge,
+            Left | Right | Up | Down if shift => Action::ExtendSelection,
+            Left | Right | Up | Down => Action::Move,
+            Char(' ') => Action::Paint,
+            Char('l') => Action::Line,
+            Char('b') => Action::Curve,
+            Char('s') => Action::Smudge,
+            Char('d') => Action::Connector,
+            Char('r') => Action::Ruler,
+            Tab | BackTab => Action::CycleTool,
+            Char('p') => Action::Polygon,
+            Enter => Action::FinishPolygon,
+            Char('e') => Action::Export,
+            Char('Q' | '@') => Action::Macro,
+            F(1..=8) if shift => Action::StoreColor,
+            F(1..=8) => Action::RecallColor,
+            F(9) => Action::ShowColorSlots,
+            F(12) => Action::Debug,
+            Char(':') => Action::Command,
+            Char('?') => Action::Help,
+            Char('X') => Action::SwapColor,
+            Char('x') => Action::Mix,
+            Char('f') => Action::Fill,
+            Char('i') => Action::Invert,
+            Char('m' | 'M') => Action::Mask,
+            Char('c' | 'v') => Action::CopyPaste,
+            Char('+' | '-') => Action::ExtraCursor,
+            Char('\'') => Action::JumpBack,
+            Char('g') => Action::Grab,
+            Char('u') => Action::Undo,
+            Esc => Action::Cancel,
+            // Ctrl saves to a quick-save slot, and Alt loads from one
+            Char('1'..='9') if ctrl || alt => Action::QuickSave,
+            Char(char)
+                if self
+                    .palette
+                    .keys
+                    .iter()
+                    .any(|color_key| color_key.key == char) =>
+            {
+                Action::Color
+            }
+            _ => return None,
+        })
+    }
+
+    /// Works out how much faster the paintbrush should move because an arrow key is being
+    /// held down. Returns 1 if it shouldn't be any faster.
+    fn movement_acceleration(&mut self, code: event::KeyCode) -> u16 {
+        use event::KeyCode::{Down, Left, Right, Up};
+        if !matches!(code, Left | Right | Up | Down) {
+            self.held_arrow = None;
+            return 1;
+        }
+        let presses = match self.held_arrow {
+            Some((held_code, last_press, presses))
+                if held_code == code && last_press.elapsed() < ACCELERATION_RESET =>
+            {
+                presses.saturating_add(1)
+            }
+            _ => 0,
+        };
+        self.held_arrow = Some((code, Instant::now(), presses));
+        // Macros are played back instantly, so they'd always be accelerated.
+        // Painting while moving would also leave gaps if the brush skipped over cells.
+        if self.playing_macro || self.space_button_held {
+            return 1;
+        }
+        let steps = presses / self.settings.acceleration_ramp;
+        let base = self.settings.acceleration_base;
+        let speed = match self.settings.acceleration_curve {
+            AccelerationCurve::Linear => base.saturating_add(steps),
+            AccelerationCurve::Doubling => base.saturating_mul(2u16.saturating_pow(steps.into())),
+        };
+        speed.min(self.settings.max_acceleration)
+    }
+
+    /// Handles a key press while a selection is being moved
+    fn on_grab_key(&mut self, key: KeyEvent, mut grab: Grab) -> std::io::Result<()> {
+        let area = grab.area;
+        let (max_row, max_col) = (
+            self.canvas.height() - 1 - (area.bottom - area.top),
+            self.canvas.width() - 1 - (area.right - area.left),
+        );
+        let distance = if key.modifiers.contains(event::KeyModifiers::CONTROL) {
+            8
+        } else {
+            1
+        };
+        match key.code {
+            event::KeyCode::Left => grab.to.1 = grab.to.1.saturating_sub(distance),
+            event::KeyCode::Right => grab.to.1 = (grab.to.1 + distance).min(max_col),
+            event::KeyCode::Up => grab.to.0 = grab.to.0.saturating_sub(distance),
+            event::KeyCode::Down => grab.to.0 = (grab.to.0 + distance).min(max_row),
+            event::KeyCode::Enter => {
+                self.canvas.move_area(area, grab.to);
+                self.selection = Some(area.moved_to(grab.to));
+                self.grab = None;
+                return self.redraw_screen();
+            }
+            event::KeyCode::Esc => {
+                self.selection = Some(area);
+                self.grab = None;
+                return self.redraw_screen();
+            }
+            _ => return self.reject(),
+        }
+        self.selection = Some(area.moved_to(grab.to));
+        self.grab = Some(grab);
+        self.redraw_screen()
+    }
+
+    /// Replays the key presses recorded into a macro register
+    fn play_macro(&mut self, register: char) -> std::io::Result<()> {
+        // Macros that play macros could end up playing themselves forever
+        if self.playing_macro {
+            return Ok(());
+        }
+        let Some(keys) = self.macros.get(&register).cloned() else {
+            self.status_message = Some(format!("No macro has been recorded to @{}", register));
+            return self.reject();
+        };
+        self.playing_macro = true;
+        let result = keys.into_iter().try_for_each(|key| self.handle_key(key));
+        self.playing_macro = false;
+        result
+    }
+
+    fn on_resize(&mut self, cols: u16, rows: u16) -> std::io::Result<()> {
+        self.terminal_size = (cols, rows);
+        self.color_bar_row = rows.saturating_sub(COLOR_BAR_ROW_FROM_BOTTOM);
+        if self.canvas_follows_terminal {
+            // The canvas only ever grows to fill the terminal. When the terminal gets
+            // smaller, less of the canvas is shown, and the rest can be scrolled to.
+            let (width, height) = canvas_size_for_terminal((cols, rows));
+            if self.canvas.fit_to(width, height).is_ok() {
+                self.on_canvas_resized();
+            }
+        }
+        self.scroll_to_cursor();
+        self.redraw_screen()?;
+        Ok(())
+    }
+
+    fn handle_mouse(&mut self, mouse: MouseEvent) -> std::io::Result<()> {
+        match mouse {
+            MouseEvent {
+                kind: MouseEventKind::Down(MouseButton::Left),
+                column,
+                row,
+                ..
+            } => {
+                self.canvas.end_stroke();
+                // Click to teleport the cursor
+                if let Some((row, column)) = self.screen_to_canvas(row, column) {
+                    self.cursor.col = column;
+                    self.cursor.row = row;
+                    // When building a polygon or line, clicking places a point
+                    if matches!(
+                        self.tool,
+                        Tool::Polygon { .. } | Tool::Line { .. } | Tool::Curve { .. }
+                    ) {
+                        self.use_tool()?;
+                        self.redraw_screen()?;
+                        return Ok(());
+                    }
+                    // Clicking also starts a drag, which can be used to select an area
+                    self.drag_start = Some((row, column));
+                    self.selection = None;
+                    self.redraw_screen()?;
+                } else if self.settings.tool_sidebar
+                    && column >= self.terminal_size.0.saturating_sub(TOOL_SIDEBAR_WIDTH)
+                {
+                    // Click on a tool in the sidebar to switch to it
+                    if let Some(tool) = Tool::all().into_iter().nth(row as usize) {
+                        self.tool = tool;
+                        self.redraw_screen()?;
+                    }
+                } else if self.settings.palette_strip && column >= self.viewport_size().0 {
+                    // Click on a swatch in the palette strip to select its colour
+                    if let Some(color) = self.palette_strip_colors().get(row as usize) {
+                        self.choose_color(*color);
+                        self.redraw_screen()?;
+                    }
+                } else if row == self.color_bar_row {
+                    // Click on a color to select it
+                    for (color, color_cols) in self.color_bar_color_labels.iter() {
+                        if color_cols.contains(&column) {
+                            self.choose_color(*color);
+                            self.redraw_screen()?;
+                            break;
+                        }
+                    }
+                }
+            }
+            MouseEvent {
+                kind: MouseEventKind::Drag(MouseButton::Left),
+                column,
+                row,
+                ..
+            } => {
+                // Drag to select a rectangular area
+                if let Some(drag_start) = self.drag_start {
+                    let row = (row + self.scroll.0).min(self.canvas.height() - 1);
+                    let column = (column + self.scroll.1).min(self.canvas.width() - 1);
+                    self.selection = Some(Selection::from_corners(drag_start, (row, column)));
+                    self.redraw_screen()?;
+                }
+            }
+            MouseEvent {
+                kind: MouseEventKind::Up(MouseButton::Left),
+                ..
+            } => {
+                self.drag_start = None;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for Paint2D<W> {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+        let _ = self.stdout.execute(cursor::Show);
+        let _ = self.stdout.execute(event::DisableMouseCapture);
+        if self.keyboard_enhancement_pushed {
+            let _ = self.stdout.execute(event::PopKeyboardEnhancementFlags);
+        }
+        if self.alternate_screen {
+            let _ = self.stdout.execute(terminal::LeaveAlternateScreen);
+        } else if let Some((col, row)) = self.inline_cursor_start {
+            // Clear away everything that was drawn (the whole screen), and put the cursor
+            // back where it was before, just after what was in the terminal already
+            let _ = self.stdout.execute(cursor::MoveTo(0, 0));
+            let _ = self.stdout.execute(Clear(ClearType::FromCursorDown));
+            let _ = self.stdout.execute(cursor::MoveTo(col, row));
+        }
+        let _ = self
+            .stdout
+            .execute(cursor::SetCursorStyle::DefaultUserShape);
+    }
+}
+
+/// Printed by `--help`
+const HELP: &str = "\
+Usage: paint-2d [options] [session.json]
+
+A fun, two-dimensional painting app that runs in your terminal.
+Give it a file saved with the :save command to carry on with that session.
+
+Options:
+  --no-confirm      Don't ask before quitting with unsaved changes
+  --remember-view   Remember where the paintbrush was in each session file
+  --debug           Show the debug overlay (F12 also toggles it)
+  --no-altscreen    Draw in the terminal's normal screen, instead of the alternate one
+  --bg <colour>     Start with the canvas filled in, instead of transparent
+  --palette <name>  Use different colours on the number keys (see :palette)
+  --quit-key <key>  Quit with a different key than Q, or only with :q if it's off
+  --mono            Draw without colours (also turned on by NO_COLOR)
+  --log <file>      Write key presses, resizes and errors to a file, for debugging
+  --list-keys       List the controls as tab-separated keys, actions and descriptions
+  -h, --help        Show this help and exit
+  -V, --version     Show the version number and exit
+";
+
+/// The controls, as `(keys, action, description)`, apart from quitting, which depends on
+/// the settings (see `keymap`). The tests check that every key `Paint2D::key_action`
+/// handles is in here, and that the keys written here do what they say.
+const KEYMAP: &[(&str, Action, &str)] = &[
+    (
+        "Arrow keys",
+        Action::Move,
+        "Move the paintbrush (hold Ctrl, or Ctrl+Alt, to go faster)",
+    ),
+    (
+        "Shift+arrow keys",
+        Action::ExtendSelection,
+        "Select an area, starting from the paintbrush",
+    ),
+    (
+        "Ctrl+Shift+arrow keys",
+        Action::JumpToEdge,
+        "Jump to the next place where the colour changes",
+    ),
+    ("'", Action::JumpBack, "Jump back to where you last painted"),
+    (
+        "+, -",
+        Action::ExtraCursor,
+        "Add or remove an extra cursor (Alt+arrow keys move just the main one)",
+    ),
+    ("Space", Action::Paint, "Paint, or use the current tool"),
+    (
+        "Number keys",
+        Action::Color,
+        "Change colour (0 is the eraser)",
+    ),
+    (
+        "Shift+F1 to F8",
+        Action::StoreColor,
+        "Store the current colour in a slot",
+    ),
+    ("F1 to F8", Action::RecallColor, "Switch to a stored colour"),
+    ("F9", Action::ShowColorSlots, "Show the stored colours"),
+    ("L", Action::Line, "Draw straight lines"),
+    (
+        "B",
+        Action::Curve,
+        "Draw curves (Space marks the start, bend, then end)",
+    ),
+    (
+        "P",
+        Action::Polygon,
+        "Draw a polygon (Enter draws it, Esc cancels)",
+    ),
+    ("Enter", Action::FinishPolygon, "Draw the polygon"),
+    (
+        "S",
+        Action::Smudge,
+        "Smudge colours together by moving the paintbrush",
+    ),
+    (
+        "D",
+        Action::Connector,
+        "Draw diagrams with box-drawing lines that join up",
+    ),
+    (
+        "R",
+        Action::Ruler,
+        "Measure distances (Space marks the start, then pins the measurement)",
+    ),
+    (
+        "Tab, Shift+Tab",
+        Action::CycleTool,
+        "Switch to the next or previous tool",
+    ),
+    (
+        "Mouse drag",
+        Action::Select,
+        "Select an area (Esc clears the selection)",
+    ),
+    ("Ctrl+A", Action::SelectAll, "Select the whole canvas"),
+    (
+        "Ctrl+S, Ctrl+O",
+        Action::SaveOpen,
+        "Save the session to a file, and open one",
+    ),
+    (
+        "Ctrl/Alt+1 to 9",
+        Action::QuickSave,
+        "Quick save to a numbered slot, and load from it",
+    ),
+    (
+        "G",
+        Action::Grab,
+        "Grab the selection to move it with the arrow keys",
+    ),
+    (
+        "C, V",
+        Action::CopyPaste,
+        "Copy the selection, and paste it at the paintbrush",
+    ),
+    (
+        "F",
+        Action::Fill,
+        "Fill in the area of the same colour (without going outside the selection)",
+    ),
+    ("X", Action::Mix, "Mix a colour from red, green and blue"),
+    (
+        "Shift+X",
+        Action::SwapColor,
+        "Swap back to the colour you were using before",
+    ),
+    ("I", Action::Invert, "Invert the colours of the selection"),
+    (
+        "M",
+        Action::Mask,
+        "Mask the selection (Shift+M removes the mask)",
+    ),
+    ("U", Action::Undo, "Undo"),
+    ("Shift+Q, @", Action::Macro, "Record and play macros"),
+    ("E", Action::Export, "Export a PNG"),
+    (":", Action::Command, "Open the command prompt"),
+    (
+        "Esc",
+        Action::Cancel,
+        "Stop using the current tool, or clear the selection",
+    ),
+    ("?", Action::Help, "Show these controls"),
+    ("F12", Action::Debug, "Show the debug overlay"),
+];
+
+/// The controls, as `(keys, action, description)`, including the keys for quitting that
+/// the settings choose
+fn keymap(settings: &Settings) -> Vec<(String, Action, &'static str)> {
+    let mut controls: Vec<_> = KEYMAP
+        .iter()
+        .map(|(keys, action, description)| (keys.to_string(), *action, *description))
+        .collect();
+    let quit_keys = match settings.quit_key {
+        Some(key) => format!("{}, Ctrl+C", key.to_uppercase()),
+        None => "Ctrl+C".to_string(),
+    };
+    let quit_description = match settings.ctrl_c {
+        CtrlC::Quit => "Quit (Ctrl+C again quits without asking)",
+        CtrlC::Cancel => {
+            "Quit (Ctrl+C cancels what you're doing first, or quits if there's nothing)"
+        }
+    };
+    controls.push((quit_keys, Action::Quit, quit_description));
+    controls
+}
+
+/// The width of the column of keys when the controls are listed, including the indent
+const KEYMAP_KEYS_WIDTH: usize = 24;
+
+/// Each control on its own line, with the keys lined up on the left
+fn controls_lines(settings: &Settings) -> Vec<String> {
+    keymap(settings)
+        .iter()
+        .map(|(keys, _, description)| {
+            format!("  {:<1$}{2}", keys, KEYMAP_KEYS_WIDTH - 2, description)
+        })
+        .collect()
+}
+
+fn main() -> std::io::Result<()> {
+    let mut settings = Settings::default();
+    let mut session_path = None;
+    let mut debug = false;
+    let mut alternate_screen = true;
+    let mut background = None;
+    let mut palette = &PALETTES[0];
+    let mut quit_key = None;
+    // See https://no-color.org
+    let mut mono = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    #[cfg(feature = "ipc")]
+    let mut socket_path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--no-confirm" => settings.confirm_quit = false,
+            "--remember-view" => settings.remember_view = true,
+            "--debug" => debug = true,
+            "--no-altscreen" => alternate_screen = false,
+            "--mono" => mono = true,
+            "--palette" => {
+                let Some(name) = args.next() else {
+                    eprintln!("--palette needs the name of a palette");
+                    std::process::exit(2);
+                };
+                palette = find_palette(&name).unwrap_or_else(|error| {
+                    eprintln!("{}", error);
+                    std::process::exit(2);
+                });
+            }
+            "--quit-key" => {
+                let Some(key) = args.next() else {
+                    eprintln!("--quit-key needs a key, or off");
+                    std::process::exit(2);
+                };
+                quit_key = Some(key);
+            }
+            "--bg" => {
+                let color = args.next();
+                let Some(color) = color.as_deref().and_then(parse_color) else {
+                    eprintln!("--bg needs a colour, such as red or #ff8800");
+                    std::process::exit(2);
+                };
+                background = Some(color);
+            }
+            "--log" => {
+                let Some(path) = args.next() else {
+                    eprintln!("--log needs the path of a file to log to");
+                    std::process::exit(2);
+                };
+                if let Err(error) = logging::start(&path) {
+                    eprintln!("{}", error);
+                    std::process::exit(1);
+                }
+                // Panic messages get printed on the alternate screen, where they're lost
+                let default_hook = std::panic::take_hook();
+                std::panic::set_hook(Box::new(move |info| {
+                    log::error!("{}", info);
+                    default_hook(info);
+                }));
+            }
+            "-h" | "--help" => {
+                print!("{}", HELP);
+                println!("\nControls:");
+                for line in controls_lines(&settings) {
+                    println!("{}", line);
+                }
+                return Ok(());
+            }
+            "--list-keys" => {
+                let mut stdout = std::io::stdout().lock();
+                for (keys, action, description) in keymap(&settings) {
+                    // Stop quietly if the output is piped into something that's finished
+                    if writeln!(stdout, "{}\t{}\t{}", keys, action.name(), description).is_err() {
+                        break;
+                    }
+                }
+                return Ok(());
+            }
+            #[cfg(feature = "benchmark")]
+            "--benchmark" => return benchmark::run(),
+            #[cfg(feature = "ipc")]
+            "--socket" => {
+                let Some(path) = args.next() else {
+                    eprintln!("--socket needs the path to open the socket at");
+                    std::process::exit(2);
+                };
+                socket_path = Some(path);
+            }
+            "-V" | "--version" => {
+                println!("Paint 2D {}", env!("CARGO_PKG_VERSION"));
+                return Ok(());
+            }
+            _ if !arg.starts_with('-') && session_path.is_none() => session_path = Some(arg),
+            _ => {
+                eprintln!("Unknown argument: {}", arg);
+                eprintln!("Run paint-2d --help to see the available options");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let terminal_size: (u16, u16) = terminal::size().unwrap_or((1, 1));
+    let mut app = Paint2D::new(std::io::stdout(), &terminal_size, settings, background);
+    if debug {
+        app.debug = Some(DebugStats::default());
+    }
+    app.alternate_screen = alternate_screen;
+    app.mono = mono;
+    app.set_palette(palette);
+    // This is checked against the other keys (including the palette's), like :set does
+    if let Some(key) = quit_key
+        && let Err(error) = app.set_option("quit-key", &key)
+    {
+        eprintln!("--quit-key: {}", error);
+        std::process::exit(2);
+    }
+    if mono {
+        // Stops any colours from being drawn, even outside of the canvas
+        Colored::set_ansi_color_disabled(true);
+    }
+    log::info!(
+        "Starting Paint 2D {} in a {}x{} terminal",
+        env!("CARGO_PKG_VERSION"),
+        terminal_size.0,
+        terminal_size.1
+    );
+    #[cfg(feature = "ipc")]
+    if let Some(path) = socket_path {
+        match ipc::Server::start(Path::new(&path)) {
+            Ok(server) => app.socket = Some(server),
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+        }
+        log::info!("Listening for commands on {}", path);
+    }
+    app.setup()?;
+    if let Some(path) = session_path
+        && let Err(error) = app.open_session(&path)
+    {
+        log::warn!("Couldn't open {}: {}", path, error);
+        app.status_message = Some(error);
+    }
+    if let Err(error) = app.run() {
+        log::error!("Stopped because of an error: {}", error);
+        return Err(error);
+    }
+    app.save_view_state()?;
+    log::info!("Quitting");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    /// An app that draws into a buffer instead of the terminal
+    fn test_app(cols: u16, rows: u16) -> Paint2D<Vec<u8>> {
+        Paint2D::new(Vec::new(), &(cols, rows), Settings::default(), None)
+    }
+
+    /// Presses a key without any modifiers
+    fn press(app: &mut Paint2D<Vec<u8>>, code: event::KeyCode) {
+        app.handle_key(KeyEvent::new(code, event::KeyModifiers::NONE))
+            .unwrap();
+    }
+
+    #[test]
+    fn painting_with_a_cursor_off_the_edge_only_flashes_when_strict() {
+        for (bounds, flashes) in [("strict", true), ("lenient", false)] {
+            let mut app = test_app(20, 12);
+            app.set_option("bounds", bounds).unwrap();
+            // Brushes can hang off the edge without it counting, but cursors can't
+            app.set_option("brush-size", "3").unwrap();
+            (app.cursor.row, app.cursor.col) = (0, 0);
+            app.paint_at_cursor().unwrap();
+            assert!(app.flash_until.is_none(), "{}", bounds);
+            app.extra_cursors.push((app.canvas.height(), 0));
+            app.paint_at_cursor().unwrap();
+            assert_eq!(app.flash_until.is_some(), flashes, "{}", bounds);
+            // The part that's on the canvas is painted either way
+            assert!(app.canvas.get_cell(0, 0).flatten().is_some(), "{}", bounds);
+        }
+    }
+
+    #[test]
+    fn goto_off_the_edge_fails_when_strict_and_clamps_when_lenient() {
+        let mut app = test_app(20, 12);
+        let (width, height) = (app.canvas.width(), app.canvas.height());
+        assert!(app.run_command("goto 9999 0").is_err());
+        assert_eq!((app.cursor.row, app.cursor.col), (0, 1));
+        app.set_option("bounds", "lenient").unwrap();
+        app.run_command("goto 9999 9999").unwrap();
+        assert_eq!((app.cursor.row, app.cursor.col), (height - 1, width - 1));
+        // Positions right on the edge work in both modes
+        app.set_option("bounds", "strict").unwrap();
+        app.run_command("goto 0 0").unwrap();
+        assert_eq!((app.cursor.row, app.cursor.col), (0, 0));
+    }
+
+    #[test]
+    fn fill_from_the_edge_reaches_the_whole_canvas_in_both_modes() {
+        for bounds in ["strict", "lenient"] {
+            let mut app = test_app(20, 12);
+            app.set_option("bounds", bounds).unwrap();
+            let (width, height) = (app.canvas.width(), app.canvas.height());
+            (app.cursor.row, app.cursor.col) = (height - 1, width - 1);
+            press(&mut app, event::KeyCode::Char('f'));
+            let total = usize::from(width) * usize::from(height);
+            assert_eq!(app.canvas.painted_count(), total, "{}", bounds);
+            assert!(app.flash_until.is_none(), "{}", bounds);
+        }
+    }
+
+    /// Plays back what was written to the terminal onto a grid of characters, ignoring
+    /// the colours. Only the escape codes that the app uses for drawing are understood.
+    fn screen_text(output: &[u8], (cols, rows): (u16, u16)) -> Vec<String> {
+        let mut screen = vec![vec![' '; cols.into()]; rows.into()];
+        let (mut row, mut col) = (0, 0);
+        let text = String::from_utf8_lossy(output);
+        let mut chars = text.chars();
+        while let Some(char) = chars.next() {
+            if char == '\x1b' {
+                let Some('[') = chars.next() else {
+                    continue;
+                };
+                let mut params = String::new();
+                let command = loop {
+                    match chars.next() {
+                        Some(char) if char.is_ascii_alphabetic() => break char,
+                        Some(char) => params.push(char),
+                        None => break ' ',
+                    }
+                };
+                let numbers: Vec<usize> = params
+                    .split(';')
+                    .map(|number| number.parse().unwrap_or(1))
+                    .collect();
+                match command {
+                    'H' => (row, col) = (numbers[0] - 1, numbers.get(1).map_or(0, |n| n - 1)),
+                    'J' if params == "2" => screen.iter_mut().for_each(|line| line.fill(' ')),
+                    'K' if params == "2" => screen[row].fill(' '),
+                    _ => {}
+                }
+                continue;
+            }
+            if char.is_control() {
+                continue;
+            }
+            if let Some(cell) = screen.get_mut(row).and_then(|line| line.get_mut(col)) {
+                *cell = char;
+            }
+            col += 1;
+        }
+        screen.into_iter().map(String::from_iter).collect()
+    }
+
+    #[test]
+    fn wrapping_the_cursor_leaves_nothing_behind() {
+        let size = (30, 10);
+        for key in [
+            event::KeyCode::Right,
+            event::KeyCode::Down,
+            event::KeyCode::Left,
+            event::KeyCode::Up,
+        ] {
+            let mut app = test_app(size.0, size.1);
+            // Start on the edge that the key wraps across
+            let (width, height) = (app.canvas.width(), app.canvas.height());
+            (app.cursor.row, app.cursor.col) = match key {
+                event::KeyCode::Right => (3, width - 1),
+                event::KeyCode::Down => (height - 1, 5),
+                event::KeyCode::Left => (3, 0),
+                _ => (0, 5),
+            };
+            app.canvas.paint(3, 4, Color::Red);
+            app.render_screen().unwrap();
+            let before = (app.cursor.row, app.cursor.col);
+            press(&mut app, key);
+            assert_ne!((app.cursor.row, app.cursor.col), before, "{:?}", key);
+            // The move should only redraw the cursors, not the whole screen
+            assert!(app.cursors_moved && !app.needs_redraw, "{:?}", key);
+            app.redraw_cursors().unwrap();
+            let moved = screen_text(&app.stdout, size);
+
+            app.stdout.clear();
+            app.render_screen().unwrap();
+            assert_eq!(moved, screen_text(&app.stdout, size), "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn wrap_preview_works_on_very_wide_canvases() {
+        let mut app = test_app(20, 12);
+        app.set_option("wrap-preview", "on").unwrap();
+        app.canvas = Canvas::new_filled(40_000, 1, Color::Red);
+        app.on_canvas_resized();
+        app.draw_cell_column(0, 40_000, false, false).unwrap();
+        // The first cell is shown again, rather than the shading past the edge
+        assert!(!String::from_utf8_lossy(&app.stdout).contains('░'));
+    }
+
+    #[test]
+    fn tools_stay_on_the_canvas_when_it_shrinks_before_they_finish() {
+        for name in ["Line", "Polygon", "Curve", "Ruler"] {
+            let mut app = test_app(20, 12);
+            let corner = (app.canvas.height() - 1, app.canvas.width() - 1);
+            app.tool = match name {
+                "Line" => Tool::Line {
+                    start: Some(corner),
+                },
+                "Polygon" => Tool::Polygon {
+                    vertices: vec![(0, 0), corner],
+                },
+                "Curve" => Tool::Curve {
+                    points: vec![(0, 0), corner],
+                },
+                _ => Tool::Ruler {
+                    start: Some(corner),
+                },
+            };
+            app.run_command("resize 10 4").unwrap();
+            let (width, height) = (app.canvas.width(), app.canvas.height());
+            assert_eq!((width, height), (10, 4));
+            app.use_tool().unwrap();
+            match name {
+                "Polygon" => assert!(app.finish_polygon()),
+                "Ruler" => {
+                    let (start, _) = app.measurement.unwrap();
+                    assert_eq!(start, (height - 1, width - 1));
+                }
+                _ => {}
+            }
+            if name != "Ruler" {
+                assert!(app.canvas.painted_count() > 0, "{} didn't draw", name);
+            }
+        }
+    }
+
+    #[test]
+    fn shrinking_the_terminal_keeps_the_whole_picture() {
+        let mut app = test_app(20, 12);
+        let corner = (app.canvas.height() - 1, app.canvas.width() - 1);
+        app.canvas.set_cell(corner.0, corner.1, Some(Color::Red));
+        app.canvas.end_stroke();
+        app.on_resize(10, 6).unwrap();
+        assert_eq!(
+            app.canvas.get_cell(corner.0, corner.1),
+            Some(Some(Color::Red))
+        );
+        app.on_resize(20, 12).unwrap();
+        assert_eq!(
+            app.canvas.get_cell(corner.0, corner.1),
+            Some(Some(Color::Red))
+        );
+        assert!(app.canvas.undo());
+        assert_eq!(app.canvas.get_cell(corner.0, corner.1), Some(None));
+    }
+
+    #[test]
+    fn resizing_the_terminal_never_rescales_the_picture() {
+        let mut app = test_app(20, 12);
+        app.settings.resize_mode = ResizeMode::Rescale;
+        app.canvas.set_cell(0, 0, Some(Color::Red));
+        app.canvas.set_cell(0, 1, Some(Color::Blue));
+        app.canvas.end_stroke();
+        let before = app.canvas.rows().to_vec();
+        app.on_resize(7, 5).unwrap();
+        app.on_resize(20, 12).unwrap();
+        assert_eq!(app.canvas.rows(), before);
+        assert!(app.canvas.undo());
+        assert!(app.canvas.rows().iter().flatten().all(Option::is_none));
+    }
+
+    #[test]
+    fn square_pixels_never_cut_off_the_picture() {
+        let mut app = test_app(20, 12);
+        app.run_command("set square-pixels on").unwrap();
+        let corner = (app.canvas.height() - 1, app.canvas.width() - 1);
+        app.canvas.set_cell(corner.0, corner.1, Some(Color::Red));
+        app.on_resize(24, 12).unwrap();
+        assert_eq!((app.canvas.width(), app.canvas.height()), (24, 10));
+        assert_eq!(
+            app.canvas.get_cell(corner.0, corner.1),
+            Some(Some(Color::Red))
+        );
+    }
+
+    #[test]
+    fn palette_strip_and_tool_sidebar_keep_the_canvas_size() {
+        for setting in ["palette-strip", "tool-sidebar"] {
+            let mut app = test_app(20, 12);
+            let corner = (app.canvas.height() - 1, app.canvas.width() - 1);
+            app.canvas.set_cell(corner.0, corner.1, Some(Color::Red));
+            app.run_command(&format!("set {} on", setting)).unwrap();
+            assert_eq!((app.canvas.width(), app.canvas.height()), (20, 10));
+            assert_eq!(
+                app.canvas.get_cell(corner.0, corner.1),
+                Some(Some(Color::Red))
+            );
+        }
+    }
+
+    #[test]
+    fn smudging_across_a_wrapped_edge_leaves_the_rest_of_the_row_alone() {
+        let mut app = test_app(20, 12);
+        let width = app.canvas.width();
+        for col in 0..width {
+            let color = if col < 2 { Color::Red } else { Color::Blue };
+            app.canvas.set_cell(0, col, Some(color));
+        }
+        app.canvas.end_stroke();
+        app.tool = Tool::Smudge;
+        (app.cursor.row, app.cursor.col) = (0, 1);
+        press(&mut app, event::KeyCode::Left);
+        press(&mut app, event::KeyCode::Left);
+        assert_eq!(app.cursor.col, width - 1);
+        // Only the cells the cursor actually passed over got smudged
+        for col in 2..width {
+            assert_eq!(app.canvas.get_cell(0, col), Some(Some(Color::Blue)));
+        }
+        // Smudging within a row still works
+        (app.cursor.row, app.cursor.col) = (0, 1);
+        press(&mut app, event::KeyCode::Right);
+        assert_eq!(app.cursor.col, 2);
+        assert_ne!(app.canvas.get_cell(0, 2), Some(Some(Color::Blue)));
+    }
+
+    /// Reads keys written the way `KEYMAP` writes them, like `L` or `Ctrl+Shift+Tab`. Returns
+    /// None for descriptions of several keys, like `Arrow keys`.
+    fn parse_keys(keys: &str) -> Option<Vec<KeyEvent>> {
+        keys.split(", ")
+            .map(|key| {
+                let (modifier_names, name) = match key.rsplit_once('+') {
+                    Some((modifiers, name)) if !name.is_empty() => (modifiers, name),
+                    _ => ("", key),
+                };
+                let mut modifiers = event::KeyModifiers::NONE;
+                for modifier in modifier_names.split('+').filter(|name| !name.is_empty()) {
+                    modifiers |= match modifier {
+                        "Ctrl" => event::KeyModifiers::CONTROL,
+                        "Shift" => event::KeyModifiers::SHIFT,
+                        "Alt" => event::KeyModifiers::ALT,
+                        _ => return None,
+                    };
+                }
+                let shift = modifiers.contains(event::KeyModifiers::SHIFT);
+                let mut chars = name.chars();
+                let code = match name {
+                    "Space" => event::KeyCode::Char(' '),
+                    "Enter" => event::KeyCode::Enter,
+                    "Esc" => event::KeyCode::Esc,
+                    "Tab" if shift => event::KeyCode::BackTab,
+                    "Tab" => event::KeyCode::Tab,
+                    _ if name.starts_with('F') && name.len() > 1 => {
+                        event::KeyCode::F(name[1..].parse().ok()?)
+                    }
+                    _ => match (chars.next(), chars.next()) {
+                        // Letters are written in capitals, with Shift for the capital letter
+                        (Some(char), None) if char.is_ascii_uppercase() && !shift => {
+                            event::KeyCode::Char(char.to_ascii_lowercase())
+                        }
+                        (Some(char), None) => event::KeyCode::Char(char),
+                        _ => return None,
+                    },
+                };
+                Some(KeyEvent::new(code, modifiers))
+            })
+            .collect()
+    }
+
+    /// Every key that could do something, with every combination of modifiers
+    fn all_keys() -> Vec<KeyEvent> {
+        let mut codes: Vec<event::KeyCode> = (' '..='~').map(event::KeyCode::Char).collect();
+        codes.extend((1..=12).map(event::KeyCode::F));
+        codes.extend([
+            event::KeyCode::Left,
+            event::KeyCode::Right,
+            event::KeyCode::Up,
+            event::KeyCode::Down,
+            event::KeyCode::Tab,
+            event::KeyCode::BackTab,
+            event::KeyCode::Enter,
+            event::KeyCode::Esc,
+            event::KeyCode::Backspace,
+            event::KeyCode::Delete,
+            event::KeyCode::Home,
+            event::KeyCode::End,
+        ]);
+        let modifiers = [
+            event::KeyModifiers::NONE,
+            event::KeyModifiers::SHIFT,
+            event::KeyModifiers::CONTROL,
+            event::KeyModifiers::ALT,
+            event::KeyModifiers::CONTROL | event::KeyModifiers::SHIFT,
+            event::KeyModifiers::CONTROL | event::KeyModifiers::ALT,
+        ];
+        codes
+            .into_iter()
+            .flat_map(|code| modifiers.map(|modifiers| KeyEvent::new(code, modifiers)))
+            .collect()
+    }
+
+    #[test]
+    fn every_key_that_does_something_is_listed_and_does_what_it_says() {
+        let app = test_app(20, 12);
+        let controls = keymap(&app.settings);
+        let listed: HashSet<Action> = controls.iter().map(|(_, action, _)| *action).collect();
+        let mut handled = HashSet::from([Action::Select]);
+        for key in all_keys() {
+            if let Some(action) = app.key_action(&key) {
+                assert!(listed.contains(&action), "{:?} isn't listed", key);
+                handled.insert(action);
+            }
+        }
+        assert_eq!(handled, listed);
+        for (keys, action, _) in &controls {
+            for key in parse_keys(keys).unwrap_or_default() {
+                assert_eq!(
+                    app.key_action(&key),
+                    Some(*action),
+                    "{} isn't {:?}",
+                    keys,
+                    action
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn the_quit_controls_follow_the_settings() {
+        let mut app = test_app(20, 12);
+        let quit_keys = |app: &Paint2D<Vec<u8>>| {
+            let controls = keymap(&app.settings);
+            let (keys, _, description) = controls
+                .into_iter()
+                .find(|(_, action, _)| *action == Action::Quit)
+                .unwrap();
+            (keys, description)
+        };
+        assert_eq!(quit_keys(&app).0, "Q, Ctrl+C");
+        app.set_option("quit-key", "off").unwrap();
+        assert_eq!(quit_keys(&app).0, "Ctrl+C");
+        assert_eq!(
+            app.key_action(&KeyEvent::new(
+                event::KeyCode::Char('q'),
+                event::KeyModifiers::NONE
+            )),
+            None
+        );
+        app.set_option("quit-key", "z").unwrap();
+        assert_eq!(quit_keys(&app).0, "Z, Ctrl+C");
+        // Keys that already do something can't quit instead
+        for key in ["x", "1", " ", ":", "Q"] {
+            assert!(
+                app.set_option("quit-key", key).is_err(),
+                "{:?} was allowed",
+                key
+            );
+            assert_eq!(app.settings.quit_key, Some('z'));
+        }
+        app.set_option("quit-key", "q").unwrap();
+        let description = quit_keys(&app).1;
+        app.set_option("ctrl-c", "cancel").unwrap();
+        assert_ne!(quit_keys(&app).1, description);
+    }
+
+    #[test]
+    fn space_taps_with_moves_in_between_undo_separately() {
+        let mut app = test_app(20, 12);
+        let start = (app.cursor.row, app.cursor.col);
+        press(&mut app, event::KeyCode::Char(' '));
+        press(&mut app, event::KeyCode::Right);
+        press(&mut app, event::KeyCode::Char(' '));
+        assert_eq!(app.canvas.painted_count(), 2);
+        press(&mut app, event::KeyCode::Char('u'));
+        assert_eq!(app.canvas.painted_count(), 1);
+        assert!(app.canvas.get_cell(start.0, start.1).unwrap().is_some());
+        press(&mut app, event::KeyCode::Char('u'));
+        assert_eq!(app.canvas.painted_count(), 0);
+    }
+
+    #[test]
+    fn dragging_with_space_held_undoes_in_one_step() {
+        let mut app = test_app(20, 12);
+        let space = |kind| {
+            KeyEvent::new_with_kind(event::KeyCode::Char(' '), event::KeyModifiers::NONE, kind)
+        };
+        // Only terminals that send key releases let Space be held down
+        app.enhanced_key_events = true;
+        app.handle_key(space(KeyEventKind::Press)).unwrap();
+        for _ in 0..3 {
+            press(&mut app, event::KeyCode::Right);
+        }
+        app.handle_key(space(KeyEventKind::Release)).unwrap();
+        assert_eq!(app.canvas.painted_count(), 4);
+        // Moving after letting go of Space starts a new stroke
+        press(&mut app, event::KeyCode::Down);
+        press(&mut app, event::KeyCode::Char(' '));
+        assert_eq!(app.canvas.painted_count(), 5);
+        press(&mut app, event::KeyCode::Char('u'));
+        assert_eq!(app.canvas.painted_count(), 4);
+        press(&mut app, event::KeyCode::Char('u'));
+        assert_eq!(app.canvas.painted_count(), 0);
+    }
+
+    #[test]
+    fn arrow_keys_only_clear_selections_made_with_shift() {
+        let mut app = test_app(20, 12);
+        app.handle_key(KeyEvent::new(
+            event::KeyCode::Right,
+            event::KeyModifiers::SHIFT,
+        ))
+        .unwrap();
+        assert!(app.selection.is_some());
+        press(&mut app, event::KeyCode::Right);
+        assert_eq!(app.selection, None);
+        // A selection made with the mouse stays while the paintbrush moves inside it
+        let dragged = Selection::from_corners((0, 0), (5, 5));
+        app.selection = Some(dragged);
+        press(&mut app, event::KeyCode::Right);
+        assert_eq!(app.selection, Some(dragged));
+    }
+
+    #[test]
+    fn cursor_wraps_or_stops_at_each_edge() {
+        for (wrap_h, wrap_v) in [(false, false), (true, false), (false, true), (true, true)] {
+            let mut cursor = PaintCursor::new(0, 0, (10, 5));
+            (cursor.wrap_h, cursor.wrap_v) = (wrap_h, wrap_v);
+            cursor.left(1);
+            assert_eq!(
+                cursor.col,
+                if wrap_h { 9 } else { 0 },
+                "left, wrap_h {}",
+                wrap_h
+            );
+            cursor.col = 9;
+            cursor.right(1);
+            assert_eq!(
+                cursor.col,
+                if wrap_h { 0 } else { 9 },
+                "right, wrap_h {}",
+                wrap_h
+            );
+            cursor.up(1);
+            assert_eq!(
+                cursor.row,
+                if wrap_v { 4 } else { 0 },
+                "up, wrap_v {}",
+                wrap_v
+            );
+            cursor.row = 4;
+            cursor.down(1);
+            assert_eq!(
+                cursor.row,
+                if wrap_v { 0 } else { 4 },
+                "down, wrap_v {}",
+                wrap_v
+            );
+        }
+    }
+
+    #[test]
+    fn cursor_wraps_on_very_wide_canvases() {
+        let mut cursor = PaintCursor::new(0, 39_999, (40_000, 1));
+        cursor.right(1);
+        assert_eq!(cursor.col, 0);
+        cursor.left(1);
+        assert_eq!(cursor.col, 39_999);
+    }
 }
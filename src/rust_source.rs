@@ -0,0 +1,44 @@
+//! Writing canvases as Rust source code, so that small pictures (like sprites) can be
+//! built into other programs without needing to read any files
+
+use crate::{Canvas, Color, color_to_rgb};
+
+/// Checks that a name can be used for a Rust constant. Keywords aren't checked for.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|char| char.is_ascii_alphabetic() || char == '_')
+        && chars.all(|char| char.is_ascii_alphanumeric() || char == '_')
+        && name != "_"
+}
+
+/// Writes the canvas's colours as a Rust constant with the given name. Each cell becomes
+/// `Some([r, g, b])`, or `None` if it's transparent (or erased). Glyphs aren't included.
+/// The code doesn't need any crates, so it can be pasted into any program.
+pub fn to_rust_source(canvas: &Canvas, name: &str) -> Result<String, String> {
+    if !is_identifier(name) {
+        return Err(format!("{} isn't a valid name for a Rust constant", name));
+    }
+    let mut source = format!(
+        "// Made with Paint 2D. Each cell is None if it's transparent, or Some([red, green, blue])\n\
+         pub const {}: [[Option<[u8; 3]>; {}]; {}] = [\n",
+        name,
+        canvas.width(),
+        canvas.height()
+    );
+    for row in 0..canvas.height() {
+        let cells: Vec<String> = (0..canvas.width())
+            .map(|col| match canvas.get_cell(row, col).flatten() {
+                None | Some(Color::Reset) => "None".to_string(),
+                Some(color) => {
+                    let [r, g, b] = color_to_rgb(color);
+                    format!("Some([{}, {}, {}])", r, g, b)
+                }
+            })
+            .collect();
+        source += &format!("    [{}],\n", cells.join(", "));
+    }
+    source += "];\n";
+    Ok(source)
+}